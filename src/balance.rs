@@ -0,0 +1,260 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use datastore::Datastore;
+use datastore::FreebyAble;
+use datastore::PricedSpecial;
+use datastore::Purchase;
+use datastore::budget_freeby_index;
+use datastore::count_freeby_index;
+use datastore::purchase_unit_price;
+use ledger::BalanceLine;
+use typescriptify::TypeScriptifyTrait;
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, TypeScriptify)]
+pub struct GiveoutLine {
+    //the recipient for giveouts given, the donor for giveouts received
+    pub user_id: u32,
+    pub item_id: u32,
+    pub itemname: String,
+    pub count: u32,
+    pub cost_cents: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, TypeScriptify)]
+pub struct BudgetLine {
+    pub user_id: u32,
+    pub cents: u64,
+}
+
+//everything a user would pay if all open purchases were billed now.
+//freebies are used up in the same order as in FinalizeBill, so total_cents matches the later bill
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, TypeScriptify)]
+pub struct UserBalance {
+    pub user_id: u32,
+    //items paid by the user, budget giveouts are subtracted separately
+    pub items: Vec<BalanceLine>,
+    pub specials: Vec<PricedSpecial>,
+    //purchase ids of specials without a price yet, not part of the total
+    pub unpriced_specials: Vec<u64>,
+    //items taken by others from the user's free for all giveouts
+    pub ffa_giveouts: Vec<BalanceLine>,
+    pub count_giveouts_given: Vec<GiveoutLine>,
+    //free for the user, listed for completeness
+    pub count_giveouts_received: Vec<GiveoutLine>,
+    pub budget_given: Vec<BudgetLine>,
+    pub budget_received: Vec<BudgetLine>,
+    //bottle deposits, not part of the item lines
+    pub deposit_charged_cents: u64,
+    pub deposit_refunded_cents: u64,
+    pub total_cents: i64,
+}
+
+//count and cents, each purchase priced as recorded
+type Tally = (u32, u32);
+
+fn tally(tally: &mut Tally, cost_cents: u32) {
+    tally.0 += 1;
+    tally.1 += cost_cents;
+}
+
+#[derive(Default)]
+struct Accumulator {
+    items: BTreeMap<u32, Tally>,
+    specials: Vec<PricedSpecial>,
+    unpriced_specials: Vec<u64>,
+    ffa_giveouts: BTreeMap<u32, Tally>,
+    count_giveouts_given: BTreeMap<(u32, u32), Tally>,
+    count_giveouts_received: BTreeMap<(u32, u32), Tally>,
+    budget_given: BTreeMap<u32, u64>,
+    budget_received: BTreeMap<u32, u64>,
+}
+
+impl Accumulator {
+    fn into_balance(self, store: &Datastore, user_id: u32) -> UserBalance {
+        let name = |item_id: u32| store.items.get(&item_id).map(|i| i.name.to_string()).unwrap_or_default();
+        let line = |(item_id, (count, cost_cents)): (u32, Tally)| BalanceLine {
+            item_id,
+            itemname: name(item_id),
+            count,
+            cost_cents,
+        };
+        let giveout = |((other_id, item_id), (count, cost_cents)): ((u32, u32), Tally)| GiveoutLine {
+            user_id: other_id,
+            item_id,
+            itemname: name(item_id),
+            count,
+            cost_cents,
+        };
+        let budget = |(other_id, cents): (u32, u64)| BudgetLine { user_id: other_id, cents };
+
+        let mut balance = UserBalance {
+            user_id,
+            items: self.items.into_iter().map(line).collect(),
+            specials: self.specials,
+            unpriced_specials: self.unpriced_specials,
+            ffa_giveouts: self.ffa_giveouts.into_iter().map(line).collect(),
+            count_giveouts_given: self.count_giveouts_given.into_iter().map(giveout).collect(),
+            count_giveouts_received: self.count_giveouts_received.into_iter().map(giveout).collect(),
+            budget_given: self.budget_given.into_iter().map(budget).collect(),
+            budget_received: self.budget_received.into_iter().map(budget).collect(),
+            deposit_charged_cents: 0,
+            deposit_refunded_cents: 0,
+            total_cents: 0,
+        };
+        let (charged, refunded) = store.deposits.open_totals(user_id);
+        balance.deposit_charged_cents = charged;
+        balance.deposit_refunded_cents = refunded;
+        balance.total_cents = balance.items.iter().chain(balance.ffa_giveouts.iter()).map(|l| i64::from(l.cost_cents)).sum::<i64>()
+            + balance.specials.iter().map(|s| i64::from(s.price)).sum::<i64>()
+            + balance.count_giveouts_given.iter().map(|g| i64::from(g.cost_cents)).sum::<i64>()
+            + balance.budget_given.iter().map(|b| b.cents as i64).sum::<i64>()
+            - balance.budget_received.iter().map(|b| b.cents as i64).sum::<i64>()
+            + balance.deposit_charged_cents as i64
+            - balance.deposit_refunded_cents as i64;
+        balance
+    }
+}
+
+//balances of every user with open purchases or freebies used up by open purchases
+pub fn running_balances(store: &Datastore) -> HashMap<u32, UserBalance> {
+    let mut accumulators: HashMap<u32, Accumulator> = HashMap::new();
+    let mut open_freebies = store.open_freebies.clone();
+
+    for purchase in &store.purchases {
+        match *purchase {
+            Purchase::SpecialPurchase { unique_id, ref special_name, specialcost, consumer_id, .. } => {
+                let acc = accumulators.entry(consumer_id).or_default();
+                match specialcost {
+                    Some(price) => acc.specials.push(PricedSpecial {
+                        purchase_id: unique_id,
+                        price,
+                        name: special_name.to_string(),
+                    }),
+                    None => acc.unpriced_specials.push(unique_id),
+                }
+            },
+            Purchase::SimplePurchase { item_id, consumer_id, .. } => {
+                let item = match store.items.get(&item_id) {
+                    Some(item) => item,
+                    None => continue,
+                };
+                let unit_price = purchase_unit_price(store, purchase);
+                let freebies = open_freebies.entry(consumer_id).or_default();
+                if let Some(idx) = count_freeby_index(freebies, item) {
+                    let donor_id = freebies[idx].get_donor();
+                    let old_count = freebies[idx].left();
+                    freebies[idx].decrement();
+                    if old_count <= 1 {
+                        freebies.remove(idx);
+                    }
+                    tally(accumulators.entry(donor_id).or_default().count_giveouts_given.entry((consumer_id, item_id)).or_default(), unit_price);
+                    tally(accumulators.entry(consumer_id).or_default().count_giveouts_received.entry((donor_id, item_id)).or_default(), unit_price);
+                } else {
+                    tally(accumulators.entry(consumer_id).or_default().items.entry(item_id).or_default(), unit_price);
+                    if let Some(idx) = budget_freeby_index(freebies) {
+                        let donor_id = freebies[idx].get_donor();
+                        let max_budget = freebies[idx].get_budget_cents_left();
+                        let item_cost = u64::from(unit_price);
+                        let taken_budget = cmp::min(max_budget, item_cost);
+                        freebies[idx].remove_budget_by(taken_budget);
+                        if max_budget <= item_cost {
+                            freebies.remove(idx);
+                        }
+                        *accumulators.entry(donor_id).or_default().budget_given.entry(consumer_id).or_insert(0) += taken_budget;
+                        *accumulators.entry(consumer_id).or_default().budget_received.entry(donor_id).or_insert(0) += taken_budget;
+                    }
+                }
+            },
+            Purchase::FFAPurchase { item_id, donor, .. } => {
+                let unit_price = purchase_unit_price(store, purchase);
+                tally(accumulators.entry(donor).or_default().ffa_giveouts.entry(item_id).or_default(), unit_price);
+            },
+        }
+    }
+
+    //users with nothing but deposits open
+    for entry in &store.deposits.open {
+        accumulators.entry(entry.user_id).or_default();
+    }
+
+    accumulators.into_iter().map(|(user_id, acc)| (user_id, acc.into_balance(store, user_id))).collect()
+}
+
+pub fn running_balance(store: &Datastore, user_id: u32) -> UserBalance {
+    running_balances(store).remove(&user_id).unwrap_or(UserBalance {
+        user_id,
+        ..UserBalance::default()
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use balance::*;
+    use datastore::DatastoreQueries;
+    use datastore::UserGroup;
+    use rustix_backend::WriteBackend;
+    use rustix_event_shop::BLEvents;
+
+    #[test]
+    fn running_balances_match_the_finalized_bill() {
+        let mut backend = ::build_transient_backend();
+        for (idx, name) in ["klaus", "dieter", "heinz"].iter().enumerate() {
+            backend.create_user(name.to_string());
+            backend.update_user(idx as u32, name.to_string(), true, false, Some(name.to_string()), true);
+        }
+        backend.create_item("beer".to_string(), 100, Some("alcohol".to_string()));
+        backend.create_item("mate".to_string(), 150, None);
+
+        //klaus pays two beers for dieter and gives heinz a budget of 120 cents
+        backend.create_free_count(vec!["alcohol".to_string()], vec![], 2, "cheers".to_string(), 1, 0, 1);
+        backend.create_free_budget(120, "have fun".to_string(), 2, 0, 2);
+        backend.create_ffa(vec![], vec![1], 5, "for everyone".to_string(), 3, 1);
+
+        backend.purchase(1, 0, 10);
+        backend.purchase(1, 1, 11);
+        backend.purchase(1, 0, 12);
+        backend.purchase(1, 0, 13);
+        backend.purchase(2, 0, 14);
+        backend.purchase(2, 0, 15);
+        backend.purchase(0, 1, 16);
+        let ffa_id = backend.datastore.open_ffa[0].get_id();
+        assert!(backend.ffa_purchase(ffa_id, 1, 17));
+        backend.special_purchase(2, "pizza".to_string(), 18);
+
+        let klaus = backend.datastore.running_balance(0);
+        assert_eq!(klaus.count_giveouts_given, vec![GiveoutLine { user_id: 1, item_id: 0, itemname: "beer".to_string(), count: 2, cost_cents: 200 }]);
+        assert_eq!(klaus.budget_given, vec![BudgetLine { user_id: 2, cents: 120 }]);
+        assert_eq!(klaus.total_cents, 150 + 200 + 120);
+
+        let dieter = backend.datastore.running_balance(1);
+        assert_eq!(dieter.items.iter().map(|l| (l.item_id, l.count)).collect::<Vec<_>>(), vec![(0, 1), (1, 1)]);
+        assert_eq!(dieter.count_giveouts_received.len(), 1);
+        assert_eq!(dieter.ffa_giveouts[0].cost_cents, 150);
+        assert_eq!(dieter.total_cents, 100 + 150 + 150);
+
+        let heinz = backend.datastore.running_balance(2);
+        assert_eq!(heinz.unpriced_specials.len(), 1);
+        assert_eq!(heinz.budget_received, vec![BudgetLine { user_id: 0, cents: 120 }]);
+        assert_eq!(heinz.total_cents, 200 - 120);
+        assert_eq!(backend.datastore.running_balance(7).total_cents, 0);
+
+        //the open specials are priced, then everything gets billed
+        let special_id = heinz.unpriced_specials[0];
+        assert!(backend.apply(&BLEvents::SetPriceForSpecial { unique_id: special_id, price: 800 }));
+        let balances = running_balances(&backend.datastore);
+        assert_eq!(balances[&2].total_cents, 200 - 120 + 800);
+
+        backend.create_bill(0, 100, UserGroup::AllUsers, "".to_string());
+        assert!(backend.apply(&BLEvents::FinalizeBill { timestamp_from: 0, timestamp_to: 100 }));
+        for user_id in 0..3 {
+            assert_eq!(backend.datastore.bills[0].total_cents_of(user_id), balances[&user_id].total_cents);
+        }
+        assert_eq!(backend.datastore.running_balance(0), UserBalance { user_id: 0, ..UserBalance::default() });
+    }
+}
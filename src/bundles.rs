@@ -0,0 +1,134 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::iter;
+use datastore::Datastore;
+use pricing_rules::effective_price;
+use typescriptify::TypeScriptifyTrait;
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, TypeScriptify)]
+pub struct BundleComponent {
+    pub item_id: u32,
+    pub quantity: u32,
+}
+
+//one purchase records at most this many units, however many the client asks for
+pub const MAX_PURCHASE_UNITS: u64 = 1000;
+
+//components have to be plain items that are not deleted, bundles can not be part of other bundles
+pub fn is_valid_bundle(store: &Datastore, item_id: u32, components: &[BundleComponent]) -> bool {
+    let is_component = store.items.values().any(|i| i.item_id != item_id && i.bundle.iter().any(|c| c.item_id == item_id));
+    let units: u64 = components.iter().map(|c| u64::from(c.quantity)).sum();
+    !components.is_empty() && !is_component && units <= MAX_PURCHASE_UNITS && components.iter().all(|c| {
+        c.quantity > 0 && c.item_id != item_id && store.items.get(&c.item_id).map(|i| !i.deleted && i.bundle.is_empty()).unwrap_or(false)
+    })
+}
+
+//a bundle can not be bought anymore once one of its components was deleted
+pub fn has_deleted_components(store: &Datastore, item_id: u32) -> bool {
+    store.items.get(&item_id).map(|item| item.bundle.iter().any(|c| store.items.get(&c.item_id).map(|i| i.deleted).unwrap_or(true))).unwrap_or(false)
+}
+
+//units a purchase of quantity times the item records
+pub fn unit_count(store: &Datastore, item_id: u32, quantity: u32) -> u64 {
+    let per_item = store.items.get(&item_id).map(|item| item.bundle.iter().map(|c| u64::from(c.quantity)).sum::<u64>().max(1)).unwrap_or(1);
+    u64::from(quantity) * per_item
+}
+
+//splits cents in proportion to the weights, the remaining cents go to the first units
+fn split(cents: u32, weights: &[u64]) -> Vec<u32> {
+    let total: u64 = weights.iter().sum();
+    let weights: Vec<u64> = if total == 0 { vec![1; weights.len()] } else { weights.to_vec() };
+    let total: u64 = weights.iter().sum();
+    let mut shares: Vec<u32> = weights.iter().map(|w| (u64::from(cents) * w / total) as u32).collect();
+    let remainder = cents - shares.iter().sum::<u32>();
+    for share in shares.iter_mut().take(remainder as usize) {
+        *share += 1;
+    }
+    shares
+}
+
+//(item_id, unit_price_cents) of every unit one purchase of the item records, a purchase of a quantity repeats them.
+//bundles are split into their components, which share the bundle price in proportion to their own prices
+pub fn purchase_units(store: &Datastore, item_id: u32, user_id: u32, timestamp_epoch_millis: i64) -> Vec<(u32, u32)> {
+    let item = match store.items.get(&item_id) {
+        Some(item) => item,
+        None => return vec![],
+    };
+    let price = effective_price(store, item_id, user_id, timestamp_epoch_millis);
+    if item.bundle.is_empty() {
+        return vec![(item_id, price)];
+    }
+    let units: Vec<u32> = item.bundle.iter().flat_map(|c| iter::repeat_n(c.item_id, c.quantity as usize)).collect();
+    let weights: Vec<u64> = units.iter().map(|id| u64::from(effective_price(store, *id, user_id, timestamp_epoch_millis))).collect();
+    units.into_iter().zip(split(price, &weights)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bundles::*;
+    use datastore::DatastoreQueries;
+    use datastore::PurchaseFunctions;
+    use datastore::UserGroup;
+    use rustix_backend::WriteBackend;
+    use rustix_event_shop::BLEvents;
+
+    #[test]
+    fn bundle_purchases_are_recorded_as_their_components() {
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.update_user(0, "klaus".to_string(), true, false, Some("klaus".to_string()), true);
+        backend.create_item("beer".to_string(), 100, Some("alcohol".to_string()));
+        backend.create_item("pizza".to_string(), 600, None);
+        backend.create_item("mate".to_string(), 150, None);
+        backend.set_item_deposit(0, 8);
+        backend.deliver_stock(0, 40, 0, "".to_string());
+
+        let crate_of_beer = vec![BundleComponent { item_id: 0, quantity: 20 }];
+        assert!(backend.create_bundle("crate of beer".to_string(), 1590, Some("alcohol".to_string()), crate_of_beer));
+        assert!(backend.create_bundle("menu".to_string(), 650, None, vec![BundleComponent { item_id: 1, quantity: 1 }, BundleComponent { item_id: 2, quantity: 1 }]));
+        assert!(!backend.create_bundle("nested".to_string(), 100, None, vec![BundleComponent { item_id: 3, quantity: 1 }]));
+        assert!(!backend.create_bundle("unknown".to_string(), 100, None, vec![BundleComponent { item_id: 9, quantity: 1 }]));
+        assert!(!backend.set_bundle_components(0, vec![BundleComponent { item_id: 2, quantity: 2 }]));
+        assert_eq!(backend.datastore.items_searchhit_ids("crate"), vec![3]);
+
+        backend.purchase_quantity(0, 3, 1, 10);
+        backend.purchase_quantity(0, 4, 1, 11);
+        backend.purchase_quantity(0, 2, 3, 12);
+        assert!(!backend.purchase_quantity(0, 2, 0, 13));
+        assert!(!backend.purchase_quantity(0, 2, u32::MAX, 13));
+        assert!(!backend.purchase_quantity(0, 3, 51, 13));
+        let prices: Vec<(u32, u32)> = backend.datastore.purchases.iter().map(|p| (*p.get_item_id(), p.get_unit_price().unwrap())).collect();
+        assert_eq!(prices.len(), 20 + 2 + 3);
+        assert_eq!(prices[..20].iter().map(|p| p.1).sum::<u32>(), 1590);
+        assert_eq!((prices[0], prices[19]), ((0, 80), (0, 79)));
+        assert_eq!(&prices[20..], &[(1, 520), (2, 130), (2, 150), (2, 150), (2, 150)]);
+
+        assert_eq!(backend.datastore.stock_level(0), Some(20));
+        assert_eq!(backend.datastore.bestselling_item_ids(2, None, None), vec![0, 2]);
+        assert_eq!(backend.datastore.running_balance(0).deposit_charged_cents, 20 * 8);
+
+        //undoing one bottle of a crate takes back the whole crate
+        backend.purchase_quantity(0, 3, 1, 14);
+        assert_eq!(backend.datastore.stock_level(0), Some(0));
+        let bottle = backend.datastore.purchases.last().unwrap().get_unique_id();
+        assert!(backend.apply(&BLEvents::UndoPurchase { unique_id: bottle }));
+        assert_eq!(backend.datastore.purchases.len(), 20 + 2 + 3);
+        assert_eq!(backend.datastore.stock_level(0), Some(20));
+        assert_eq!(backend.datastore.running_balance(0).deposit_charged_cents, 20 * 8);
+
+        backend.create_bill(0, 100, UserGroup::AllUsers, "".to_string());
+        assert!(backend.apply(&BLEvents::FinalizeBill { timestamp_from: 0, timestamp_to: 100 }));
+        let bill = &backend.datastore.bills[0];
+        let day = &bill.finalized_data.user_consumption[&0].per_day[&0];
+        assert_eq!((day.personally_consumed[&0], day.personally_consumed_cents[&0]), (20, 1590));
+        assert_eq!(bill.total_cents_of(0), 1590 + 650 + 3 * 150 + 20 * 8);
+
+        //without its mate the menu can not be bought anymore
+        backend.delete_item(2);
+        assert!(!backend.purchase_quantity(0, 4, 1, 200));
+        assert!(backend.datastore.purchases.is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use typescriptify::TypeScriptifyTrait;
+
+//calendar arithmetic on epoch millis, always in UTC (callers shift by an offset for local time, see TimeZone)
+
+pub const MILLIS_PER_MINUTE: i64 = 60 * 1000;
+pub const MILLIS_PER_HOUR: i64 = 60 * MILLIS_PER_MINUTE;
+pub const MILLIS_PER_DAY: i64 = 24 * MILLIS_PER_HOUR;
+pub const MILLIS_PER_WEEK: i64 = 7 * MILLIS_PER_DAY;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default, TypeScriptify)]
+pub enum DaylightSaving {
+    #[default]
+    Never,
+    //one hour more from the last sunday of march to the last sunday of october, switching at 01:00 UTC
+    European,
+}
+
+//local time of the shop, UTC by default
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default, TypeScriptify)]
+pub struct TimeZone {
+    pub utc_offset_minutes: i32,
+    pub daylight_saving: DaylightSaving,
+}
+
+
+pub fn floor_to(millis: i64, unit: i64) -> i64 {
+    millis.div_euclid(unit) * unit
+}
+
+pub fn days_since_epoch(millis: i64) -> i64 {
+    millis.div_euclid(MILLIS_PER_DAY)
+}
+
+//days since 1970-01-01 for a proleptic gregorian date (month and day starting at 1)
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let m = i64::from(month);
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+//inverse of days_from_civil, returns (year, month, day)
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+pub fn millis_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    days_from_civil(year, month, day) * MILLIS_PER_DAY
+}
+
+pub fn start_of_hour(millis: i64) -> i64 {
+    floor_to(millis, MILLIS_PER_HOUR)
+}
+
+pub fn start_of_day(millis: i64) -> i64 {
+    floor_to(millis, MILLIS_PER_DAY)
+}
+
+//0 = monday, ..., 6 = sunday
+pub fn weekday(millis: i64) -> u32 {
+    //1970-01-01 was a thursday
+    (days_since_epoch(millis) + 3).rem_euclid(7) as u32
+}
+
+//weeks start on monday
+pub fn start_of_week(millis: i64) -> i64 {
+    start_of_day(millis) - i64::from(weekday(millis)) * MILLIS_PER_DAY
+}
+
+pub fn start_of_month(millis: i64) -> i64 {
+    let (year, month, _) = civil_from_days(days_since_epoch(millis));
+    millis_from_civil(year, month, 1)
+}
+
+//start of the month n months after the month containing millis
+pub fn add_months(millis: i64, n: i64) -> i64 {
+    let (year, month, _) = civil_from_days(days_since_epoch(millis));
+    let total = year * 12 + i64::from(month) - 1 + n;
+    millis_from_civil(total.div_euclid(12), (total.rem_euclid(12) + 1) as u32, 1)
+}
+
+pub fn minute_of_day(millis: i64) -> u32 {
+    (millis - start_of_day(millis)).div_euclid(MILLIS_PER_MINUTE) as u32
+}
+
+pub fn hour_of_day(millis: i64) -> u32 {
+    minute_of_day(millis) / 60
+}
+
+//01:00 UTC on the last sunday of the month
+fn last_sunday_switch(year: i64, month: u32) -> i64 {
+    let last_day = days_from_civil(year, month + 1, 1) - 1;
+    let sunday = last_day - (last_day + 3 + 1).rem_euclid(7);
+    sunday * MILLIS_PER_DAY + MILLIS_PER_HOUR
+}
+
+impl TimeZone {
+    //offsets beyond +-14 hours do not exist
+    pub fn is_valid(&self) -> bool {
+        self.utc_offset_minutes.abs() <= 14 * 60
+    }
+
+    //difference between local time and UTC at the given instant
+    pub fn offset_millis_at(&self, millis: i64) -> i64 {
+        let base = i64::from(self.utc_offset_minutes) * MILLIS_PER_MINUTE;
+        match self.daylight_saving {
+            DaylightSaving::Never => base,
+            DaylightSaving::European => {
+                let (year, _, _) = civil_from_days(days_since_epoch(millis));
+                if last_sunday_switch(year, 3) <= millis && millis < last_sunday_switch(year, 10) {
+                    base + MILLIS_PER_HOUR
+                } else {
+                    base
+                }
+            },
+        }
+    }
+
+    //millis shifted so that the UTC functions above give local hours, days and weekdays
+    pub fn to_local(&self, millis: i64) -> i64 {
+        millis + self.offset_millis_at(millis)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use calendar::*;
+
+    #[test]
+    fn civil_conversion_roundtrips() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        for days in -1000..20_000 {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn buckets_start_at_calendar_boundaries() {
+        //2018-03-15 (a thursday) 13:45:10 UTC
+        let t = millis_from_civil(2018, 3, 15) + 13 * MILLIS_PER_HOUR + 45 * MILLIS_PER_MINUTE + 10_000;
+        assert_eq!(start_of_hour(t), millis_from_civil(2018, 3, 15) + 13 * MILLIS_PER_HOUR);
+        assert_eq!(start_of_day(t), millis_from_civil(2018, 3, 15));
+        assert_eq!(weekday(t), 3);
+        assert_eq!(start_of_week(t), millis_from_civil(2018, 3, 12));
+        assert_eq!(start_of_month(t), millis_from_civil(2018, 3, 1));
+        assert_eq!(add_months(t, 10), millis_from_civil(2019, 1, 1));
+        assert_eq!(add_months(t, -3), millis_from_civil(2017, 12, 1));
+        assert_eq!(minute_of_day(t), 13 * 60 + 45);
+        assert_eq!(hour_of_day(t), 13);
+    }
+
+    #[test]
+    fn european_summer_time_starts_and_ends_on_the_last_sundays() {
+        let berlin = TimeZone { utc_offset_minutes: 60, daylight_saving: DaylightSaving::European };
+        //2018-03-25 and 2018-10-28 were the last sundays
+        let spring = millis_from_civil(2018, 3, 25) + MILLIS_PER_HOUR;
+        let autumn = millis_from_civil(2018, 10, 28) + MILLIS_PER_HOUR;
+        assert_eq!(berlin.offset_millis_at(spring - 1), MILLIS_PER_HOUR);
+        assert_eq!(berlin.offset_millis_at(spring), 2 * MILLIS_PER_HOUR);
+        assert_eq!(berlin.offset_millis_at(autumn - 1), 2 * MILLIS_PER_HOUR);
+        assert_eq!(berlin.offset_millis_at(autumn), MILLIS_PER_HOUR);
+        assert_eq!(hour_of_day(berlin.to_local(millis_from_civil(2018, 7, 1) + 22 * MILLIS_PER_HOUR)), 0);
+        assert!(!TimeZone { utc_offset_minutes: 15 * 60, daylight_saving: DaylightSaving::Never }.is_valid());
+    }
+}
@@ -7,6 +7,8 @@ pub struct StaticConfig {
     pub top_drinks_per_user: usize,
     pub use_persistence: bool,
     pub persistence_file_path: String,
+    //items with less stock than this are listed as running low
+    pub low_stock_threshold: i64,
 }
 
 impl StaticConfig {
@@ -17,6 +19,7 @@ impl StaticConfig {
             top_drinks_per_user: 4,
             use_persistence: true,
             persistence_file_path: filepath.to_string(),
+            low_stock_threshold: 10,
         };
     }
 }
@@ -29,6 +32,7 @@ impl Default for StaticConfig {
             top_drinks_per_user: 4,
             use_persistence: false,
             persistence_file_path: String::new(),
+            low_stock_threshold: 10,
         };
     }
 }
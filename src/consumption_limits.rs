@@ -0,0 +1,228 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use calendar;
+use calendar::TimeZone;
+use datastore::Datastore;
+use datastore::Purchase;
+use datastore::purchase_unit_price;
+use bundles::purchase_units;
+use typescriptify::TypeScriptifyTrait;
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub enum LimitWindow {
+    Day,
+    //24 hours from start_hour on, so a night out counts as one evening
+    Evening { start_hour: u32 },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub enum ConsumptionRule {
+    //category None counts all items
+    MaxItems { category: Option<String>, max_count: u32, window: LimitWindow },
+    MaxSpend { max_cents: u64, window: LimitWindow },
+    MinInterval { category: String, interval_minutes: u32 },
+}
+
+//an admin lets the user buy past the limits from timestamp until valid_until
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct LimitOverride {
+    pub user_id: u32,
+    pub timestamp: i64,
+    pub valid_until: i64,
+    pub comment: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct LimitViolation {
+    pub rule: ConsumptionRule,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ConsumptionLimits {
+    pub rules: HashMap<u32, Vec<ConsumptionRule>>,
+    pub overrides: Vec<LimitOverride>,
+}
+
+impl ConsumptionLimits {
+    //an empty list removes all limits of the user
+    pub fn set_rules(&mut self, user_id: u32, rules: Vec<ConsumptionRule>) {
+        if rules.is_empty() {
+            self.rules.remove(&user_id);
+        } else {
+            self.rules.insert(user_id, rules);
+        }
+    }
+
+    pub fn is_overridden(&self, user_id: u32, timestamp_epoch_millis: i64) -> bool {
+        self.overrides.iter().any(|o| o.user_id == user_id && o.timestamp <= timestamp_epoch_millis && timestamp_epoch_millis < o.valid_until)
+    }
+}
+
+impl LimitWindow {
+    //days and evenings start in the shop's local time
+    pub fn start_of(&self, timestamp_epoch_millis: i64, time_zone: &TimeZone) -> i64 {
+        let shift = time_zone.offset_millis_at(timestamp_epoch_millis);
+        let local = timestamp_epoch_millis + shift;
+        let local_start = match *self {
+            LimitWindow::Day => calendar::start_of_day(local),
+            LimitWindow::Evening { start_hour } => {
+                let offset = i64::from(start_hour) * calendar::MILLIS_PER_HOUR;
+                calendar::start_of_day(local - offset) + offset
+            },
+        };
+        local_start - shift
+    }
+
+    fn describe(&self) -> &'static str {
+        match *self {
+            LimitWindow::Day => "day",
+            LimitWindow::Evening { .. } => "evening",
+        }
+    }
+}
+
+fn in_category(store: &Datastore, item_id: u32, category: &Option<String>) -> bool {
+    category.is_none() || store.items.get(&item_id).map(|i| i.category == *category).unwrap_or(false)
+}
+
+//open simple purchases of the user in the given range, billed purchases are not known anymore
+fn user_purchases(store: &Datastore, user_id: u32, millis_start_inclusive: i64, millis_end_exclusive: i64) -> Vec<&Purchase> {
+    store.purchases
+        .range(millis_start_inclusive, millis_end_exclusive)
+        .iter()
+        .filter(|p| matches!(**p, Purchase::SimplePurchase { consumer_id, .. } if consumer_id == user_id))
+        .collect()
+}
+
+fn item_id_of(purchase: &Purchase) -> u32 {
+    match *purchase {
+        Purchase::SimplePurchase { item_id, .. } => item_id,
+        _ => u32::MAX,
+    }
+}
+
+//units are (item_id, unit_price_cents, count), with bundles split into their components
+fn check_rule(store: &Datastore, user_id: u32, rule: &ConsumptionRule, units: &[(u32, u32, u64)], timestamp_epoch_millis: i64) -> Option<String> {
+    match rule {
+        ConsumptionRule::MaxItems { category, max_count, window } => {
+            let start = window.start_of(timestamp_epoch_millis, &store.time_zone);
+            let bought = user_purchases(store, user_id, start, start + calendar::MILLIS_PER_DAY)
+                .into_iter()
+                .filter(|p| in_category(store, item_id_of(p), category))
+                .count() as u64;
+            let wanted: u64 = units.iter().filter(|(item_id, _, _)| in_category(store, *item_id, category)).map(|(_, _, count)| count).sum();
+            if wanted > 0 && bought + wanted > u64::from(*max_count) {
+                Some(format!("at most {} items of {} per {}, {} already bought", max_count, category.as_deref().unwrap_or("any category"), window.describe(), bought))
+            } else {
+                None
+            }
+        },
+        ConsumptionRule::MaxSpend { max_cents, window } => {
+            let start = window.start_of(timestamp_epoch_millis, &store.time_zone);
+            let spent: u64 = user_purchases(store, user_id, start, start + calendar::MILLIS_PER_DAY)
+                .into_iter()
+                .map(|p| u64::from(purchase_unit_price(store, p)))
+                .sum();
+            let wanted: u64 = units.iter().map(|(_, price, count)| u64::from(*price) * count).sum();
+            if wanted > 0 && spent + wanted > *max_cents {
+                Some(format!("at most {} cents per {}, {} cents already spent", max_cents, window.describe(), spent))
+            } else {
+                None
+            }
+        },
+        ConsumptionRule::MinInterval { category, interval_minutes } => {
+            let limited = Some(category.to_string());
+            let wanted: u64 = units.iter().filter(|(item_id, _, _)| in_category(store, *item_id, &limited)).map(|(_, _, count)| count).sum();
+            let interval = i64::from(*interval_minutes) * calendar::MILLIS_PER_MINUTE;
+            if wanted == 0 || interval == 0 {
+                return None;
+            }
+            let closest = user_purchases(store, user_id, timestamp_epoch_millis - interval + 1, timestamp_epoch_millis + interval)
+                .into_iter()
+                .filter(|p| in_category(store, item_id_of(p), &limited))
+                .map(|p| match *p {
+                    Purchase::SimplePurchase { timestamp_epoch_millis: t, .. } => (t - timestamp_epoch_millis).abs(),
+                    _ => interval,
+                })
+                .min();
+            match closest {
+                Some(distance) => Some(format!("{} minutes between purchases of {}, the closest one is {} minutes away", interval_minutes, category, distance / calendar::MILLIS_PER_MINUTE)),
+                None if wanted > 1 => Some(format!("{} minutes between purchases of {}, only one at a time", interval_minutes, category)),
+                None => None,
+            }
+        },
+    }
+}
+
+//the first rule the purchases, as (item_id, quantity), would break, None if they are allowed
+pub fn limit_violation(store: &Datastore, user_id: u32, purchases: &[(u32, u32)], timestamp_epoch_millis: i64) -> Option<LimitViolation> {
+    if store.consumption_limits.is_overridden(user_id, timestamp_epoch_millis) {
+        return None;
+    }
+    let rules = store.consumption_limits.rules.get(&user_id)?;
+    let units: Vec<(u32, u32, u64)> = purchases.iter()
+        .flat_map(|&(item_id, quantity)| purchase_units(store, item_id, user_id, timestamp_epoch_millis).into_iter().map(move |(unit_item_id, price)| (unit_item_id, price, u64::from(quantity))))
+        .collect();
+    rules.iter()
+        .find_map(|rule| check_rule(store, user_id, rule, &units, timestamp_epoch_millis).map(|reason| LimitViolation {
+            rule: rule.clone(),
+            reason,
+        }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use consumption_limits::*;
+    use datastore::DatastoreQueries;
+    use rustix_backend::WriteBackend;
+
+    #[test]
+    fn limits_reject_purchases_until_an_admin_overrides_them() {
+        let hour = calendar::MILLIS_PER_HOUR;
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.create_user("dieter".to_string());
+        backend.create_item("beer".to_string(), 100, Some("alcohol".to_string()));
+        backend.create_item("mate".to_string(), 150, None);
+
+        assert!(backend.set_consumption_rules(0, vec![
+            ConsumptionRule::MaxItems { category: Some("alcohol".to_string()), max_count: 2, window: LimitWindow::Evening { start_hour: 18 } },
+            ConsumptionRule::MaxSpend { max_cents: 400, window: LimitWindow::Day },
+            ConsumptionRule::MinInterval { category: "alcohol".to_string(), interval_minutes: 30 },
+        ]));
+        assert!(!backend.set_consumption_rules(7, vec![]));
+
+        //the evening goes on past midnight
+        backend.purchase(0, 0, 20 * hour);
+        backend.purchase(0, 0, 20 * hour + 10 * calendar::MILLIS_PER_MINUTE);
+        assert_eq!(backend.datastore.purchases.len(), 1);
+        let violation = backend.datastore.consumption_limit_violation(0, &[0], 20 * hour + 10 * calendar::MILLIS_PER_MINUTE).unwrap();
+        assert_eq!(violation.reason, "30 minutes between purchases of alcohol, the closest one is 10 minutes away");
+        backend.purchase(0, 0, 23 * hour);
+        backend.purchase(0, 0, 25 * hour);
+        assert_eq!(backend.datastore.purchases.len(), 2);
+        assert_eq!(backend.datastore.consumption_limit_violation(0, &[0], 25 * hour).unwrap().rule, ConsumptionRule::MaxItems { category: Some("alcohol".to_string()), max_count: 2, window: LimitWindow::Evening { start_hour: 18 } });
+        //a new evening begins at 18:00
+        assert!(backend.datastore.consumption_limit_violation(0, &[0], 42 * hour).is_none());
+
+        //200 cents were spent on beer that day
+        backend.cart_purchase(0, vec![], vec![1, 1], 21 * hour);
+        backend.purchase(0, 1, 21 * hour);
+        assert_eq!(backend.datastore.purchases.len(), 3);
+        assert_eq!(backend.datastore.consumption_limit_violation(0, &[1, 1], 21 * hour).unwrap().reason, "at most 400 cents per day, 350 cents already spent");
+        //others are not limited
+        assert!(backend.datastore.consumption_limit_violation(1, &[0, 0, 0], 21 * hour).is_none());
+
+        assert!(backend.override_consumption_limits(0, 21 * hour, 22 * hour, "birthday".to_string()));
+        assert!(!backend.override_consumption_limits(0, 22 * hour, 21 * hour, "".to_string()));
+        backend.cart_purchase(0, vec![], vec![0, 1], 21 * hour + 1);
+        assert_eq!(backend.datastore.purchases.len(), 5);
+        backend.purchase(0, 1, 22 * hour);
+        assert_eq!(backend.datastore.purchases.len(), 5);
+    }
+}
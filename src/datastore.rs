@@ -4,26 +4,104 @@
 
 use std::collections::HashSet;
 use std::collections::HashMap;
+use std::cmp::Reverse;
 use left_threaded_avl_tree::ScoredIdTreeMock;
 use suffix_rs::*;
 use suffix_rs::KDTree;
 use left_threaded_avl_tree::AVLTree;
 use typescriptify::TypeScriptifyTrait;
 use unidecode::unidecode;
+use search::SearchDocument;
+use search::SearchHit;
+use search::SearchIndex;
+use purchase_query::PurchaseQuery;
+use purchase_log::PurchaseLog;
+use recommendations::PurchasePatterns;
+use item_rankings::ItemRankings;
+use ledger::BalanceLine;
+use ledger::Ledger;
+use ledger::balance_lines;
+use balance;
+use balance::UserBalance;
+use scheduled_changes::ItemChangeSchedule;
+use scheduled_changes::ScheduledItemChange;
+use inventory::Inventory;
+use inventory::StockMovement;
+use stocktake::StocktakeReport;
+use suppliers::OrderLine;
+use suppliers::Purchasing;
+use suppliers::SupplierOrder;
+use suppliers;
+use deposits::DepositAccounts;
+use prepaid::PrepaidAccount;
+use prepaid::PrepaidAccounts;
+use consumption_limits::ConsumptionLimits;
+use consumption_limits::LimitViolation;
+use consumption_limits::limit_violation;
+use pricing_rules::ItemPrice;
+use pricing_rules::PricingRules;
+use pricing_rules::item_prices;
+use statistics::DatastoreStatistics;
+use calendar::TimeZone;
+use bundles::BundleComponent;
 
 pub trait DatastoreQueries {
     fn get_purchase_timestamp(&self, purchase_id: u64) -> Option<i64>;
     fn top_user_ids(&self, n : u16) -> Vec<u32>;
     fn top_item_ids(&self, user_id: u32, n : u8) -> Vec<u32>;
 
+    //items the user has not bought yet, by co-purchases and time of day, falling back to global popularity
+    fn recommended_item_ids(&self, user_id: u32, timestamp_epoch_millis: i64, n: u8) -> Vec<u32>;
+
+    //simple purchases over all users, optionally only in one category; a time range counts open and billed
+    //purchases within it. free for all giveouts are not sold and never count
+    fn bestselling_item_ids(&self, n: u16, category: Option<&str>, millis_range: Option<(i64, i64)>) -> Vec<u32>;
+
     fn users_searchhit_ids(&self, searchterm: &str) -> Vec<u32>;
     fn items_searchhit_ids(&self, searchterm: &str) -> Vec<u32>;
 
+    //typo-tolerant search, ranked by match quality and then by popularity
+    fn users_ranked_search(&self, searchterm: &str) -> Vec<SearchHit>;
+    fn items_ranked_search(&self, searchterm: &str) -> Vec<SearchHit>;
+
     fn personal_log_filtered(&self, user_id: u32, millis_start_inclusive: i64, millis_end_exclusive: i64) -> Vec<Purchase>;
     fn global_log_filtered(&self, millis_start_inclusive: i64, millis_end_exclusive: i64) -> &[Purchase];
 
+    fn query_purchases(&self, query: &PurchaseQuery) -> Vec<&Purchase>;
+
     fn bills_filtered(&self, user_id: Option<u32>, millis_start_inclusive: i64, millis_end_exclusive: i64) -> Vec<Bill>;
 
+    //not yet billed consumption per item, with current names
+    fn open_balance(&self, user_id: u32) -> Vec<BalanceLine>;
+
+    //what the user owes for everything not yet in a finalized bill, including freebies given and received
+    fn running_balance(&self, user_id: u32) -> UserBalance;
+
+    //item changes not yet applied, for one or all items, in the order they will take effect
+    fn pending_item_changes(&self, item_id: Option<u32>) -> Vec<ScheduledItemChange>;
+
+    //None for items whose stock is not tracked
+    fn stock_level(&self, item_id: u32) -> Option<i64>;
+    //tracked items that are not deleted with less stock than threshold, lowest stock first.
+    //WriteBackend::low_stock_item_ids uses the configured threshold
+    fn low_stock_item_ids(&self, threshold: i64) -> Vec<u32>;
+    fn stock_movements(&self, item_id: u32) -> Vec<StockMovement>;
+    fn stocktake_reports(&self, millis_start_inclusive: i64, millis_end_exclusive: i64) -> Vec<StocktakeReport>;
+
+    //packs to order so that the stock lasts days_of_cover days at the consumption of the last lookback_days days
+    fn restock_suggestions(&self, supplier_id: u32, timestamp: i64, lookback_days: u32, days_of_cover: u32) -> Vec<OrderLine>;
+    fn supplier_orders(&self, supplier_id: Option<u32>) -> Vec<SupplierOrder>;
+    fn supplier_order_csv(&self, order_id: u64) -> Option<String>;
+
+    //None for users who are invoiced
+    fn prepaid_account(&self, user_id: u32) -> Option<PrepaidAccount>;
+
+    //why buying these items at that time would be rejected, None if it is allowed
+    fn consumption_limit_violation(&self, user_id: u32, item_ids: &[u32], timestamp: i64) -> Option<LimitViolation>;
+
+    //prices of all items that are not deleted for one tier, with the pricing rule active at that time
+    fn item_prices_at(&self, timestamp: i64, tier: PriceTier) -> Vec<ItemPrice>;
+
     fn all_categories(&self) -> Vec<String>;
 
 
@@ -58,11 +136,14 @@ pub trait DatastoreQueries {
 
 
 pub trait SuffixTreeRebuildable {
-    fn rebuild_user_tree(&self) -> ();
-    fn rebuild_item_tree(&self) -> ();
+    fn rebuild_user_tree(&mut self);
+    fn rebuild_item_tree(&mut self);
 }
 
 
+//balances of older snapshots, per (user_id, username) and (item_id, itemname) of the time of purchase
+pub type BalanceMap = HashMap<(u32, String), HashMap<(u32, String), u32>>;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Datastore {
 
@@ -72,10 +153,10 @@ pub struct Datastore {
     pub item_id_counter: u32,
 
     pub users: HashMap<u32, User>,
-    pub users_suffix_tree: MockKDTree,
+    pub users_suffix_tree: SearchIndex,
     pub items: HashMap<u32, Item>,
-    pub items_suffix_tree: MockKDTree,
-    pub purchases: Vec<Purchase>,
+    pub items_suffix_tree: SearchIndex,
+    pub purchases: PurchaseLog,
     pub purchase_count: u64,
     pub bills: Vec<Bill>,
     pub top_user_scores: ScoredIdTreeMock,
@@ -84,8 +165,14 @@ pub struct Datastore {
     pub last_millis_of_purchase_by_user: HashMap<u32, i64>,
     pub top_drinks_per_user: HashMap<u32, HashSet<u32>>,
     pub drink_scores_per_user: HashMap<u32, ScoredIdTreeMock>,
-    pub balance_cost_per_user: HashMap<(u32, String), HashMap<(u32, String), u32>>,
-    pub balance_count_per_user: HashMap<(u32, String), HashMap<(u32, String), u32>>,
+    //open consumption per user_id
+    #[serde(default)]
+    pub ledgers: HashMap<u32, Ledger>,
+    //only read from older snapshots and moved into the ledgers on load
+    #[serde(default, skip_serializing, rename = "balance_cost_per_user")]
+    pub legacy_balance_cost_per_user: BalanceMap,
+    #[serde(default, skip_serializing, rename = "balance_count_per_user")]
+    pub legacy_balance_count_per_user: BalanceMap,
     pub used_up_freebies: Vec<Freeby>, //completely mixed
     pub open_freebies: HashMap<u32, Vec<Freeby>>, //per recipient
     pub open_ffa: Vec<Freeby>,
@@ -101,18 +188,41 @@ pub struct Datastore {
     // keeps per user item simplified bill (hashmap<name,hasmap<price,number>>)
     pub categories: HashSet<String>,
 
-
+    #[serde(default)]
+    pub purchase_patterns: PurchasePatterns,
+    #[serde(default)]
+    pub item_rankings: ItemRankings,
+    #[serde(default)]
+    pub item_change_schedule: ItemChangeSchedule,
+    #[serde(default)]
+    pub inventory: Inventory,
+    #[serde(default)]
+    pub stocktakes: Vec<StocktakeReport>,
+    #[serde(default)]
+    pub purchasing: Purchasing,
+    #[serde(default)]
+    pub deposits: DepositAccounts,
+    #[serde(default)]
+    pub prepaid: PrepaidAccounts,
+    #[serde(default)]
+    pub consumption_limits: ConsumptionLimits,
+    #[serde(default)]
+    pub pricing_rules: PricingRules,
+    //local time for weekly pricing windows and consumption limit days
+    #[serde(default)]
+    pub time_zone: TimeZone,
 }
 
 
 
+//full rebuilds, e.g. after loading a snapshot; events keep both indices up to date incrementally
 impl SuffixTreeRebuildable for Datastore {
-    fn rebuild_user_tree(&self) -> () {
-        unimplemented!()
+    fn rebuild_user_tree(&mut self) {
+        self.users_suffix_tree = SearchIndex::build(self.users.values().filter(|u| !u.deleted).map(SearchDocument::for_user));
     }
 
-    fn rebuild_item_tree(&self) -> () {
-        unimplemented!()
+    fn rebuild_item_tree(&mut self) {
+        self.items_suffix_tree = SearchIndex::build(self.items.values().filter(|i| !i.deleted).map(SearchDocument::for_item));
     }
 }
 
@@ -138,16 +248,21 @@ impl DatastoreQueries for Datastore {
     }
 
     fn items_searchhit_ids(&self, searchterm: &str) -> Vec<u32> {
-        return self.items_suffix_tree.search(searchterm).iter().map(|sr : &SearchResult|sr.id).collect();
+        let cleaned_searchterm = unidecode(searchterm);
+        self.items_suffix_tree.search(&cleaned_searchterm).iter().map(|sr : &SearchResult|sr.id).collect()
+    }
+
+    fn users_ranked_search(&self, searchterm: &str) -> Vec<SearchHit> {
+        self.users_suffix_tree.ranked_search(searchterm, |id| self.top_user_scores.get_score(id).unwrap_or(0))
+    }
+
+    fn items_ranked_search(&self, searchterm: &str) -> Vec<SearchHit> {
+        self.items_suffix_tree.ranked_search(searchterm, |id| item_popularity(self, id))
     }
 
     fn personal_log_filtered(&self, user_id: u32, millis_start_inclusive: i64, millis_end_exclusive: i64) -> Vec<Purchase> {
-        let v : Vec<Purchase> = self.purchases.iter()
-            .filter(|p: &&Purchase| {
-            p.get_user_id().eq(&user_id) && p.get_timestamp() >= &millis_start_inclusive && p.get_timestamp() < &millis_end_exclusive
-        })
-            .map(|p: &Purchase| p.clone())
-            .collect();
+        let query = PurchaseQuery::new().user(user_id).between(millis_start_inclusive, millis_end_exclusive);
+        let v : Vec<Purchase> = self.query_purchases(&query).into_iter().cloned().collect();
 
         return v;
     }
@@ -160,6 +275,10 @@ impl DatastoreQueries for Datastore {
         return &self.purchases[from..to];
     }
 
+    fn query_purchases(&self, query: &PurchaseQuery) -> Vec<&Purchase> {
+        query.execute(self)
+    }
+
     fn all_categories(&self) -> Vec<String> {
         let mut v : Vec<String> = Vec::new();
         for x in &self.categories {
@@ -173,6 +292,74 @@ impl DatastoreQueries for Datastore {
             None => return vec![],
         };
     }
+    fn recommended_item_ids(&self, user_id: u32, timestamp_epoch_millis: i64, n: u8) -> Vec<u32> {
+        let candidates = self.items.iter().filter(|&(_, item)| !item.deleted).map(|(id, _)| *id);
+        self.purchase_patterns.recommend(user_id, self.time_zone.to_local(timestamp_epoch_millis), candidates, n as usize)
+    }
+    fn bestselling_item_ids(&self, n: u16, category: Option<&str>, millis_range: Option<(i64, i64)>) -> Vec<u32> {
+        let (millis_start_inclusive, millis_end_exclusive) = match millis_range {
+            Some(range) => range,
+            None => return self.item_rankings.top(n as usize, category),
+        };
+        //open purchases and finalized bills, like item_rankings without free for all giveouts
+        let mut counts: HashMap<u32, u64> = HashMap::new();
+        for record in self.consumption_records(millis_start_inclusive, millis_end_exclusive) {
+            if let (Some(item_id), false) = (record.item_id, record.free_for_all) {
+                let counted = self.items.get(&item_id)
+                    .map(|item| !item.deleted && (category.is_none() || item.category.as_deref() == category))
+                    .unwrap_or(false);
+                if counted {
+                    *counts.entry(item_id).or_insert(0) += record.count;
+                }
+            }
+        }
+        let mut ranked: Vec<(u32, u64)> = counts.into_iter().collect();
+        ranked.sort_by_key(|&(item_id, count)| (Reverse(count), item_id));
+        ranked.into_iter().take(n as usize).map(|(item_id, _)| item_id).collect()
+    }
+    fn open_balance(&self, user_id: u32) -> Vec<BalanceLine> {
+        balance_lines(self, user_id)
+    }
+    fn running_balance(&self, user_id: u32) -> UserBalance {
+        balance::running_balance(self, user_id)
+    }
+    fn pending_item_changes(&self, item_id: Option<u32>) -> Vec<ScheduledItemChange> {
+        self.item_change_schedule.pending_for(item_id)
+    }
+    fn stock_level(&self, item_id: u32) -> Option<i64> {
+        self.inventory.level(item_id)
+    }
+    fn low_stock_item_ids(&self, threshold: i64) -> Vec<u32> {
+        self.inventory.below(threshold, |item_id| self.items.get(&item_id).map(|i| !i.deleted).unwrap_or(false))
+    }
+    fn stock_movements(&self, item_id: u32) -> Vec<StockMovement> {
+        self.inventory.movements(item_id).to_vec()
+    }
+    fn restock_suggestions(&self, supplier_id: u32, timestamp: i64, lookback_days: u32, days_of_cover: u32) -> Vec<OrderLine> {
+        suppliers::restock_suggestions(self, supplier_id, timestamp, lookback_days, days_of_cover)
+    }
+    fn supplier_orders(&self, supplier_id: Option<u32>) -> Vec<SupplierOrder> {
+        self.purchasing.orders.iter().filter(|o| supplier_id.map(|id| o.supplier_id == id).unwrap_or(true)).cloned().collect()
+    }
+    fn supplier_order_csv(&self, order_id: u64) -> Option<String> {
+        suppliers::order_csv(self, order_id)
+    }
+    fn prepaid_account(&self, user_id: u32) -> Option<PrepaidAccount> {
+        self.prepaid.accounts.get(&user_id).cloned()
+    }
+    fn consumption_limit_violation(&self, user_id: u32, item_ids: &[u32], timestamp: i64) -> Option<LimitViolation> {
+        let purchases: Vec<(u32, u32)> = item_ids.iter().map(|item_id| (*item_id, 1)).collect();
+        limit_violation(self, user_id, &purchases, timestamp)
+    }
+    fn item_prices_at(&self, timestamp: i64, tier: PriceTier) -> Vec<ItemPrice> {
+        item_prices(self, timestamp, tier)
+    }
+    fn stocktake_reports(&self, millis_start_inclusive: i64, millis_end_exclusive: i64) -> Vec<StocktakeReport> {
+        self.stocktakes.iter()
+            .filter(|r| r.timestamp_epoch_millis >= millis_start_inclusive && r.timestamp_epoch_millis < millis_end_exclusive)
+            .cloned()
+            .collect()
+    }
     fn bills_filtered(&self, user_id: Option<u32>, millis_start_inclusive: i64, millis_end_exclusive: i64) -> Vec<Bill> {
         let v : Vec<Bill> = self.bills.iter()
             .filter(|b: &&Bill| {
@@ -184,9 +371,7 @@ impl DatastoreQueries for Datastore {
     }
 
     fn get_mut_purchase(&mut self, id: &u64) -> Option<&mut Purchase> {
-        let idx = self.purchases.binary_search_by(|p| p.get_unique_id().cmp(id));
-
-        return idx.map(move |id| self.purchases.get_mut(id).unwrap()).ok();
+        return self.purchases.get_mut(*id);
     }
 
     fn get_mut_bill(&mut self, timestamp_from: i64, timestamp_to: i64) -> Option<&mut Bill> {
@@ -258,6 +443,8 @@ impl DatastoreQueries for Datastore {
                             //if user isn't billed per field, add to externally excluded list
                         } else if bill.users_that_will_not_be_billed.contains(&uid) {
                             //else if user is in internal exclusion list of bill, add to internally excluded list
+                        } else if self.prepaid.is_prepaid(uid) {
+                            //prepaid users are settled from their balance and need no external id
                         } else if usr.external_user_id.is_none() {
                             // else add user to other list
                             users_undefined_indices.push(uid);
@@ -300,50 +487,16 @@ impl DatastoreQueries for Datastore {
         return v;
     }
 
-    fn remove_purchases_indices(&mut self, mut indices: Vec<usize>) {
-        indices.sort();
-        for idx in indices.iter().rev() {
-            self.purchases.remove(*idx);
-        }
+    fn remove_purchases_indices(&mut self, indices: Vec<usize>) {
+        self.purchases.remove_indices(&indices);
     }
     fn get_budget_freeby_id_useable_for(&self, recipient_id: u32) -> Option<usize> {
-        for (idx, freeby) in self.open_freebies.get(&recipient_id).unwrap_or(&Vec::new()).iter().enumerate() {
-            match *freeby {
-                Freeby::Transfer { .. } => {
-                  return Some(idx);
-                },
-                _ => (),
-            }
-        }
-        return None;
+        self.open_freebies.get(&recipient_id).and_then(|freebies| budget_freeby_index(freebies))
     }
 
     fn get_count_freeby_id_useable_for(&self, recipient_id: u32, item : u32) -> Option<usize> {
-        let cat : Option<String> = self.items.get(&item).unwrap().clone().category;
-        for (idx, freeby) in self.open_freebies.get(&recipient_id).unwrap_or(&Vec::new()).iter().enumerate() {
-            match *freeby {
-                Freeby::Classic { ref allowed_categories,
-                    ref allowed_drinks, .. } => {
-                    let cat = cat.clone();
-                    if allowed_drinks.contains(&item) {
-                        return Some(idx);
-                    } else {
-                        match cat {
-                            Some(c) => {
-                                if allowed_categories.contains(&c) {
-                                    return Some(idx);
-                                } else {
-                                    ()
-                                }
-                            },
-                            None => (),
-                        }
-                    }
-                },
-                _ => (),
-            }
-        }
-        return None;
+        let item = self.items.get(&item).unwrap();
+        self.open_freebies.get(&recipient_id).and_then(|freebies| count_freeby_index(freebies, item))
     }
     fn get_ffa_freeby(&self, id: u64) -> Option<&Freeby> {
         let found_open = self.open_ffa.binary_search_by(|f|f.get_id().cmp(&id));
@@ -399,30 +552,49 @@ pub fn matches_usergroup(user_id: &Option<u32>, usergroup: &UserGroup) -> bool {
 }
 
 
+//first budget giveout among the open freebies of one recipient
+pub fn budget_freeby_index(freebies: &[Freeby]) -> Option<usize> {
+    freebies.iter().position(|f| matches!(*f, Freeby::Transfer { .. }))
+}
+
+//first count giveout among the open freebies of one recipient that covers the item
+pub fn count_freeby_index(freebies: &[Freeby], item: &Item) -> Option<usize> {
+    freebies.iter().position(|f| match *f {
+        Freeby::Classic { .. } => f.allows(item),
+        _ => false,
+    })
+}
+
+//the price recorded on purchase; purchases of older versions fall back to the item's price history
+//and the tier the user had at the time
+pub fn purchase_unit_price(store: &Datastore, purchase: &Purchase) -> u32 {
+    match purchase.get_unit_price() {
+        Some(price) => price,
+        None if purchase.has_item_id() => {
+            let tier = user_price_tier(store, *purchase.get_user_id(), *purchase.get_timestamp());
+            store.items.get(purchase.get_item_id()).map(|i| i.price_for(tier, *purchase.get_timestamp())).unwrap_or(0)
+        },
+        None => 0,
+    }
+}
+
+//number of purchases of this item over all users
+fn item_popularity(store: &Datastore, item_id: u32) -> u32 {
+    store.item_rankings.score(item_id)
+}
+
+
 fn matches_userset(user_id: &Option<u32>, usergroup: &HashSet<u32>) -> bool {
     return user_id.map(|id| usergroup.contains(&id)).unwrap_or(true);
 }
 
 //returns lowest and highest vector index to get all purchases in given timespan
+//purchases have to be sorted by timestamp; several purchases may share the same timestamp
 pub fn find_purchase_indices(purchases : &[Purchase], millis_start_inclusive: i64, millis_end_exclusive: i64) -> (usize, usize) {
-    let a = purchases.binary_search_by(|probe|probe.get_timestamp().cmp(&millis_start_inclusive));
-
-    let first = match a {
-        Ok(b) => b,
-        Err(b) => b,
-    };
+    let first = purchases.partition_point(|probe| *probe.get_timestamp() < millis_start_inclusive);
+    let last = purchases.partition_point(|probe| *probe.get_timestamp() < millis_end_exclusive);
 
-    let o = purchases.binary_search_by(|probe|{
-        let c = millis_end_exclusive + 1;
-        probe.get_timestamp().cmp(&c)
-    });
-
-    let last = match o {
-        Ok(g) => g,
-        Err(g) => g,
-    };
-
-    return (first,last);
+    return (first, last.max(first));
 }
 
 
@@ -452,15 +624,10 @@ impl Itemable for Datastore {
 
 impl Purchaseable for Datastore {
     fn get_purchase(&self, id: u64) -> Option<Purchase> {
-        match self.purchases.binary_search_by(|p|p.get_unique_id().cmp(&id)) {
-            Ok(idx) => self.purchases.get(idx).map(|p|p.clone()),
-            _ => None,
-        }
+        self.purchases.get(id).cloned()
     }
     fn get_purchase_mut(&mut self, id: u64) -> Option<&mut Purchase> {
-        return self.purchases.iter_mut().find(|p|{
-            p.has_unique_id(id)
-        });
+        return self.purchases.get_mut(id);
     }
 }
 
@@ -490,15 +657,12 @@ impl SearchableElement for Item {
 
 impl Default for Datastore {
     fn default() -> Self {
-        let empty_user_vec: Vec<User> = Vec::new();
-        let empty_item_vec: Vec<User> = Vec::new();
-
         return Datastore {
             users: HashMap::new(),
-            users_suffix_tree: MockKDTree::build(&empty_user_vec, true),
+            users_suffix_tree: SearchIndex::default(),
             items: HashMap::new(),
-            items_suffix_tree: MockKDTree::build(&empty_item_vec, true),
-            purchases: Vec::new(),
+            items_suffix_tree: SearchIndex::default(),
+            purchases: PurchaseLog::default(),
             purchase_count: 0,
             bills: Vec::new(),
             top_user_scores: ScoredIdTreeMock::default(),
@@ -507,8 +671,9 @@ impl Default for Datastore {
             highlighted_users: HashSet::new(),
             top_drinks_per_user: HashMap::new(),
             drink_scores_per_user: HashMap::new(),
-            balance_cost_per_user: HashMap::new(),
-            balance_count_per_user: HashMap::new(),
+            ledgers: HashMap::new(),
+            legacy_balance_cost_per_user: HashMap::new(),
+            legacy_balance_count_per_user: HashMap::new(),
             used_up_freebies: Vec::new(),
             open_freebies: HashMap::new(),
             open_ffa: Vec::new(),
@@ -516,6 +681,17 @@ impl Default for Datastore {
             freeby_id_counter: 0,
             item_id_counter: 0,
             categories: HashSet::new(),
+            purchase_patterns: PurchasePatterns::default(),
+            item_rankings: ItemRankings::default(),
+            item_change_schedule: ItemChangeSchedule::default(),
+            inventory: Inventory::default(),
+            stocktakes: Vec::new(),
+            purchasing: Purchasing::default(),
+            deposits: DepositAccounts::default(),
+            prepaid: PrepaidAccounts::default(),
+            consumption_limits: ConsumptionLimits::default(),
+            pricing_rules: PricingRules::default(),
+            time_zone: TimeZone::default(),
             version: 0,
         };
     }
@@ -547,6 +723,64 @@ pub struct User {
     pub is_sepa: bool,
     pub highlight_in_ui: bool,
     pub deleted: bool,
+    #[serde(default)]
+    pub price_tier: PriceTier,
+    //sorted by valid_from_millis; empty for users of older versions, who keep price_tier throughout
+    #[serde(default)]
+    pub price_tier_history: Vec<PriceTierChange>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, TypeScriptify)]
+pub struct PriceTierChange {
+    pub valid_from_millis: i64,
+    pub price_tier: PriceTier,
+}
+
+//members pay the item's cost_cents, other tiers the item's price for their tier if it has one
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, TypeScriptify)]
+pub enum PriceTier {
+    #[default]
+    Member,
+    Guest,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, TypeScriptify)]
+pub struct TierPrice {
+    pub tier: PriceTier,
+    pub cost_cents: u32,
+}
+
+//cost_cents None means that the tier pays the member price from then on
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, TypeScriptify)]
+pub struct TierPricePoint {
+    pub tier: PriceTier,
+    pub valid_from_millis: i64,
+    pub cost_cents: Option<u32>,
+}
+
+//the tier the user had at the given time, members before their first change
+pub fn user_price_tier(store: &Datastore, user_id: u32, timestamp_epoch_millis: i64) -> PriceTier {
+    store.users.get(&user_id).map(|u| u.price_tier_at(timestamp_epoch_millis)).unwrap_or_default()
+}
+
+impl User {
+    pub fn price_tier_at(&self, timestamp_epoch_millis: i64) -> PriceTier {
+        if self.price_tier_history.is_empty() {
+            return self.price_tier;
+        }
+        let idx = self.price_tier_history.partition_point(|c| c.valid_from_millis <= timestamp_epoch_millis);
+        idx.checked_sub(1).map(|i| self.price_tier_history[i].price_tier).unwrap_or_default()
+    }
+
+    //a change at the same time replaces the earlier one, price_tier follows the newest change
+    pub fn set_price_tier(&mut self, price_tier: PriceTier, valid_from_millis: i64) {
+        let change = PriceTierChange { valid_from_millis, price_tier };
+        match self.price_tier_history.binary_search_by_key(&valid_from_millis, |c| c.valid_from_millis) {
+            Ok(idx) => self.price_tier_history[idx] = change,
+            Err(idx) => self.price_tier_history.insert(idx, change),
+        }
+        self.price_tier = self.price_tier_history.last().map(|c| c.price_tier).unwrap_or(price_tier);
+    }
 }
 
 impl Clone for User {
@@ -559,6 +793,8 @@ impl Clone for User {
             is_sepa: self.is_sepa,
             highlight_in_ui: self.highlight_in_ui,
             deleted: self.deleted,
+            price_tier: self.price_tier,
+            price_tier_history: self.price_tier_history.clone(),
         };
     }
 }
@@ -570,8 +806,99 @@ pub struct Item {
     pub name: String,
     pub item_id: u32,
     pub category: Option<String>,
+    //as of the last applied change, see pricing_rules::base_price for changes that are due but not applied yet
     pub cost_cents: u32,
     pub deleted: bool,
+    //sorted by valid_from_millis; empty for items of older versions
+    #[serde(default)]
+    pub price_history: Vec<PricePoint>,
+    //what we pay per unit, sorted by valid_from_millis; empty as long as it is unknown
+    #[serde(default)]
+    pub acquisition_costs: Vec<PricePoint>,
+    //bottle deposit charged per unit on top of the price, 0 for none
+    #[serde(default)]
+    pub deposit_cents: u32,
+    //current prices of tiers other than Member, following tier_price_history
+    #[serde(default)]
+    pub tier_prices: Vec<TierPrice>,
+    //sorted by valid_from_millis; empty for items of older versions, whose tier_prices apply throughout
+    #[serde(default)]
+    pub tier_price_history: Vec<TierPricePoint>,
+    //empty for plain items. purchases of a bundle are recorded as purchases of its components
+    #[serde(default)]
+    pub bundle: Vec<BundleComponent>,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, TypeScriptify)]
+pub struct PricePoint {
+    //i64::MIN for the price an item was created with
+    pub valid_from_millis: i64,
+    pub cost_cents: u32,
+}
+
+//the point in effect at the given time, times before the first point use the first one
+fn price_point_at(history: &[PricePoint], timestamp_epoch_millis: i64) -> Option<u32> {
+    let idx = history.partition_point(|p| p.valid_from_millis <= timestamp_epoch_millis);
+    history.get(idx.saturating_sub(1)).map(|p| p.cost_cents)
+}
+
+//a point at the same time replaces the earlier one
+fn insert_price_point(history: &mut Vec<PricePoint>, point: PricePoint) {
+    match history.binary_search_by_key(&point.valid_from_millis, |p| p.valid_from_millis) {
+        Ok(idx) => history[idx] = point,
+        Err(idx) => history.insert(idx, point),
+    }
+}
+
+impl Item {
+    //price that applied at the given time, the current one if there is no history
+    pub fn price_at(&self, timestamp_epoch_millis: i64) -> u32 {
+        price_point_at(&self.price_history, timestamp_epoch_millis).unwrap_or(self.cost_cents)
+    }
+
+    //cost_cents follows the newest price
+    pub fn set_price(&mut self, cost_cents: u32, valid_from_millis: i64) {
+        insert_price_point(&mut self.price_history, PricePoint { valid_from_millis, cost_cents });
+        self.cost_cents = self.price_history.last().map(|p| p.cost_cents).unwrap_or(cost_cents);
+    }
+
+    //the tier's own price at that time, the member price while it has none
+    pub fn price_for(&self, tier: PriceTier, timestamp_epoch_millis: i64) -> u32 {
+        let tier_price = if self.tier_price_history.iter().any(|p| p.tier == tier) {
+            self.tier_price_history.iter()
+                .rfind(|p| p.tier == tier && p.valid_from_millis <= timestamp_epoch_millis)
+                .and_then(|p| p.cost_cents)
+        } else {
+            self.tier_prices.iter().find(|p| p.tier == tier).map(|p| p.cost_cents)
+        };
+        tier_price.unwrap_or_else(|| self.price_at(timestamp_epoch_millis))
+    }
+
+    //None removes the tier's price, so the tier pays the member price again.
+    //a point of the tier at the same time replaces the earlier one, tier_prices follows the newest points
+    pub fn set_tier_price(&mut self, tier: PriceTier, cost_cents: Option<u32>, valid_from_millis: i64) {
+        let point = TierPricePoint { tier, valid_from_millis, cost_cents };
+        match self.tier_price_history.iter().position(|p| p.tier == tier && p.valid_from_millis == valid_from_millis) {
+            Some(idx) => self.tier_price_history[idx] = point,
+            None => {
+                let idx = self.tier_price_history.partition_point(|p| p.valid_from_millis <= valid_from_millis);
+                self.tier_price_history.insert(idx, point);
+            },
+        }
+        let current = self.tier_price_history.iter().rfind(|p| p.tier == tier).and_then(|p| p.cost_cents);
+        self.tier_prices.retain(|p| p.tier != tier);
+        if let Some(cost_cents) = current {
+            self.tier_prices.push(TierPrice { tier, cost_cents });
+        }
+    }
+
+    pub fn acquisition_cost_at(&self, timestamp_epoch_millis: i64) -> Option<u32> {
+        price_point_at(&self.acquisition_costs, timestamp_epoch_millis)
+    }
+
+    pub fn set_acquisition_cost(&mut self, cost_cents: u32, valid_from_millis: i64) {
+        insert_price_point(&mut self.acquisition_costs, PricePoint { valid_from_millis, cost_cents });
+    }
 }
 
 
@@ -621,6 +948,17 @@ pub struct BillUserDayInstance {
 
     pub ffa_giveouts : HashMap<u32, u32>,
     pub giveouts_to_user_id: HashMap<u32, PaidFor>,
+
+    //cents per item id at the prices recorded on purchase, empty in bills of older versions
+    #[serde(default)]
+    pub personally_consumed_cents: HashMap<u32, u64>,
+    #[serde(default)]
+    pub ffa_giveouts_cents: HashMap<u32, u64>,
+    //deposits are listed apart from the item prices
+    #[serde(default)]
+    pub deposit_charged_cents: u64,
+    #[serde(default)]
+    pub deposit_refunded_cents: u64,
 }
 
 #[derive(Default, Debug, Serialize, Deserialize, Clone, TypeScriptify)]
@@ -628,9 +966,12 @@ pub struct ExportableBillData {
     pub all_users : HashMap<u32, User>,
     pub all_items : HashMap<u32, Item>,
     pub user_consumption: HashMap<u32, BillUserInstance>,
+    //cents taken from the prepaid balance per prepaid user, these users are not invoiced
+    #[serde(default)]
+    pub prepaid_settlements: HashMap<u32, i64>,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize, Clone, TypeScriptify)]
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
 pub struct PricedSpecial {
     pub purchase_id: u64,
     pub price: u32,
@@ -644,6 +985,8 @@ pub struct PaidFor {
     pub count_giveouts_used: HashMap<u32,u32>,
     pub budget_given: u64,
     pub budget_gotten: u64,
+    #[serde(default)]
+    pub count_giveouts_cents: HashMap<u32, u64>,
 }
 
 
@@ -673,6 +1016,42 @@ impl Bill {
         let day = (time - first_day_begin) / day_length;
         return day as usize;
     }
+
+    //inverse of get_day_index
+    pub fn get_day_start(&self, day_index: usize) -> i64 {
+        let day_length: i64 = 1000i64 * 3600i64 * 24i64;
+        (self.timestamp_from / day_length + day_index as i64) * day_length
+    }
+
+    //amount the user has to pay for a finalized bill: own items and specials, ffa and count giveouts
+    //paid for others, budget given to others, minus budget received from others, plus deposits charged minus refunded
+    pub fn total_cents_of(&self, user_id: u32) -> i64 {
+        let data = &self.finalized_data;
+        //bills of older versions only know the item prices at finalization
+        let cost = |counts: &HashMap<u32, u32>, cents: &HashMap<u32, u64>| -> i64 {
+            counts.iter().map(|(id, count)| match cents.get(id) {
+                Some(cents) => *cents as i64,
+                None => data.all_items.get(id).map(|i| i64::from(i.cost_cents)).unwrap_or(0) * i64::from(*count),
+            }).sum()
+        };
+        let mut total: i64 = 0;
+        for day in data.user_consumption.get(&user_id).iter().flat_map(|c| c.per_day.values()) {
+            total += cost(&day.personally_consumed, &day.personally_consumed_cents);
+            total += day.specials_consumed.iter().map(|s| i64::from(s.price)).sum::<i64>();
+            total += cost(&day.ffa_giveouts, &day.ffa_giveouts_cents);
+            total += day.deposit_charged_cents as i64 - day.deposit_refunded_cents as i64;
+            for paid_for in day.giveouts_to_user_id.values() {
+                total += cost(&paid_for.count_giveouts_used, &paid_for.count_giveouts_cents);
+                total += paid_for.budget_given as i64 - paid_for.budget_gotten as i64;
+            }
+        }
+        total
+    }
+
+    //what is left to invoice after prepaid balances were charged
+    pub fn invoiced_cents_of(&self, user_id: u32) -> i64 {
+        self.total_cents_of(user_id) - self.finalized_data.prepaid_settlements.get(&user_id).cloned().unwrap_or(0)
+    }
 }
 
 
@@ -686,6 +1065,8 @@ pub trait PurchaseFunctions {
     fn get_timestamp(&self) -> &i64;
     fn get_special_set_price(&self) -> Option<u32>;
     fn is_special(&self) -> bool;
+    //recorded item price of simple and ffa purchases, the set price of specials
+    fn get_unit_price(&self) -> Option<u32>;
 }
 
 
@@ -1131,6 +1512,9 @@ pub enum Purchase {
         item_id: u32,
         freeby_id: u64,
         donor: u32,
+        //price per unit at the time of purchase, missing in purchases of older versions
+        #[serde(default)]
+        unit_price_cents: Option<u32>,
     },
     //UndoPurchase { unique_id: u64 }, //deletes instance directly from purchases
     SimplePurchase {
@@ -1138,6 +1522,12 @@ pub enum Purchase {
         timestamp_epoch_millis: i64,
         item_id: u32, //buys one instance of this item
         consumer_id: u32,
+        //price per unit at the time of purchase, missing in purchases of older versions
+        #[serde(default)]
+        unit_price_cents: Option<u32>,
+        //unique id of the first unit recorded by the same purchase, which is undone as a whole
+        #[serde(default)]
+        group_id: Option<u64>,
     },
     SpecialPurchase {
         unique_id: u64,
@@ -1166,6 +1556,7 @@ impl PurchaseFunctions for Purchase {
                 ref timestamp_epoch_millis,
                 ref item_id,
                 ref consumer_id,
+                ..
             } => {
                 return *unique_id;
             },
@@ -1175,12 +1566,21 @@ impl PurchaseFunctions for Purchase {
                 ref item_id,
                 ref freeby_id,
                 ref donor,
+                ..
             } => {
                 return *unique_id;
             },
         }
     }
 
+    fn get_unit_price(&self) -> Option<u32> {
+        match *self {
+            Purchase::SpecialPurchase { specialcost, .. } => specialcost,
+            Purchase::SimplePurchase { unit_price_cents, .. } => unit_price_cents,
+            Purchase::FFAPurchase { unit_price_cents, .. } => unit_price_cents,
+        }
+    }
+
     fn get_special_set_price(&self) -> Option<u32> {
         match self {
             &Purchase::SpecialPurchase {
@@ -1210,6 +1610,7 @@ impl PurchaseFunctions for Purchase {
                 ref timestamp_epoch_millis,
                 ref item_id,
                 ref consumer_id,
+                ..
             } => {
                 return *unique_id == other;
             },
@@ -1219,6 +1620,7 @@ impl PurchaseFunctions for Purchase {
                 ref item_id,
                 ref freeby_id,
                 ref donor,
+                ..
             } => {
                 return *unique_id == other;
             }
@@ -1241,6 +1643,7 @@ impl PurchaseFunctions for Purchase {
                 ref timestamp_epoch_millis,
                 ref item_id,
                 ref consumer_id,
+                ..
             } => {
                 return true;
             },
@@ -1250,6 +1653,7 @@ impl PurchaseFunctions for Purchase {
                 ref item_id,
                 ref freeby_id,
                 ref donor,
+                ..
             } => {
                 return true;
             }
@@ -1271,6 +1675,7 @@ impl PurchaseFunctions for Purchase {
                 ref timestamp_epoch_millis,
                 ref item_id,
                 ref consumer_id,
+                ..
             } => {
                 return consumer_id;
             },
@@ -1280,6 +1685,7 @@ impl PurchaseFunctions for Purchase {
                 ref item_id,
                 ref freeby_id,
                 ref donor,
+                ..
             } => {
                 return donor;
             }
@@ -1302,6 +1708,7 @@ impl PurchaseFunctions for Purchase {
                 ref timestamp_epoch_millis,
                 ref item_id,
                 ref consumer_id,
+                ..
             } => {
                 return item_id;
             },
@@ -1311,6 +1718,7 @@ impl PurchaseFunctions for Purchase {
                 ref item_id,
                 ref freeby_id,
                 ref donor,
+                ..
             } => {
                 return item_id;
             }
@@ -1333,6 +1741,7 @@ impl PurchaseFunctions for Purchase {
                 ref timestamp_epoch_millis,
                 ref item_id,
                 ref consumer_id,
+                ..
             } => {
                 return timestamp_epoch_millis;
             },
@@ -1342,6 +1751,7 @@ impl PurchaseFunctions for Purchase {
                 ref item_id,
                 ref freeby_id,
                 ref donor,
+                ..
             } => {
                 return timestamp_epoch_millis;
             }
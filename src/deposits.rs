@@ -0,0 +1,147 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::collections::HashSet;
+use datastore::Bill;
+use datastore::matches_usergroup;
+use typescriptify::TypeScriptifyTrait;
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum DepositKind {
+    //paid together with the item by whoever pays the item
+    Charge,
+    //paid back for returned bottles
+    Refund,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct DepositEntry {
+    pub user_id: u32,
+    pub item_id: u32,
+    pub timestamp_epoch_millis: i64,
+    pub kind: DepositKind,
+    pub count: u32,
+    //always positive, the kind tells the direction
+    pub cents: u64,
+    //the purchase a charge belongs to
+    pub purchase_id: Option<u64>,
+}
+
+//deposits not yet part of a finalized bill
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct DepositAccounts {
+    pub open: Vec<DepositEntry>,
+}
+
+impl DepositAccounts {
+    //items without a deposit are not recorded
+    pub fn charge(&mut self, user_id: u32, item_id: u32, deposit_cents: u32, timestamp_epoch_millis: i64, purchase_id: u64) {
+        if deposit_cents > 0 {
+            self.open.push(DepositEntry {
+                user_id,
+                item_id,
+                timestamp_epoch_millis,
+                kind: DepositKind::Charge,
+                count: 1,
+                cents: u64::from(deposit_cents),
+                purchase_id: Some(purchase_id),
+            });
+        }
+    }
+
+    pub fn refund(&mut self, user_id: u32, item_id: u32, count: u32, deposit_cents: u32, timestamp_epoch_millis: i64) {
+        self.open.push(DepositEntry {
+            user_id,
+            item_id,
+            timestamp_epoch_millis,
+            kind: DepositKind::Refund,
+            count,
+            cents: u64::from(deposit_cents) * u64::from(count),
+            purchase_id: None,
+        });
+    }
+
+    //for undone purchases
+    pub fn remove_purchase(&mut self, purchase_id: u64) {
+        self.open.retain(|e| e.purchase_id != Some(purchase_id));
+    }
+
+    //charges of the billed purchases and refunds of billed users inside the bill's timespan
+    pub fn take_billed(&mut self, purchase_ids: &HashSet<u64>, bill: &Bill) -> Vec<DepositEntry> {
+        let (billed, open): (Vec<DepositEntry>, Vec<DepositEntry>) = self.open.drain(..).partition(|e| match e.kind {
+            DepositKind::Charge => e.purchase_id.map(|id| purchase_ids.contains(&id)).unwrap_or(false),
+            DepositKind::Refund => e.timestamp_epoch_millis >= bill.timestamp_from
+                && e.timestamp_epoch_millis < bill.timestamp_to
+                && matches_usergroup(&Some(e.user_id), &bill.users),
+        });
+        self.open = open;
+        billed
+    }
+
+    //charged and refunded cents of one user
+    pub fn open_totals(&self, user_id: u32) -> (u64, u64) {
+        let sum = |kind: DepositKind| -> u64 { self.open.iter().filter(|e| e.user_id == user_id && e.kind == kind).map(|e| e.cents).sum() };
+        (sum(DepositKind::Charge), sum(DepositKind::Refund))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use datastore::DatastoreQueries;
+    use datastore::FreebyAble;
+    use datastore::UserGroup;
+    use rustix_backend::WriteBackend;
+    use rustix_event_shop::BLEvents;
+
+    #[test]
+    fn deposits_are_charged_on_purchase_and_refunded_on_return() {
+        let mut backend = ::build_transient_backend();
+        for (idx, name) in ["klaus", "dieter"].iter().enumerate() {
+            backend.create_user(name.to_string());
+            backend.update_user(idx as u32, name.to_string(), true, false, Some(name.to_string()), true);
+        }
+        backend.create_item("beer".to_string(), 100, None);
+        backend.create_item("mate".to_string(), 150, None);
+        assert!(backend.set_item_deposit(0, 8));
+        assert!(backend.set_item_deposit(1, 15));
+        assert!(!backend.set_item_deposit(9, 15));
+
+        backend.purchase(0, 0, 10);
+        backend.purchase(0, 0, 11);
+        backend.purchase(0, 1, 12);
+        backend.create_ffa(vec![], vec![1], 1, "".to_string(), 13, 1);
+        let ffa_id = backend.datastore.open_ffa[0].get_id();
+        backend.ffa_purchase(ffa_id, 1, 14);
+        //undone purchases give the deposit back
+        backend.undo_purchase(2);
+
+        assert!(backend.return_deposit(0, 0, 3, 20));
+        assert!(!backend.return_deposit(0, 0, 0, 20));
+        backend.create_item("water".to_string(), 50, None);
+        assert!(!backend.return_deposit(0, 2, 1, 20));
+
+        let klaus = backend.datastore.running_balance(0);
+        assert_eq!((klaus.deposit_charged_cents, klaus.deposit_refunded_cents), (8 + 15, 24));
+        assert_eq!(klaus.total_cents, 100 + 150 + 8 + 15 - 24);
+        let dieter = backend.datastore.running_balance(1);
+        assert_eq!((dieter.deposit_charged_cents, dieter.total_cents), (15, 150 + 15));
+
+        //a later deposit change only applies to later purchases and returns
+        backend.set_item_deposit(0, 10);
+        backend.return_deposit(1, 0, 1, 200);
+
+        backend.create_bill(0, 100, UserGroup::AllUsers, "".to_string());
+        assert!(backend.apply(&BLEvents::FinalizeBill { timestamp_from: 0, timestamp_to: 100 }));
+        let bill = &backend.datastore.bills[0];
+        assert_eq!(bill.total_cents_of(0), klaus.total_cents);
+        assert_eq!(bill.total_cents_of(1), dieter.total_cents);
+        let day = &bill.finalized_data.user_consumption[&0].per_day[&0];
+        assert_eq!((day.deposit_charged_cents, day.deposit_refunded_cents), (23, 24));
+
+        //the return after the bill stays open
+        assert_eq!(backend.datastore.running_balance(0).total_cents, 0);
+        assert_eq!(backend.datastore.running_balance(1).deposit_refunded_cents, 10);
+    }
+}
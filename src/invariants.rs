@@ -0,0 +1,860 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::BTreeSet;
+use calendar;
+use config::StaticConfig;
+use datastore::Datastore;
+use datastore::Freeby;
+use datastore::FreebyAble;
+use datastore::Purchase;
+use datastore::PurchaseFunctions;
+use datastore::purchase_unit_price;
+use item_rankings::ItemRankings;
+use recommendations::PurchasePatterns;
+use ledger::Ledger;
+use ledger::LedgerEntry;
+use left_threaded_avl_tree::AVLTree;
+use left_threaded_avl_tree::ScoredIdTreeMock;
+use search::SearchDocument;
+use search::SearchIndex;
+use typescriptify::TypeScriptifyTrait;
+
+
+//everything in the datastore that is not primary data (users, items, purchases, bills) but derived from it
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, TypeScriptify)]
+pub enum DerivedStructure {
+    Counters,
+    PurchaseOrder,
+    UserScores,
+    TopUsers,
+    DrinkScores,
+    TopDrinks,
+    ItemScores,
+    PurchasePatterns,
+    HighlightedUsers,
+    LastPurchases,
+    CostBalances,
+    CountBalances,
+    Freebies,
+    Categories,
+    UserSearchIndex,
+    ItemSearchIndex,
+}
+
+//in dependency order, e.g. top sets come after the scores they are extracted from
+pub const ALL_DERIVED_STRUCTURES: [DerivedStructure; 16] = [
+    DerivedStructure::Counters,
+    DerivedStructure::PurchaseOrder,
+    DerivedStructure::UserScores,
+    DerivedStructure::TopUsers,
+    DerivedStructure::DrinkScores,
+    DerivedStructure::TopDrinks,
+    DerivedStructure::ItemScores,
+    DerivedStructure::PurchasePatterns,
+    DerivedStructure::HighlightedUsers,
+    DerivedStructure::LastPurchases,
+    DerivedStructure::CostBalances,
+    DerivedStructure::CountBalances,
+    DerivedStructure::Freebies,
+    DerivedStructure::Categories,
+    DerivedStructure::UserSearchIndex,
+    DerivedStructure::ItemSearchIndex,
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct Discrepancy {
+    pub structure: DerivedStructure,
+    //the affected entry, e.g. "user 3 / item 7"
+    pub key: String,
+    pub stored: String,
+    pub expected: String,
+    //false if the primary data itself is broken, which no rebuild can fix
+    pub repairable: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, TypeScriptify)]
+pub struct InvariantReport {
+    pub discrepancies: Vec<Discrepancy>,
+    pub repaired: Vec<DerivedStructure>,
+}
+
+impl InvariantReport {
+    pub fn is_consistent(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+
+    pub fn affected_structures(&self) -> Vec<DerivedStructure> {
+        let set: BTreeSet<DerivedStructure> = self.discrepancies.iter().map(|d| d.structure).collect();
+        set.into_iter().collect()
+    }
+
+    //number of changed entries per structure, for a short summary
+    pub fn count_per_structure(&self) -> Vec<(DerivedStructure, usize)> {
+        self.affected_structures().into_iter()
+            .map(|structure| (structure, self.discrepancies.iter().filter(|d| d.structure == structure).count()))
+            .collect()
+    }
+
+    fn add<S: ::std::fmt::Debug, E: ::std::fmt::Debug>(&mut self, structure: DerivedStructure, key: String, stored: S, expected: E) {
+        self.discrepancies.push(Discrepancy {
+            structure,
+            key,
+            stored: format!("{:?}", stored),
+            expected: format!("{:?}", expected),
+            repairable: true,
+        });
+    }
+
+    fn add_unrepairable(&mut self, structure: DerivedStructure, key: String, stored: &str, expected: &str) {
+        self.discrepancies.push(Discrepancy {
+            structure,
+            key,
+            stored: stored.to_string(),
+            expected: expected.to_string(),
+            repairable: false,
+        });
+    }
+}
+
+
+pub trait DatastoreInvariants {
+    //recomputes all derived state from primary data and lists every difference
+    fn check_invariants(&self, config: &StaticConfig) -> InvariantReport;
+
+    //checks, then rebuilds every structure with a repairable discrepancy in place
+    fn repair_invariants(&mut self, config: &StaticConfig) -> InvariantReport;
+
+    //recomputes every derived structure, e.g. after a fix to apply or after loading a snapshot.
+    //the report lists what changed, with the old value as stored and the new one as expected
+    fn rebuild_derived_state(&mut self, config: &StaticConfig) -> InvariantReport;
+}
+
+
+//number of simple purchases per consumer and item, both open and already finalized
+//(finalizing a bill removes purchases, but scores are kept)
+pub fn simple_purchase_counts(store: &Datastore) -> HashMap<u32, HashMap<u32, u32>> {
+    let mut counts: HashMap<u32, HashMap<u32, u32>> = HashMap::new();
+    for purchase in &store.purchases {
+        if let Purchase::SimplePurchase { item_id, consumer_id, .. } = *purchase {
+            *counts.entry(consumer_id).or_default().entry(item_id).or_insert(0) += 1;
+        }
+    }
+    for bill in store.bills.iter().filter(|b| b.bill_state.is_finalized()) {
+        for (user_id, instance) in &bill.finalized_data.user_consumption {
+            for day in instance.per_day.values() {
+                for (item_id, count) in &day.personally_consumed {
+                    *counts.entry(*user_id).or_default().entry(*item_id).or_insert(0) += *count;
+                }
+                //count giveouts are listed under the donor, but were bought by the recipient
+                for paid_for in day.giveouts_to_user_id.values() {
+                    for (item_id, count) in &paid_for.count_giveouts_used {
+                        *counts.entry(paid_for.recipient_id).or_default().entry(*item_id).or_insert(0) += *count;
+                    }
+                }
+            }
+        }
+    }
+    counts
+}
+
+pub fn expected_user_scores(store: &Datastore, counts: &HashMap<u32, HashMap<u32, u32>>) -> HashMap<u32, u32> {
+    store.users.values().filter(|u| !u.deleted).map(|u| {
+        (u.user_id, counts.get(&u.user_id).map(|c| c.values().sum()).unwrap_or(0))
+    }).collect()
+}
+
+pub fn expected_drink_scores(store: &Datastore, counts: &HashMap<u32, HashMap<u32, u32>>) -> HashMap<u32, HashMap<u32, u32>> {
+    store.users.values().filter(|u| !u.deleted).map(|u| {
+        let scores = store.items.values().filter(|i| !i.deleted).map(|i| {
+            (i.item_id, counts.get(&u.user_id).and_then(|c| c.get(&i.item_id)).cloned().unwrap_or(0))
+        }).collect();
+        (u.user_id, scores)
+    }).collect()
+}
+
+//global and per category scores of every item that is not deleted
+pub fn expected_item_scores(store: &Datastore, counts: &HashMap<u32, HashMap<u32, u32>>) -> (HashMap<u32, u32>, HashMap<String, HashMap<u32, u32>>) {
+    let mut global: HashMap<u32, u32> = HashMap::new();
+    let mut per_category: HashMap<String, HashMap<u32, u32>> = HashMap::new();
+    for item in store.items.values().filter(|i| !i.deleted) {
+        let score = counts.values().filter_map(|c| c.get(&item.item_id)).sum();
+        global.insert(item.item_id, score);
+        if let Some(ref category) = item.category {
+            per_category.entry(category.to_string()).or_default().insert(item.item_id, score);
+        }
+    }
+    (global, per_category)
+}
+
+//patterns of all simple purchases, open and finalized. finalized bills only keep days, so the hours
+//can only be recomputed for open purchases; billed hours are taken over from the stored patterns
+pub fn expected_purchase_patterns(store: &Datastore, counts: &HashMap<u32, HashMap<u32, u32>>) -> PurchasePatterns {
+    let mut patterns = PurchasePatterns::default();
+    for (user_id, items) in counts {
+        let mut item_ids: Vec<u32> = items.keys().cloned().collect();
+        item_ids.sort();
+        for (idx, item_id) in item_ids.iter().enumerate() {
+            for other in &item_ids[..idx] {
+                *patterns.co_purchases.entry(*item_id).or_default().entry(*other).or_insert(0) += 1;
+                *patterns.co_purchases.entry(*other).or_default().entry(*item_id).or_insert(0) += 1;
+            }
+            *patterns.popularity.entry(*item_id).or_insert(0) += items[item_id];
+        }
+        patterns.items_per_user.insert(*user_id, items.clone());
+    }
+    patterns.purchases_per_hour = open_purchases_per_hour(store);
+    patterns
+}
+
+pub fn open_purchases_per_hour(store: &Datastore) -> HashMap<u32, Vec<u32>> {
+    let mut hours: HashMap<u32, Vec<u32>> = HashMap::new();
+    for purchase in &store.purchases {
+        if let Purchase::SimplePurchase { item_id, timestamp_epoch_millis, .. } = *purchase {
+            let hour = calendar::hour_of_day(store.time_zone.to_local(timestamp_epoch_millis)) as usize;
+            hours.entry(item_id).or_insert_with(|| vec![0; 24])[hour] += 1;
+        }
+    }
+    hours
+}
+
+pub fn expected_highlighted_users(store: &Datastore) -> HashSet<u32> {
+    store.users.values().filter(|u| !u.deleted && u.highlight_in_ui).map(|u| u.user_id).collect()
+}
+
+//latest open simple purchase per user; older values may come from purchases that are billed by now
+pub fn latest_open_purchases(store: &Datastore) -> HashMap<u32, i64> {
+    let mut latest: HashMap<u32, i64> = HashMap::new();
+    for purchase in &store.purchases {
+        if let Purchase::SimplePurchase { timestamp_epoch_millis, consumer_id, .. } = *purchase {
+            let t = latest.entry(consumer_id).or_insert(timestamp_epoch_millis);
+            *t = cmp::max(*t, timestamp_epoch_millis);
+        }
+    }
+    latest
+}
+
+//ledgers of all open purchases, at the prices recorded on purchase
+pub fn expected_ledgers(store: &Datastore) -> HashMap<u32, Ledger> {
+    let mut ledgers: HashMap<u32, Ledger> = HashMap::new();
+    for purchase in store.purchases.iter().filter(|p| p.has_item_id()) {
+        if let (Some(user), Some(item)) = (store.users.get(purchase.get_user_id()), store.items.get(purchase.get_item_id())) {
+            ledgers.entry(user.user_id).or_default().add(item.item_id, 1, purchase_unit_price(store, purchase));
+        }
+    }
+    ledgers
+}
+
+pub fn expected_categories(store: &Datastore) -> HashSet<String> {
+    store.items.values().filter(|i| !i.deleted).filter_map(|i| i.category.clone()).collect()
+}
+
+pub fn expected_user_search_index(store: &Datastore) -> SearchIndex {
+    SearchIndex::build(store.users.values().filter(|u| !u.deleted).map(SearchDocument::for_user))
+}
+
+pub fn expected_item_search_index(store: &Datastore) -> SearchIndex {
+    SearchIndex::build(store.items.values().filter(|i| !i.deleted).map(SearchDocument::for_item))
+}
+
+
+pub fn tree_scores(tree: &ScoredIdTreeMock) -> HashMap<u32, u32> {
+    tree.extract_top(usize::MAX).into_iter().map(|id| (id, tree.get_score(id).unwrap_or(0))).collect()
+}
+
+pub fn scored_tree(scores: &HashMap<u32, u32>) -> ScoredIdTreeMock {
+    let mut ids: Vec<u32> = scores.keys().cloned().collect();
+    ids.sort();
+    let mut tree = ScoredIdTreeMock::default();
+    for id in ids {
+        tree.insert(id);
+        for _ in 0..scores[&id] {
+            tree.increment_by_one(id);
+        }
+    }
+    tree
+}
+
+//top sets are only re-extracted when needed, so ties may be resolved differently than by a fresh extraction.
+//a set is fine as long as no outsider beats a member, and it only stays short while outsiders have no score
+pub fn is_valid_top(top: &HashSet<u32>, scores: &HashMap<u32, u32>, n: usize) -> bool {
+    if top.len() > n || top.iter().any(|id| !scores.contains_key(id)) {
+        return false;
+    }
+    let full = top.len() == cmp::min(n, scores.len());
+    let lowest_inside = top.iter().map(|id| scores[id]).min();
+    let highest_outside = scores.iter().filter(|&(id, _)| !top.contains(id)).map(|(_, s)| *s).max();
+    match (lowest_inside, highest_outside) {
+        (_, None) => true,
+        (None, Some(outside)) => full || outside == 0,
+        (Some(inside), Some(outside)) => inside >= outside && (full || outside == 0),
+    }
+}
+
+pub fn is_exhausted(freeby: &Freeby) -> bool {
+    match *freeby {
+        Freeby::Transfer { cents_worth_total, cents_worth_used, .. } => cents_worth_used >= cents_worth_total,
+        Freeby::Classic { allowed_number_total, allowed_number_used, .. } |
+        Freeby::FFA { allowed_number_total, allowed_number_used, .. } => allowed_number_used >= allowed_number_total,
+    }
+}
+
+pub fn freeby_recipient(freeby: &Freeby) -> Option<u32> {
+    match *freeby {
+        Freeby::Transfer { recipient, .. } | Freeby::Classic { recipient, .. } => Some(recipient),
+        Freeby::FFA { .. } => None,
+    }
+}
+
+fn sorted<T: Ord + Clone>(set: &HashSet<T>) -> Vec<T> {
+    let mut v: Vec<T> = set.iter().cloned().collect();
+    v.sort();
+    v
+}
+
+//either the costs or the counts of all ledger entries, per (user_id, item_id)
+fn flatten<F: Fn(&LedgerEntry) -> u32>(ledgers: &HashMap<u32, Ledger>, value: F) -> HashMap<(u32, u32), u32> {
+    let mut flat = HashMap::new();
+    for (user_id, ledger) in ledgers {
+        for (item_id, entry) in &ledger.items {
+            flat.insert((*user_id, *item_id), value(entry));
+        }
+    }
+    flat
+}
+
+fn compare_balances<F: Fn(&LedgerEntry) -> u32>(report: &mut InvariantReport, structure: DerivedStructure, stored: &HashMap<u32, Ledger>, expected: &HashMap<u32, Ledger>, value: F) {
+    let stored = flatten(stored, &value);
+    let expected = flatten(expected, &value);
+    let mut keys: Vec<&(u32, u32)> = stored.keys().chain(expected.keys().filter(|k| !stored.contains_key(k))).collect();
+    keys.sort();
+    for key in keys {
+        let s = stored.get(key).cloned().unwrap_or(0);
+        let e = expected.get(key).cloned().unwrap_or(0);
+        if s != e {
+            report.add(structure, format!("user {} / item {}", key.0, key.1), s, e);
+        }
+    }
+}
+
+fn compare_search_index(report: &mut InvariantReport, structure: DerivedStructure, stored: &SearchIndex, expected: &SearchIndex) {
+    let mut ids: Vec<u32> = stored.ids().into_iter().chain(expected.ids()).collect();
+    ids.sort();
+    ids.dedup();
+    for id in ids {
+        if stored.get(id) != expected.get(id) {
+            report.add(structure, format!("document {}", id), stored.get(id), expected.get(id));
+        }
+    }
+}
+
+//smallest values the id counters may have without handing out an id twice
+fn counter_minimums(store: &Datastore) -> (u32, u32, u64, u64) {
+    let min_user = store.users.keys().max().map(|id| id + 1).unwrap_or(0);
+    let min_item = store.items.keys().max().map(|id| id + 1).unwrap_or(0);
+    let min_freeby = all_freebies(store).map(|f| f.get_id()).max().unwrap_or(0);
+    let min_purchase = cmp::max(
+        store.purchases.iter().map(|p| p.get_unique_id()).max().unwrap_or(0),
+        store.bills.iter()
+            .flat_map(|b| b.finalized_data.user_consumption.values())
+            .flat_map(|u| u.per_day.values())
+            .flat_map(|d| d.specials_consumed.iter())
+            .map(|s| s.purchase_id)
+            .max()
+            .unwrap_or(0));
+    (min_user, min_item, min_freeby, min_purchase)
+}
+
+fn check_counters(store: &Datastore, report: &mut InvariantReport) {
+    let (min_user, min_item, min_freeby, min_purchase) = counter_minimums(store);
+    if store.user_id_counter < min_user {
+        report.add(DerivedStructure::Counters, "user_id_counter".to_string(), store.user_id_counter, min_user);
+    }
+    if store.item_id_counter < min_item {
+        report.add(DerivedStructure::Counters, "item_id_counter".to_string(), store.item_id_counter, min_item);
+    }
+    if store.freeby_id_counter < min_freeby {
+        report.add(DerivedStructure::Counters, "freeby_id_counter".to_string(), store.freeby_id_counter, min_freeby);
+    }
+    if store.purchase_count < min_purchase {
+        report.add(DerivedStructure::Counters, "purchase_count".to_string(), store.purchase_count, min_purchase);
+    }
+}
+
+fn check_purchases(store: &Datastore, report: &mut InvariantReport) {
+    let mut seen: HashSet<u64> = HashSet::new();
+    for purchase in &store.purchases {
+        let id = purchase.get_unique_id();
+        if !seen.insert(id) {
+            report.add_unrepairable(DerivedStructure::PurchaseOrder, format!("purchase {}", id), "used more than once", "unique id");
+        }
+        if !store.users.contains_key(purchase.get_user_id()) {
+            report.add_unrepairable(DerivedStructure::PurchaseOrder, format!("purchase {}", id), &format!("user {}", purchase.get_user_id()), "existing user");
+        }
+        if purchase.has_item_id() && !store.items.contains_key(purchase.get_item_id()) {
+            report.add_unrepairable(DerivedStructure::PurchaseOrder, format!("purchase {}", id), &format!("item {}", purchase.get_item_id()), "existing item");
+        }
+    }
+    //range queries rely on purchases being sorted by timestamp, lookups on the id index
+    for pair in store.purchases.windows(2) {
+        if pair[0].get_timestamp() > pair[1].get_timestamp() {
+            report.add(DerivedStructure::PurchaseOrder, format!("purchase {}", pair[1].get_unique_id()), pair[1].get_timestamp(), format!("after {}", pair[0].get_timestamp()));
+        }
+    }
+    for (idx, purchase) in store.purchases.iter().enumerate() {
+        if store.purchases.position(purchase.get_unique_id()) != Some(idx) {
+            report.add(DerivedStructure::PurchaseOrder, format!("purchase {}", purchase.get_unique_id()), store.purchases.position(purchase.get_unique_id()), Some(idx));
+        }
+    }
+}
+
+fn check_scores(store: &Datastore, config: &StaticConfig, report: &mut InvariantReport) {
+    let counts = simple_purchase_counts(store);
+
+    let stored_user_scores = tree_scores(&store.top_user_scores);
+    let expected_scores = expected_user_scores(store, &counts);
+    let mut user_ids: Vec<u32> = stored_user_scores.keys().chain(expected_scores.keys()).cloned().collect();
+    user_ids.sort();
+    user_ids.dedup();
+    for user_id in &user_ids {
+        if stored_user_scores.get(user_id) != expected_scores.get(user_id) {
+            report.add(DerivedStructure::UserScores, format!("user {}", user_id), stored_user_scores.get(user_id), expected_scores.get(user_id));
+        }
+    }
+    if !is_valid_top(&store.top_users, &expected_scores, config.users_in_top_users) {
+        let expected_top: HashSet<u32> = scored_tree(&expected_scores).extract_top(config.users_in_top_users).into_iter().collect();
+        report.add(DerivedStructure::TopUsers, String::new(), sorted(&store.top_users), sorted(&expected_top));
+    }
+
+    let expected_drinks = expected_drink_scores(store, &counts);
+    let mut drink_user_ids: Vec<u32> = store.drink_scores_per_user.keys().chain(expected_drinks.keys()).cloned().collect();
+    drink_user_ids.sort();
+    drink_user_ids.dedup();
+    for user_id in drink_user_ids {
+        let stored = store.drink_scores_per_user.get(&user_id).map(tree_scores);
+        let expected = expected_drinks.get(&user_id);
+        match (stored.as_ref(), expected) {
+            (Some(stored), Some(expected)) => {
+                let mut item_ids: Vec<u32> = stored.keys().chain(expected.keys()).cloned().collect();
+                item_ids.sort();
+                item_ids.dedup();
+                for item_id in item_ids {
+                    if stored.get(&item_id) != expected.get(&item_id) {
+                        report.add(DerivedStructure::DrinkScores, format!("user {} / item {}", user_id, item_id), stored.get(&item_id), expected.get(&item_id));
+                    }
+                }
+            },
+            (stored, expected) => {
+                report.add(DerivedStructure::DrinkScores, format!("user {}", user_id), stored.is_some(), expected.is_some());
+            },
+        }
+    }
+
+    let mut top_user_ids: Vec<u32> = store.top_drinks_per_user.keys().chain(expected_drinks.keys()).cloned().collect();
+    top_user_ids.sort();
+    top_user_ids.dedup();
+    for user_id in top_user_ids {
+        let stored = store.top_drinks_per_user.get(&user_id);
+        match (stored, expected_drinks.get(&user_id)) {
+            (Some(stored), Some(expected)) => if !is_valid_top(stored, expected, config.top_drinks_per_user) {
+                let expected_top: HashSet<u32> = scored_tree(expected).extract_top(config.top_drinks_per_user).into_iter().collect();
+                report.add(DerivedStructure::TopDrinks, format!("user {}", user_id), sorted(stored), sorted(&expected_top));
+            },
+            (stored, expected) => {
+                report.add(DerivedStructure::TopDrinks, format!("user {}", user_id), stored.map(sorted), expected.map(|_| "top drinks"));
+            },
+        }
+    }
+
+    let (expected_global, expected_per_category) = expected_item_scores(store, &counts);
+    let stored_global = tree_scores(&store.item_rankings.global);
+    if stored_global != expected_global {
+        report.add(DerivedStructure::ItemScores, "global".to_string(), sorted_scores(&stored_global), sorted_scores(&expected_global));
+    }
+    let mut categories: Vec<&String> = store.item_rankings.per_category.keys().chain(expected_per_category.keys()).collect();
+    categories.sort();
+    categories.dedup();
+    for category in categories {
+        let stored = store.item_rankings.per_category.get(category).map(tree_scores);
+        let expected = expected_per_category.get(category);
+        if stored.as_ref() != expected {
+            report.add(DerivedStructure::ItemScores, format!("category {}", category), stored.as_ref().map(sorted_scores), expected.map(sorted_scores));
+        }
+    }
+
+    check_purchase_patterns(store, &counts, report);
+}
+
+fn check_purchase_patterns(store: &Datastore, counts: &HashMap<u32, HashMap<u32, u32>>, report: &mut InvariantReport) {
+    let stored = &store.purchase_patterns;
+    let expected = expected_purchase_patterns(store, counts);
+    let mut user_ids: Vec<u32> = stored.items_per_user.keys().chain(expected.items_per_user.keys()).cloned().collect();
+    user_ids.sort();
+    user_ids.dedup();
+    for user_id in user_ids {
+        let s = stored.items_per_user.get(&user_id).map(sorted_scores);
+        let e = expected.items_per_user.get(&user_id).map(sorted_scores);
+        if s != e {
+            report.add(DerivedStructure::PurchasePatterns, format!("user {}", user_id), s, e);
+        }
+    }
+    let mut item_ids: Vec<u32> = stored.co_purchases.keys().chain(expected.co_purchases.keys()).cloned().collect();
+    item_ids.sort();
+    item_ids.dedup();
+    for item_id in item_ids {
+        let s = stored.co_purchases.get(&item_id).map(sorted_scores);
+        let e = expected.co_purchases.get(&item_id).map(sorted_scores);
+        if s != e {
+            report.add(DerivedStructure::PurchasePatterns, format!("co purchases of item {}", item_id), s, e);
+        }
+    }
+    if stored.popularity != expected.popularity {
+        report.add(DerivedStructure::PurchasePatterns, "popularity".to_string(), sorted_scores(&stored.popularity), sorted_scores(&expected.popularity));
+    }
+    //billed purchases may add to the stored hours, but the open ones have to be in there
+    let mut hour_item_ids: Vec<&u32> = expected.purchases_per_hour.keys().collect();
+    hour_item_ids.sort();
+    for item_id in hour_item_ids {
+        let open = &expected.purchases_per_hour[item_id];
+        let hours = stored.purchases_per_hour.get(item_id);
+        if hours.map(|h| h.iter().zip(open).any(|(s, e)| s < e)).unwrap_or(true) {
+            report.add(DerivedStructure::PurchasePatterns, format!("hours of item {}", item_id), hours, format!("at least {:?}", open));
+        }
+    }
+}
+
+fn repair_purchase_patterns(store: &mut Datastore) {
+    let counts = simple_purchase_counts(store);
+    let mut patterns = expected_purchase_patterns(store, &counts);
+    for (item_id, hours) in &store.purchase_patterns.purchases_per_hour {
+        if patterns.popularity.contains_key(item_id) {
+            let merged = patterns.purchases_per_hour.entry(*item_id).or_insert_with(|| vec![0; 24]);
+            for (m, h) in merged.iter_mut().zip(hours) {
+                *m = cmp::max(*m, *h);
+            }
+        }
+    }
+    store.purchase_patterns = patterns;
+}
+
+fn sorted_scores(scores: &HashMap<u32, u32>) -> Vec<(u32, u32)> {
+    let mut v: Vec<(u32, u32)> = scores.iter().map(|(id, score)| (*id, *score)).collect();
+    v.sort();
+    v
+}
+
+fn check_users(store: &Datastore, report: &mut InvariantReport) {
+    let expected_highlighted = expected_highlighted_users(store);
+    if store.highlighted_users != expected_highlighted {
+        report.add(DerivedStructure::HighlightedUsers, String::new(), sorted(&store.highlighted_users), sorted(&expected_highlighted));
+    }
+
+    let mut latest: Vec<(u32, i64)> = latest_open_purchases(store).into_iter().collect();
+    latest.sort();
+    for (user_id, timestamp) in latest {
+        let stored = store.last_millis_of_purchase_by_user.get(&user_id);
+        if stored.map(|t| *t < timestamp).unwrap_or(true) {
+            report.add(DerivedStructure::LastPurchases, format!("user {}", user_id), stored, timestamp);
+        }
+    }
+}
+
+fn all_freebies<'a>(store: &'a Datastore) -> impl Iterator<Item = &'a Freeby> + 'a {
+    store.open_ffa.iter()
+        .chain(store.open_freebies.values().flat_map(|v| v.iter()))
+        .chain(store.used_up_freebies.iter())
+}
+
+fn check_freebies(store: &Datastore, report: &mut InvariantReport) {
+    let mut seen: HashSet<u64> = HashSet::new();
+    for freeby in all_freebies(store) {
+        if !seen.insert(freeby.get_id()) {
+            report.add_unrepairable(DerivedStructure::Freebies, format!("freeby {}", freeby.get_id()), "stored more than once", "unique id");
+        }
+    }
+
+    for freeby in &store.open_ffa {
+        if freeby_recipient(freeby).is_some() {
+            report.add(DerivedStructure::Freebies, format!("freeby {}", freeby.get_id()), "open free for all", format!("open for user {}", freeby_recipient(freeby).unwrap()));
+        } else if is_exhausted(freeby) {
+            report.add(DerivedStructure::Freebies, format!("freeby {}", freeby.get_id()), "open", "used up");
+        }
+    }
+    let mut recipients: Vec<&u32> = store.open_freebies.keys().collect();
+    recipients.sort();
+    for recipient in recipients {
+        for freeby in &store.open_freebies[recipient] {
+            if freeby_recipient(freeby) != Some(*recipient) {
+                report.add(DerivedStructure::Freebies, format!("freeby {}", freeby.get_id()), format!("open for user {}", recipient), freeby_recipient(freeby).map(|r| format!("open for user {}", r)).unwrap_or_else(|| "open free for all".to_string()));
+            } else if is_exhausted(freeby) {
+                report.add(DerivedStructure::Freebies, format!("freeby {}", freeby.get_id()), "open", "used up");
+            }
+        }
+    }
+    for freeby in &store.used_up_freebies {
+        if !is_exhausted(freeby) {
+            report.add(DerivedStructure::Freebies, format!("freeby {}", freeby.get_id()), "used up", "open");
+        }
+    }
+
+    //every open free for all purchase has to be accounted for in its freeby
+    let mut ffa_purchases: HashMap<u64, u16> = HashMap::new();
+    for purchase in &store.purchases {
+        if let Purchase::FFAPurchase { freeby_id, .. } = *purchase {
+            *ffa_purchases.entry(freeby_id).or_insert(0) += 1;
+        }
+    }
+    for freeby in all_freebies(store) {
+        if let Freeby::FFA { id, allowed_number_used, .. } = *freeby {
+            let bought = ffa_purchases.get(&id).cloned().unwrap_or(0);
+            if allowed_number_used < bought {
+                report.add_unrepairable(DerivedStructure::Freebies, format!("freeby {}", id), &format!("{} used", allowed_number_used), &format!("at least {} used", bought));
+            }
+        }
+    }
+}
+
+fn check_catalog(store: &Datastore, report: &mut InvariantReport) {
+    let expected = expected_categories(store);
+    if store.categories != expected {
+        report.add(DerivedStructure::Categories, String::new(), sorted(&store.categories), sorted(&expected));
+    }
+    compare_search_index(report, DerivedStructure::UserSearchIndex, &store.users_suffix_tree, &expected_user_search_index(store));
+    compare_search_index(report, DerivedStructure::ItemSearchIndex, &store.items_suffix_tree, &expected_item_search_index(store));
+}
+
+
+fn repair_freebies(store: &mut Datastore) {
+    let mut freebies: Vec<Freeby> = store.open_ffa.drain(..).collect();
+    let mut recipients: Vec<u32> = store.open_freebies.keys().cloned().collect();
+    recipients.sort();
+    for recipient in recipients {
+        freebies.append(store.open_freebies.get_mut(&recipient).unwrap());
+    }
+    freebies.append(&mut store.used_up_freebies);
+
+    for freeby in freebies {
+        if is_exhausted(&freeby) {
+            store.used_up_freebies.push(freeby);
+        } else if let Some(recipient) = freeby_recipient(&freeby) {
+            store.open_freebies.entry(recipient).or_default().push(freeby);
+        } else {
+            store.open_ffa.push(freeby);
+        }
+    }
+    store.used_up_freebies.sort_by_key(|f| f.get_id());
+}
+
+fn repair_structure(store: &mut Datastore, config: &StaticConfig, structure: DerivedStructure) {
+    match structure {
+        DerivedStructure::Counters => {
+            let (min_user, min_item, min_freeby, min_purchase) = counter_minimums(store);
+            store.user_id_counter = cmp::max(store.user_id_counter, min_user);
+            store.item_id_counter = cmp::max(store.item_id_counter, min_item);
+            store.freeby_id_counter = cmp::max(store.freeby_id_counter, min_freeby);
+            store.purchase_count = cmp::max(store.purchase_count, min_purchase);
+        },
+        DerivedStructure::PurchaseOrder => store.purchases = store.purchases.iter().cloned().collect(),
+        DerivedStructure::UserScores => {
+            let counts = simple_purchase_counts(store);
+            store.top_user_scores = scored_tree(&expected_user_scores(store, &counts));
+        },
+        DerivedStructure::TopUsers => {
+            store.top_users = store.top_user_scores.extract_top(config.users_in_top_users).into_iter().collect();
+        },
+        DerivedStructure::DrinkScores => {
+            let counts = simple_purchase_counts(store);
+            store.drink_scores_per_user = expected_drink_scores(store, &counts).iter().map(|(user_id, scores)| (*user_id, scored_tree(scores))).collect();
+        },
+        DerivedStructure::ItemScores => {
+            let counts = simple_purchase_counts(store);
+            let (global, per_category) = expected_item_scores(store, &counts);
+            store.item_rankings = ItemRankings {
+                global: scored_tree(&global),
+                per_category: per_category.iter().map(|(category, scores)| (category.to_string(), scored_tree(scores))).collect(),
+            };
+        },
+        DerivedStructure::TopDrinks => {
+            let users: Vec<u32> = store.users.values().filter(|u| !u.deleted).map(|u| u.user_id).collect();
+            store.top_drinks_per_user.retain(|user_id, _| users.contains(user_id));
+            for user_id in users {
+                let scores = store.drink_scores_per_user.get(&user_id).map(tree_scores).unwrap_or_default();
+                let valid = store.top_drinks_per_user.get(&user_id).map(|top| is_valid_top(top, &scores, config.top_drinks_per_user)).unwrap_or(false);
+                if !valid {
+                    let top: HashSet<u32> = scored_tree(&scores).extract_top(config.top_drinks_per_user).into_iter().collect();
+                    store.top_drinks_per_user.insert(user_id, top);
+                }
+            }
+        },
+        DerivedStructure::PurchasePatterns => repair_purchase_patterns(store),
+        DerivedStructure::HighlightedUsers => store.highlighted_users = expected_highlighted_users(store),
+        DerivedStructure::LastPurchases => {
+            for (user_id, timestamp) in latest_open_purchases(store) {
+                let t = store.last_millis_of_purchase_by_user.entry(user_id).or_insert(timestamp);
+                *t = cmp::max(*t, timestamp);
+            }
+        },
+        //both balances live in the same ledger entries
+        DerivedStructure::CostBalances | DerivedStructure::CountBalances => store.ledgers = expected_ledgers(store),
+        DerivedStructure::Freebies => repair_freebies(store),
+        DerivedStructure::Categories => store.categories = expected_categories(store),
+        DerivedStructure::UserSearchIndex => store.users_suffix_tree = expected_user_search_index(store),
+        DerivedStructure::ItemSearchIndex => store.items_suffix_tree = expected_item_search_index(store),
+    }
+}
+
+
+impl DatastoreInvariants for Datastore {
+    fn check_invariants(&self, config: &StaticConfig) -> InvariantReport {
+        let mut report = InvariantReport::default();
+        check_counters(self, &mut report);
+        check_purchases(self, &mut report);
+        check_scores(self, config, &mut report);
+        check_users(self, &mut report);
+        let expected_ledgers = expected_ledgers(self);
+        compare_balances(&mut report, DerivedStructure::CostBalances, &self.ledgers, &expected_ledgers, |e| e.cost_cents);
+        compare_balances(&mut report, DerivedStructure::CountBalances, &self.ledgers, &expected_ledgers, |e| e.count);
+        check_freebies(self, &mut report);
+        check_catalog(self, &mut report);
+        report
+    }
+
+    fn repair_invariants(&mut self, config: &StaticConfig) -> InvariantReport {
+        let mut report = self.check_invariants(config);
+        let mut structures: BTreeSet<DerivedStructure> = report.discrepancies.iter()
+            .filter(|d| d.repairable)
+            .map(|d| d.structure)
+            .collect();
+        //top sets have to follow repaired scores
+        if structures.contains(&DerivedStructure::UserScores) {
+            structures.insert(DerivedStructure::TopUsers);
+        }
+        if structures.contains(&DerivedStructure::DrinkScores) {
+            structures.insert(DerivedStructure::TopDrinks);
+        }
+        //the enum is declared in dependency order
+        for structure in &structures {
+            repair_structure(self, config, *structure);
+        }
+        report.repaired = structures.into_iter().collect();
+        report
+    }
+
+    fn rebuild_derived_state(&mut self, config: &StaticConfig) -> InvariantReport {
+        let mut report = self.check_invariants(config);
+        let top_users_before = self.top_users.clone();
+        let top_drinks_before = self.top_drinks_per_user.clone();
+
+        //without any top drinks left, every set gets extracted anew
+        self.top_drinks_per_user.clear();
+        for structure in ALL_DERIVED_STRUCTURES.iter() {
+            repair_structure(self, config, *structure);
+        }
+
+        //fresh extraction may resolve ties differently than before, which is a change as well
+        if self.top_users != top_users_before && !report.affected_structures().contains(&DerivedStructure::TopUsers) {
+            report.add(DerivedStructure::TopUsers, String::new(), sorted(&top_users_before), sorted(&self.top_users));
+        }
+        let reported_drinks: HashSet<String> = report.discrepancies.iter()
+            .filter(|d| d.structure == DerivedStructure::TopDrinks)
+            .map(|d| d.key.to_string())
+            .collect();
+        let mut user_ids: Vec<u32> = self.top_drinks_per_user.keys().cloned().collect();
+        user_ids.sort();
+        for user_id in user_ids {
+            let key = format!("user {}", user_id);
+            let after = &self.top_drinks_per_user[&user_id];
+            if top_drinks_before.get(&user_id) != Some(after) && !reported_drinks.contains(&key) {
+                match top_drinks_before.get(&user_id) {
+                    Some(before) => report.add(DerivedStructure::TopDrinks, key, sorted(before), sorted(after)),
+                    None => report.add(DerivedStructure::TopDrinks, key, None::<Vec<u32>>, sorted(after)),
+                }
+            }
+        }
+
+        report.repaired = ALL_DERIVED_STRUCTURES.to_vec();
+        report
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use invariants::*;
+    use datastore::UserGroup;
+    use rustix_backend::WriteBackend;
+    use rustix_event_shop::BLEvents;
+
+    #[test]
+    fn regular_usage_keeps_invariants() {
+        let mut backend = ::build_transient_backend();
+        let config = StaticConfig::default();
+        backend.create_user("klaus".to_string());
+        backend.create_user("dieter".to_string());
+        backend.create_item("beer".to_string(), 100, Some("Alcohol".to_string()));
+        backend.create_item("soda".to_string(), 50, None);
+        backend.create_free_count(vec![], vec![1], 1, "soda on me".to_string(), 0, 1, 0);
+        backend.purchase(0, 0, 10);
+        backend.purchase(0, 1, 20);
+        backend.purchase(1, 0, 30);
+        backend.purchase(1, 1, 40);
+        assert!(backend.undo_purchase(4));
+        backend.update_user(0, "klaus".to_string(), true, false, Some("k".to_string()), true);
+        backend.update_user(1, "dieter b".to_string(), true, true, Some("d".to_string()), true);
+        backend.update_item(1, "lemonade".to_string(), 50, Some("Soft".to_string()));
+
+        assert_eq!(backend.datastore.check_invariants(&config), InvariantReport::default());
+
+        backend.create_bill(0, 100, UserGroup::AllUsers, "first bill".to_string());
+        assert!(backend.apply(&BLEvents::FinalizeBill { timestamp_from: 0, timestamp_to: 100 }));
+        backend.delete_item(0);
+        backend.delete_user(0);
+
+        assert_eq!(backend.datastore.check_invariants(&config), InvariantReport::default());
+        assert!(backend.datastore.ledgers.is_empty());
+        assert_eq!(backend.datastore.used_up_freebies.len(), 1);
+        assert_eq!(backend.datastore.top_user_scores.get_score(1), Some(1));
+    }
+
+    #[test]
+    fn drift_is_reported_and_repaired() {
+        let mut backend = ::build_transient_backend();
+        let config = StaticConfig::default();
+        backend.create_user("klaus".to_string());
+        backend.create_item("beer".to_string(), 100, None);
+        backend.purchase(0, 0, 10);
+        backend.purchase(0, 0, 20);
+
+        backend.datastore.top_user_scores.increment_by_one(0);
+        backend.datastore.ledgers.values_mut().for_each(|l| l.items.values_mut().for_each(|e| e.cost_cents -= 1));
+        backend.datastore.highlighted_users.insert(0);
+        backend.datastore.purchase_count = 1;
+        backend.datastore.items_suffix_tree.remove(0);
+        backend.datastore.purchase_patterns.popularity.insert(0, 3);
+
+        let report = backend.datastore.check_invariants(&config);
+        assert_eq!(report.affected_structures(), vec![
+            DerivedStructure::Counters,
+            DerivedStructure::UserScores,
+            DerivedStructure::PurchasePatterns,
+            DerivedStructure::HighlightedUsers,
+            DerivedStructure::CostBalances,
+            DerivedStructure::ItemSearchIndex,
+        ]);
+        let cost = report.discrepancies.iter().find(|d| d.structure == DerivedStructure::CostBalances).unwrap();
+        assert_eq!(cost.key, "user 0 / item 0");
+        assert_eq!((cost.stored.as_str(), cost.expected.as_str()), ("199", "200"));
+        assert!(report.discrepancies.iter().all(|d| d.repairable));
+
+        let repaired = backend.datastore.repair_invariants(&config);
+        assert_eq!(repaired.discrepancies, report.discrepancies);
+        assert!(repaired.repaired.contains(&DerivedStructure::TopUsers));
+        assert!(backend.datastore.check_invariants(&config).is_consistent());
+        assert_eq!(backend.datastore.purchase_count, 2);
+        assert_eq!(backend.datastore.top_user_scores.get_score(0), Some(2));
+        assert_eq!(backend.datastore.purchase_patterns.popularity_of(0), 2);
+    }
+}
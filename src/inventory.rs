@@ -0,0 +1,193 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use typescriptify::TypeScriptifyTrait;
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum StockMovementKind {
+    Purchase,
+    FreeForAll,
+    Undo,
+    Delivery,
+    Correction,
+    WriteOff,
+    Stocktake,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct StockMovement {
+    pub timestamp_epoch_millis: i64,
+    pub kind: StockMovementKind,
+    pub change: i64,
+    //stock level after the movement, for stocktakes the counted level
+    pub level: i64,
+    //set for purchases, ffa giveouts and their undos
+    pub purchase_id: Option<u64>,
+    pub comment: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ItemStock {
+    pub level: i64,
+    pub movements: Vec<StockMovement>,
+}
+
+//stock is tracked per item from its first delivery or correction on.
+//purchases of items without tracked stock leave the inventory alone
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Inventory {
+    pub stock: HashMap<u32, ItemStock>,
+}
+
+impl Inventory {
+    pub fn is_tracked(&self, item_id: u32) -> bool {
+        self.stock.contains_key(&item_id)
+    }
+
+    pub fn level(&self, item_id: u32) -> Option<i64> {
+        self.stock.get(&item_id).map(|s| s.level)
+    }
+
+    pub fn movements(&self, item_id: u32) -> &[StockMovement] {
+        self.stock.get(&item_id).map(|s| s.movements.as_slice()).unwrap_or(&[])
+    }
+
+    //movements dated at or before the latest stocktake belong to the counted period, the count already
+    //includes them, so they are recorded without changing the level
+    fn record(&mut self, item_id: u32, kind: StockMovementKind, change: i64, timestamp_epoch_millis: i64, purchase_id: Option<u64>, comment: String) {
+        let counted_at = self.last_stocktake(item_id).map(|m| m.timestamp_epoch_millis);
+        let stock = self.stock.entry(item_id).or_default();
+        if kind == StockMovementKind::Stocktake || counted_at.map(|t| timestamp_epoch_millis > t).unwrap_or(true) {
+            stock.level += change;
+        }
+        stock.movements.push(StockMovement {
+            timestamp_epoch_millis,
+            kind,
+            change,
+            level: stock.level,
+            purchase_id,
+            comment,
+        });
+    }
+
+    //purchases, ffa giveouts and undos, only for tracked items
+    pub fn record_consumption(&mut self, item_id: u32, kind: StockMovementKind, change: i64, timestamp_epoch_millis: i64, purchase_id: u64) {
+        if self.is_tracked(item_id) {
+            self.record(item_id, kind, change, timestamp_epoch_millis, Some(purchase_id), String::new());
+        }
+    }
+
+    pub fn deliver(&mut self, item_id: u32, count: u32, timestamp_epoch_millis: i64, comment: String) {
+        self.record(item_id, StockMovementKind::Delivery, i64::from(count), timestamp_epoch_millis, None, comment);
+    }
+
+    pub fn write_off(&mut self, item_id: u32, count: u32, timestamp_epoch_millis: i64, comment: String) {
+        self.record(item_id, StockMovementKind::WriteOff, -i64::from(count), timestamp_epoch_millis, None, comment);
+    }
+
+    //sets the level to what was actually counted
+    pub fn correct(&mut self, item_id: u32, level: i64, timestamp_epoch_millis: i64, comment: String) {
+        let change = level - self.level(item_id).unwrap_or(0);
+        self.record(item_id, StockMovementKind::Correction, change, timestamp_epoch_millis, None, comment);
+    }
+
+    //like a correction, but marks the start of the next stocktaking period. movements dated after a count
+    //that is entered late stay on top of the counted level, a count dated before a later one leaves the level alone.
+    //the movement keeps the counted level, which the next stocktake starts from
+    pub fn count(&mut self, item_id: u32, level: i64, timestamp_epoch_millis: i64, comment: String) {
+        let current = self.level(item_id).unwrap_or(0);
+        let is_latest = self.last_stocktake(item_id).map(|m| m.timestamp_epoch_millis <= timestamp_epoch_millis).unwrap_or(true);
+        let new_level = if is_latest {
+            level + self.movements(item_id).iter()
+                .filter(|m| m.kind != StockMovementKind::Stocktake && m.timestamp_epoch_millis > timestamp_epoch_millis)
+                .map(|m| m.change)
+                .sum::<i64>()
+        } else {
+            current
+        };
+        self.record(item_id, StockMovementKind::Stocktake, new_level - current, timestamp_epoch_millis, None, comment);
+        if let Some(movement) = self.stock.get_mut(&item_id).and_then(|s| s.movements.last_mut()) {
+            movement.level = level;
+        }
+    }
+
+    //the stocktake with the latest timestamp
+    pub fn last_stocktake(&self, item_id: u32) -> Option<&StockMovement> {
+        self.movements(item_id).iter().filter(|m| m.kind == StockMovementKind::Stocktake).max_by_key(|m| m.timestamp_epoch_millis)
+    }
+
+    //movements dated after the latest stocktake, including ones that were entered before it
+    pub fn movements_since_stocktake(&self, item_id: u32) -> Vec<&StockMovement> {
+        let counted_at = self.last_stocktake(item_id).map(|m| m.timestamp_epoch_millis);
+        self.movements(item_id)
+            .iter()
+            .filter(|m| m.kind != StockMovementKind::Stocktake && counted_at.map(|t| m.timestamp_epoch_millis > t).unwrap_or(true))
+            .collect()
+    }
+
+    //tracked items below the threshold, lowest stock first
+    pub fn below<F: Fn(u32) -> bool>(&self, threshold: i64, include: F) -> Vec<u32> {
+        let mut low: Vec<(i64, u32)> = self.stock.iter()
+            .filter(|&(item_id, stock)| stock.level < threshold && include(*item_id))
+            .map(|(item_id, stock)| (stock.level, *item_id))
+            .collect();
+        low.sort_unstable();
+        low.into_iter().map(|(_, item_id)| item_id).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use inventory::*;
+    use datastore::DatastoreQueries;
+    use datastore::FreebyAble;
+    use rustix_backend::WriteBackend;
+
+    #[test]
+    fn stock_follows_purchases_deliveries_and_write_offs() {
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.create_item("beer".to_string(), 100, None);
+        backend.create_item("mate".to_string(), 150, None);
+        backend.create_item("water".to_string(), 50, None);
+
+        //untracked items are not touched by purchases
+        backend.purchase(0, 2, 5);
+        assert_eq!(backend.datastore.stock_level(2), None);
+
+        assert!(backend.deliver_stock(0, 24, 10, "brewery".to_string()));
+        assert!(backend.deliver_stock(1, 12, 10, "".to_string()));
+        assert!(!backend.deliver_stock(9, 12, 10, "".to_string()));
+        backend.purchase(0, 0, 20);
+        backend.purchase(0, 0, 21);
+        backend.create_ffa(vec![], vec![1], 2, "".to_string(), 22, 0);
+        let ffa_id = backend.datastore.open_ffa[0].get_id();
+        backend.ffa_purchase(ffa_id, 1, 23);
+        assert!(backend.write_off_stock(0, 2, 30, "broken".to_string()));
+        assert_eq!(backend.datastore.stock_level(0), Some(20));
+        assert_eq!(backend.datastore.stock_level(1), Some(11));
+
+        //undo gives the bottle back
+        backend.undo_purchase(3);
+        assert_eq!(backend.datastore.stock_level(0), Some(21));
+
+        assert!(backend.correct_stock(1, 4, 40, "counted".to_string()));
+        let kinds: Vec<(StockMovementKind, i64, i64)> = backend.datastore.stock_movements(1).iter().map(|m| (m.kind, m.change, m.level)).collect();
+        assert_eq!(kinds, vec![
+            (StockMovementKind::Delivery, 12, 12),
+            (StockMovementKind::FreeForAll, -1, 11),
+            (StockMovementKind::Correction, -7, 4),
+        ]);
+        assert_eq!(backend.datastore.stock_movements(0)[4].purchase_id, Some(3));
+
+        assert_eq!(backend.datastore.low_stock_item_ids(25), vec![1, 0]);
+        assert_eq!(backend.datastore.low_stock_item_ids(5), vec![1]);
+        backend.persistencer.config.low_stock_threshold = 22;
+        assert_eq!(WriteBackend::low_stock_item_ids(&backend), vec![1, 0]);
+        backend.delete_item(1);
+        assert!(backend.datastore.low_stock_item_ids(5).is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use left_threaded_avl_tree::AVLTree;
+use left_threaded_avl_tree::ScoredIdTreeMock;
+
+
+//number of simple purchases per item over all users, globally and per category.
+//like the other scores they are kept when purchases get billed
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ItemRankings {
+    pub global: ScoredIdTreeMock,
+    pub per_category: HashMap<String, ScoredIdTreeMock>,
+}
+
+impl ItemRankings {
+    pub fn insert(&mut self, item_id: u32, category: Option<&str>) {
+        self.global.insert(item_id);
+        if let Some(category) = category {
+            self.per_category.entry(category.to_string()).or_default().insert(item_id);
+        }
+    }
+
+    pub fn record_purchase(&mut self, item_id: u32, category: Option<&str>) {
+        self.global.increment_by_one(item_id);
+        if let Some(tree) = category.and_then(|c| self.per_category.get_mut(c)) {
+            tree.increment_by_one(item_id);
+        }
+    }
+
+    pub fn revert_purchase(&mut self, item_id: u32, category: Option<&str>) {
+        self.global.decrement_by_one(item_id);
+        if let Some(tree) = category.and_then(|c| self.per_category.get_mut(c)) {
+            tree.decrement_by_one(item_id);
+        }
+    }
+
+    pub fn remove(&mut self, item_id: u32, category: Option<&str>) {
+        self.global.remove(item_id);
+        self.remove_from_category(item_id, category);
+    }
+
+    //keeps the score when an item moves to another category
+    pub fn change_category(&mut self, item_id: u32, old_category: Option<&str>, new_category: Option<&str>) {
+        if old_category == new_category {
+            return;
+        }
+        self.remove_from_category(item_id, old_category);
+        if let Some(category) = new_category {
+            let tree = self.per_category.entry(category.to_string()).or_default();
+            tree.insert(item_id);
+            for _ in 0..self.global.get_score(item_id).unwrap_or(0) {
+                tree.increment_by_one(item_id);
+            }
+        }
+    }
+
+    pub fn score(&self, item_id: u32) -> u32 {
+        self.global.get_score(item_id).unwrap_or(0)
+    }
+
+    //best selling items first, ties in order of insertion
+    pub fn top(&self, n: usize, category: Option<&str>) -> Vec<u32> {
+        match category {
+            None => self.global.extract_top(n),
+            Some(category) => self.per_category.get(category).map(|tree| tree.extract_top(n)).unwrap_or_default(),
+        }
+    }
+
+    fn remove_from_category(&mut self, item_id: u32, category: Option<&str>) {
+        if let Some(category) = category {
+            let now_empty = match self.per_category.get_mut(category) {
+                Some(tree) => {
+                    tree.remove(item_id);
+                    tree.extract_top(1).is_empty()
+                },
+                None => false,
+            };
+            if now_empty {
+                self.per_category.remove(category);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use datastore::DatastoreQueries;
+    use datastore::FreebyAble;
+    use datastore::UserGroup;
+    use rustix_backend::WriteBackend;
+    use rustix_event_shop::BLEvents;
+
+    #[test]
+    fn items_are_ranked_globally_per_category_and_in_time_ranges() {
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.create_user("dieter".to_string());
+        backend.create_item("beer".to_string(), 100, Some("alcohol".to_string()));
+        backend.create_item("wine".to_string(), 200, Some("alcohol".to_string()));
+        backend.create_item("mate".to_string(), 150, Some("soft".to_string()));
+        backend.create_item("water".to_string(), 50, None);
+
+        backend.purchase(0, 1, 10);
+        backend.purchase(1, 1, 20);
+        backend.purchase(0, 2, 30);
+        backend.purchase(1, 2, 40);
+        backend.purchase(1, 2, 50);
+        backend.purchase(0, 0, 60);
+        backend.purchase(0, 3, 70);
+
+        let store = &backend.datastore;
+        assert_eq!(store.item_rankings.score(2), 3);
+        assert_eq!(store.bestselling_item_ids(2, None, None), vec![2, 1]);
+        assert_eq!(store.bestselling_item_ids(5, Some("alcohol"), None), vec![1, 0]);
+        assert_eq!(store.bestselling_item_ids(5, Some("nothing"), None), Vec::<u32>::new());
+        assert_eq!(store.bestselling_item_ids(1, None, Some((0, 30))), vec![1]);
+        assert_eq!(store.bestselling_item_ids(5, Some("alcohol"), Some((15, 100))), vec![0, 1]);
+
+        //undone purchases no longer count
+        backend.undo_purchase(5);
+        backend.undo_purchase(4);
+        assert_eq!(backend.datastore.item_rankings.score(2), 1);
+        assert_eq!(backend.datastore.bestselling_item_ids(1, None, None), vec![1]);
+
+        //moving an item keeps its score
+        backend.update_item(2, "mate".to_string(), 150, Some("alcohol".to_string()));
+        assert_eq!(backend.datastore.bestselling_item_ids(5, Some("alcohol"), None), vec![1, 0, 2]);
+        assert_eq!(backend.datastore.bestselling_item_ids(5, Some("soft"), None), Vec::<u32>::new());
+
+        //deleted items are gone from all rankings
+        backend.delete_item(1);
+        assert_eq!(backend.datastore.bestselling_item_ids(5, Some("alcohol"), None), vec![0, 2]);
+        assert_eq!(backend.datastore.bestselling_item_ids(5, None, Some((0, 100))), vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn time_ranges_include_billed_purchases_but_not_giveouts() {
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.update_user(0, "klaus".to_string(), true, false, Some("klaus".to_string()), true);
+        backend.create_item("beer".to_string(), 100, None);
+        backend.create_item("mate".to_string(), 150, None);
+        backend.purchase(0, 0, 10);
+        backend.create_ffa(vec![], vec![1], 2, "".to_string(), 20, 0);
+        let ffa_id = backend.datastore.open_ffa[0].get_id();
+        backend.ffa_purchase(ffa_id, 1, 30);
+        backend.ffa_purchase(ffa_id, 1, 40);
+        backend.purchase(0, 1, 50);
+        backend.purchase(0, 0, 60);
+        //two beers sold against one mate, the free mates do not count with or without a range
+        assert_eq!(backend.datastore.bestselling_item_ids(2, None, Some((0, 100))), vec![0, 1]);
+        assert_eq!(backend.datastore.bestselling_item_ids(2, None, None), vec![0, 1]);
+
+        backend.create_bill(0, 100, UserGroup::AllUsers, "".to_string());
+        assert!(backend.apply(&BLEvents::FinalizeBill { timestamp_from: 0, timestamp_to: 100 }));
+        assert!(backend.datastore.purchases.is_empty());
+        assert_eq!(backend.datastore.bestselling_item_ids(2, None, Some((0, 100))), vec![0, 1]);
+    }
+}
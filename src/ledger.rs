@@ -0,0 +1,177 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use datastore::BalanceMap;
+use datastore::Datastore;
+use typescriptify::TypeScriptifyTrait;
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, TypeScriptify)]
+pub struct LedgerEntry {
+    pub count: u32,
+    pub cost_cents: u32,
+}
+
+//open (not yet billed) consumption of one user, keyed by item id only.
+//names are resolved when queried, so renaming users or items does not split entries
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Ledger {
+    pub items: HashMap<u32, LedgerEntry>,
+}
+
+impl Ledger {
+    pub fn add(&mut self, item_id: u32, count: u32, cost_cents: u32) {
+        let entry = self.items.entry(item_id).or_default();
+        entry.count += count;
+        entry.cost_cents += cost_cents;
+    }
+
+    //entries without any open purchase left are dropped
+    pub fn remove(&mut self, item_id: u32, count: u32, cost_cents: u32) {
+        let now_empty = match self.items.get_mut(&item_id) {
+            Some(entry) => {
+                entry.count = entry.count.saturating_sub(count);
+                entry.cost_cents = entry.cost_cents.saturating_sub(cost_cents);
+                entry.count == 0 && entry.cost_cents == 0
+            },
+            None => false,
+        };
+        if now_empty {
+            self.items.remove(&item_id);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn total_cost_cents(&self) -> u32 {
+        self.items.values().map(|e| e.cost_cents).sum()
+    }
+}
+
+//one line of a user's open balance, with the names as of now
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct BalanceLine {
+    pub item_id: u32,
+    pub itemname: String,
+    pub count: u32,
+    pub cost_cents: u32,
+}
+
+pub fn add_to_ledger(store: &mut Datastore, user_id: u32, item_id: u32, cost_cents: u32) {
+    store.ledgers.entry(user_id).or_default().add(item_id, 1, cost_cents);
+}
+
+pub fn remove_from_ledger(store: &mut Datastore, user_id: u32, item_id: u32, cost_cents: u32) {
+    let now_empty = match store.ledgers.get_mut(&user_id) {
+        Some(ledger) => {
+            ledger.remove(item_id, 1, cost_cents);
+            ledger.is_empty()
+        },
+        None => false,
+    };
+    if now_empty {
+        store.ledgers.remove(&user_id);
+    }
+}
+
+pub fn balance_lines(store: &Datastore, user_id: u32) -> Vec<BalanceLine> {
+    let mut lines: Vec<BalanceLine> = store.ledgers.get(&user_id).map(|ledger| {
+        ledger.items.iter().map(|(item_id, entry)| BalanceLine {
+            item_id: *item_id,
+            itemname: store.items.get(item_id).map(|i| i.name.to_string()).unwrap_or_default(),
+            count: entry.count,
+            cost_cents: entry.cost_cents,
+        }).collect()
+    }).unwrap_or_default();
+    lines.sort_by_key(|l| l.item_id);
+    lines
+}
+
+//older snapshots contain name-keyed balance maps; they are summed up by id into the ledgers.
+//returns true if there was anything to migrate
+pub fn migrate_legacy_balances(store: &mut Datastore) -> bool {
+    if store.legacy_balance_cost_per_user.is_empty() && store.legacy_balance_count_per_user.is_empty() {
+        return false;
+    }
+    let costs = flatten_legacy(&store.legacy_balance_cost_per_user);
+    let counts = flatten_legacy(&store.legacy_balance_count_per_user);
+    let mut ledgers: HashMap<u32, Ledger> = HashMap::new();
+    for (&(user_id, item_id), count) in &counts {
+        let cost_cents = costs.get(&(user_id, item_id)).cloned().unwrap_or(0);
+        ledgers.entry(user_id).or_default().add(item_id, *count, cost_cents);
+    }
+    for (&(user_id, item_id), cost_cents) in costs.iter().filter(|&(k, _)| !counts.contains_key(k)) {
+        ledgers.entry(user_id).or_default().add(item_id, 0, *cost_cents);
+    }
+    store.ledgers = ledgers;
+    store.legacy_balance_cost_per_user.clear();
+    store.legacy_balance_count_per_user.clear();
+    true
+}
+
+fn flatten_legacy(balances: &BalanceMap) -> HashMap<(u32, u32), u32> {
+    let mut flat = HashMap::new();
+    for (user_key, per_item) in balances {
+        for (item_key, value) in per_item {
+            *flat.entry((user_key.0, item_key.0)).or_insert(0) += *value;
+        }
+    }
+    flat
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ledger::*;
+    use datastore::DatastoreQueries;
+    use rustix_backend::WriteBackend;
+    use serde_yaml;
+
+    #[test]
+    fn ledgers_survive_renames_and_old_snapshots_are_migrated() {
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.create_item("beer".to_string(), 100, None);
+        backend.create_item("mate".to_string(), 150, None);
+        backend.purchase(0, 0, 10);
+        backend.update_user(0, "klaus b".to_string(), true, false, None, true);
+        backend.update_item(0, "pils".to_string(), 100, None);
+        backend.purchase(0, 0, 20);
+        backend.purchase(0, 1, 30);
+
+        let lines = backend.datastore.open_balance(0);
+        assert_eq!(lines, vec![
+            BalanceLine { item_id: 0, itemname: "pils".to_string(), count: 2, cost_cents: 200 },
+            BalanceLine { item_id: 1, itemname: "mate".to_string(), count: 1, cost_cents: 150 },
+        ]);
+        assert_eq!(backend.datastore.ledgers[&0].total_cost_cents(), 350);
+
+        assert!(backend.undo_purchase(3));
+        assert_eq!(backend.datastore.open_balance(0).len(), 1);
+
+        //a snapshot of an older version, with balances split up by the rename
+        let mut legacy: BalanceMap = HashMap::new();
+        legacy.entry((0, "klaus".to_string())).or_default().insert((0, "beer".to_string()), 100);
+        legacy.entry((0, "klaus b".to_string())).or_default().insert((0, "pils".to_string()), 100);
+        let mut legacy_count: BalanceMap = HashMap::new();
+        legacy_count.entry((0, "klaus".to_string())).or_default().insert((0, "beer".to_string()), 1);
+        legacy_count.entry((0, "klaus b".to_string())).or_default().insert((0, "pils".to_string()), 1);
+
+        let mut yaml = serde_yaml::to_string(&Datastore::default()).unwrap();
+        for (key, balances) in vec![("balance_cost_per_user", &legacy), ("balance_count_per_user", &legacy_count)] {
+            yaml += &format!("\n{}:", key);
+            for line in serde_yaml::to_string(balances).unwrap().lines().skip(1) {
+                yaml += &format!("\n  {}", line);
+            }
+        }
+        let mut store: Datastore = serde_yaml::from_str(&yaml).unwrap();
+        assert!(store.ledgers.is_empty());
+        assert!(migrate_legacy_balances(&mut store));
+        assert!(!migrate_legacy_balances(&mut store));
+        assert_eq!(store.ledgers, backend.datastore.ledgers);
+        assert!(!serde_yaml::to_string(&store).unwrap().contains("balance_cost_per_user"));
+    }
+}
@@ -10,8 +10,10 @@ pub trait AVLTree {
     fn empty() -> Self;
     fn insert(&mut self, id: u32) -> bool;
     fn increment_by_one(&mut self, id: u32) -> Option<u32>; // returns score if successful
+    fn decrement_by_one(&mut self, id: u32) -> Option<u32>; // returns score if successful, never drops below zero
     fn remove(&mut self, id: u32) -> Option<u32>; // returns score if successful
     fn extract_top(&self, n: usize) -> Vec<u32>;
+    fn get_score(&self, id: u32) -> Option<u32>;
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -75,6 +77,13 @@ impl AVLTree for ScoredIdTreeMock {
         });
     }
 
+    fn decrement_by_one(&mut self, id: u32) -> Option<u32> {
+        self.index_of(id).map(|i| {
+            self.scores[i] = self.scores[i].saturating_sub(1);
+            self.scores[i]
+        })
+    }
+
     fn remove(&mut self, id: u32) -> Option<u32> {
         let o = self.index_of(id);
         match o {
@@ -92,6 +101,10 @@ impl AVLTree for ScoredIdTreeMock {
         let n: usize = cmp::min(n, self.ids.len());
         return self.score_sorted_copy()[0..(n)].to_vec();
     }
+
+    fn get_score(&self, id: u32) -> Option<u32> {
+        self.index_of(id).map(|i| self.scores[i])
+    }
 }
 
 #[cfg(test)]
@@ -124,6 +137,12 @@ mod tests {
         assert_eq!(out[0], 2);
         assert_eq!(out[1], 1);
         assert_eq!(out[2], 3);
+        assert_eq!(tree.get_score(2), Some(3));
+        assert_eq!(tree.get_score(3), Some(0));
+        assert_eq!(tree.get_score(4), None);
+        assert_eq!(tree.decrement_by_one(2), Some(2));
+        assert_eq!(tree.decrement_by_one(3), Some(0));
+        assert!(tree.decrement_by_one(4).is_none());
     }
     #[test]
     fn basic_removal_works() {
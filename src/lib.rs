@@ -43,6 +43,48 @@ pub mod errors;
 
 pub mod config;
 
+pub mod search;
+
+pub mod calendar;
+
+pub mod statistics;
+
+pub mod purchase_query;
+
+pub mod purchase_log;
+
+pub mod invariants;
+
+pub mod projections;
+
+pub mod recommendations;
+
+pub mod item_rankings;
+
+pub mod ledger;
+
+pub mod balance;
+
+pub mod scheduled_changes;
+
+pub mod price_adjustments;
+
+pub mod inventory;
+
+pub mod stocktake;
+
+pub mod suppliers;
+
+pub mod deposits;
+
+pub mod prepaid;
+
+pub mod consumption_limits;
+
+pub mod pricing_rules;
+
+pub mod bundles;
+
 
 use std::collections::HashSet;
 use config::StaticConfig;
@@ -64,6 +106,7 @@ pub fn build_transient_backend_with(
     return rustix_backend::RustixBackend {
         datastore: datastore::Datastore::default(),
         persistencer: persistencer::FilePersister::new(config).unwrap(),
+        projections: projections::ProjectionRegistry::default(),
     };
 }
 
@@ -73,6 +116,7 @@ pub fn build_persistent_backend(dir: &std::path::Path) -> rustix_backend::Rustix
     return rustix_backend::RustixBackend {
         datastore: datastore::Datastore::default(),
         persistencer: persistencer::FilePersister::new(config).unwrap(),
+        projections: projections::ProjectionRegistry::default(),
     };
 }
 
@@ -79,6 +79,9 @@ pub trait Persistencer {
 
     //returns number of events loaded
     fn reload_from_filepath(&mut self, datastore: &mut Datastore) -> Result<u64, RustixError>;
+
+    //same as reload_from_filepath, but calls the observer after each applied event
+    fn reload_from_filepath_observed(&mut self, datastore: &mut Datastore, observer: &mut dyn FnMut(&BLEvents, &Datastore)) -> Result<u64, RustixError>;
     //fn initialize(&mut self, datastore: &mut Datastore) -> Result<u32, RustixError>;
 
     fn load_into_string(&self) -> Result<String, RustixError>;
@@ -177,6 +180,10 @@ impl Persistencer for FilePersister {
     }
 
     fn reload_from_filepath(&mut self, datastore: &mut Datastore) -> Result<u64, RustixError> {
+        return self.reload_from_filepath_observed(datastore, &mut |_, _| ());
+    }
+
+    fn reload_from_filepath_observed(&mut self, datastore: &mut Datastore, observer: &mut dyn FnMut(&BLEvents, &Datastore)) -> Result<u64, RustixError> {
         let counter = datastore.version;
 
         println!("Reloading events from lmdb with counter = {}", counter);
@@ -205,6 +212,7 @@ impl Persistencer for FilePersister {
                             if event.can_be_applied(datastore) {
                                 event.apply(datastore, &self.config);
                                 datastore.version += 1u64;
+                                observer(&event, datastore);
                             } else {
                                 println!("CARE: could not apply event {:?} to datastore state: {:?}", event, datastore);
                             }
@@ -0,0 +1,173 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use datastore::Datastore;
+use bundles::purchase_units;
+use typescriptify::TypeScriptifyTrait;
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum PrepaidTransactionKind {
+    Cash,
+    BankTransfer,
+    //item price and deposit of a purchase, the price of a special once it is set
+    Purchase,
+    Undo,
+    //difference between what was deducted and what the finalized bill says, e.g. for freebies
+    BillSettlement,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct PrepaidTransaction {
+    pub timestamp_epoch_millis: i64,
+    pub kind: PrepaidTransactionKind,
+    pub change: i64,
+    //balance after the transaction
+    pub balance: i64,
+    pub purchase_id: Option<u64>,
+    pub comment: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, TypeScriptify)]
+pub struct PrepaidAccount {
+    pub user_id: u32,
+    pub balance_cents: i64,
+    //how far the balance may go below 0
+    pub overdraft_limit_cents: u64,
+    pub transactions: Vec<PrepaidTransaction>,
+}
+
+//users with an account pay from their balance instead of being invoiced
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct PrepaidAccounts {
+    pub accounts: HashMap<u32, PrepaidAccount>,
+}
+
+impl PrepaidAccounts {
+    pub fn is_prepaid(&self, user_id: u32) -> bool {
+        self.accounts.contains_key(&user_id)
+    }
+
+    //opens the account or changes the limit of an existing one
+    pub fn open(&mut self, user_id: u32, overdraft_limit_cents: u64) {
+        self.accounts.entry(user_id).or_insert(PrepaidAccount {
+            user_id,
+            ..PrepaidAccount::default()
+        }).overdraft_limit_cents = overdraft_limit_cents;
+    }
+
+    //always true for users without an account
+    pub fn can_afford(&self, user_id: u32, cents: u64) -> bool {
+        self.accounts.get(&user_id).map(|a| {
+            let limit = i64::try_from(a.overdraft_limit_cents).unwrap_or(i64::MAX);
+            i64::try_from(cents).ok().and_then(|c| a.balance_cents.checked_sub(c)).map(|balance| balance >= -limit).unwrap_or(false)
+        }).unwrap_or(true)
+    }
+
+    //amounts have to fit into the signed balance
+    pub fn can_top_up(&self, user_id: u32, cents: u64) -> bool {
+        self.accounts.get(&user_id).map(|a| i64::try_from(cents).ok().and_then(|c| a.balance_cents.checked_add(c)).is_some()).unwrap_or(false)
+    }
+
+    //does nothing for users without an account
+    pub fn book(&mut self, user_id: u32, kind: PrepaidTransactionKind, change: i64, timestamp_epoch_millis: i64, purchase_id: Option<u64>, comment: String) {
+        if let Some(account) = self.accounts.get_mut(&user_id) {
+            account.balance_cents += change;
+            account.transactions.push(PrepaidTransaction {
+                timestamp_epoch_millis,
+                kind,
+                change,
+                balance: account.balance_cents,
+                purchase_id,
+                comment,
+            });
+        }
+    }
+
+    //cents already taken from the balance for the given purchases
+    pub fn deducted_for(&self, user_id: u32, purchase_ids: &HashSet<u64>) -> i64 {
+        self.accounts.get(&user_id).map(|a| a.transactions.iter()
+            .filter(|t| t.kind == PrepaidTransactionKind::Purchase && t.purchase_id.map(|id| purchase_ids.contains(&id)).unwrap_or(false))
+            .map(|t| -t.change)
+            .sum()).unwrap_or(0)
+    }
+}
+
+//what a purchase takes from a prepaid balance, bundles with the deposits of their components
+pub fn purchase_cost(store: &Datastore, item_id: u32, user_id: u32, timestamp_epoch_millis: i64) -> u64 {
+    purchase_units(store, item_id, user_id, timestamp_epoch_millis)
+        .into_iter()
+        .map(|(unit_item_id, unit_price)| u64::from(unit_price) + u64::from(store.items[&unit_item_id].deposit_cents))
+        .sum()
+}
+
+//purchases are (item_id, quantity); specials are priced later, so they need at least one cent of room left
+pub fn can_afford_purchases(store: &Datastore, user_id: u32, purchases: &[(u32, u32)], with_specials: bool, timestamp_epoch_millis: i64) -> bool {
+    let cost: u64 = purchases.iter().map(|&(item_id, quantity)| u64::from(quantity) * purchase_cost(store, item_id, user_id, timestamp_epoch_millis)).sum();
+    store.prepaid.can_afford(user_id, if with_specials { cost + 1 } else { cost })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use prepaid::*;
+    use datastore::DatastoreQueries;
+    use datastore::PurchaseFunctions;
+    use datastore::UserGroup;
+    use rustix_backend::WriteBackend;
+    use rustix_event_shop::BLEvents;
+
+    #[test]
+    fn prepaid_users_pay_from_their_balance_and_are_not_invoiced() {
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.update_user(0, "klaus".to_string(), true, false, Some("klaus".to_string()), true);
+        //guests have no external id, their account is enough for billing
+        backend.create_user("guest".to_string());
+        backend.create_item("mate".to_string(), 150, None);
+
+        assert!(backend.open_prepaid_account(1, 100));
+        assert!(!backend.open_prepaid_account(7, 100));
+        assert!(backend.top_up_prepaid(1, 500, PrepaidTransactionKind::Cash, 1, "".to_string()));
+        assert!(!backend.top_up_prepaid(0, 500, PrepaidTransactionKind::Cash, 1, "".to_string()));
+        assert!(!backend.top_up_prepaid(1, 500, PrepaidTransactionKind::Purchase, 1, "".to_string()));
+        assert!(!backend.top_up_prepaid(1, u64::MAX, PrepaidTransactionKind::Cash, 1, "".to_string()));
+        assert!(!backend.top_up_prepaid(1, i64::MAX as u64, PrepaidTransactionKind::Cash, 1, "".to_string()));
+        assert!(!backend.open_prepaid_account(1, u64::MAX));
+
+        //klaus gives the guest one mate
+        backend.create_free_count(vec![], vec![0], 1, "".to_string(), 2, 0, 1);
+        for i in 0..4 {
+            backend.purchase(1, 0, 10 + i);
+        }
+        assert_eq!(backend.datastore.prepaid_account(1).unwrap().balance_cents, -100);
+        backend.purchase(1, 0, 14);
+        backend.special_purchase(1, "pizza".to_string(), 14);
+        backend.cart_purchase(1, vec![], vec![0], 14);
+        assert_eq!(backend.datastore.purchases.len(), 4);
+        //users without an account are not limited
+        backend.purchase(0, 0, 15);
+        assert_eq!(backend.datastore.purchases.len(), 5);
+
+        assert!(backend.top_up_prepaid(1, 300, PrepaidTransactionKind::BankTransfer, 16, "".to_string()));
+        assert!(backend.special_purchase(1, "pizza".to_string(), 20));
+        let special_id = backend.datastore.purchases.iter().find(|p| p.is_special()).unwrap().get_unique_id();
+        assert!(backend.apply(&BLEvents::SetPriceForSpecial { unique_id: special_id, price: 80 }));
+        backend.undo_purchase(4);
+        assert_eq!(backend.datastore.prepaid_account(1).unwrap().balance_cents, 270);
+
+        //three mates, one of them given by klaus, and the pizza
+        backend.create_bill(0, 100, UserGroup::AllUsers, "".to_string());
+        assert!(backend.apply(&BLEvents::FinalizeBill { timestamp_from: 0, timestamp_to: 100 }));
+        let bill = &backend.datastore.bills[0];
+        assert_eq!(bill.total_cents_of(1), 300 + 80);
+        assert_eq!(bill.invoiced_cents_of(1), 0);
+        assert_eq!(bill.invoiced_cents_of(0), 300);
+        let account = backend.datastore.prepaid_account(1).unwrap();
+        assert_eq!(account.balance_cents, 500 + 300 - 380);
+        assert_eq!(account.transactions.last().map(|t| (t.kind, t.change)), Some((PrepaidTransactionKind::BillSettlement, 150)));
+    }
+}
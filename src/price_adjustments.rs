@@ -0,0 +1,152 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use datastore::Datastore;
+use typescriptify::TypeScriptifyTrait;
+
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, TypeScriptify)]
+pub enum PriceSelection {
+    Category { category: String },
+    Items { item_ids: Vec<u32> },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, TypeScriptify)]
+pub enum PriceRounding {
+    Cent,
+    FiveCents,
+    TenCents,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, TypeScriptify)]
+pub enum PriceChange {
+    //added to every price, negative values lower prices down to at most 0
+    ByCents { cents: i64 },
+    //in hundredths of a percent, e.g. 250 for +2.5%
+    ByBasisPoints { basis_points: i64, rounding: PriceRounding },
+    Fixed { price_cents: u32 },
+}
+
+impl PriceRounding {
+    //to the nearest multiple, halves are rounded up
+    pub fn round(&self, cents: i64) -> i64 {
+        let step = match *self {
+            PriceRounding::Cent => 1,
+            PriceRounding::FiveCents => 5,
+            PriceRounding::TenCents => 10,
+        };
+        (cents + step / 2).div_euclid(step) * step
+    }
+}
+
+//at most +10000%, a price can not drop below 0 anyway
+pub const MAX_BASIS_POINTS: i64 = 1_000_000;
+
+impl PriceChange {
+    //changes beyond these bounds do not describe a price, so events with them are rejected
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            PriceChange::ByCents { cents } => cents.unsigned_abs() <= u64::from(u32::MAX),
+            PriceChange::ByBasisPoints { basis_points, .. } => (-10_000..=MAX_BASIS_POINTS).contains(&basis_points),
+            PriceChange::Fixed { .. } => true,
+        }
+    }
+
+    //saturates at 0 and u32::MAX, also for changes that are not valid
+    pub fn apply_to(&self, price_cents: u32) -> u32 {
+        let price = i64::from(price_cents);
+        let clamp = |cents: i64| cents.clamp(0, i64::from(u32::MAX));
+        let new_price = match *self {
+            PriceChange::ByCents { cents } => price.saturating_add(cents),
+            PriceChange::ByBasisPoints { basis_points, rounding } => {
+                let scaled = price.saturating_mul(basis_points.saturating_add(10_000)).saturating_add(5_000).div_euclid(10_000);
+                rounding.round(clamp(scaled))
+            },
+            PriceChange::Fixed { price_cents } => i64::from(price_cents),
+        };
+        clamp(new_price) as u32
+    }
+}
+
+//items that are not deleted, by id. an explicit list is only valid if all of its items exist
+pub fn selected_item_ids(store: &Datastore, selection: &PriceSelection) -> Option<Vec<u32>> {
+    let exists = |item_id: &u32| store.items.get(item_id).map(|i| !i.deleted).unwrap_or(false);
+    let mut item_ids: Vec<u32> = match selection {
+        PriceSelection::Category { category } => store.items.values()
+            .filter(|i| !i.deleted && i.category.as_ref() == Some(category))
+            .map(|i| i.item_id)
+            .collect(),
+        PriceSelection::Items { item_ids } => {
+            if !item_ids.iter().all(exists) {
+                return None;
+            }
+            item_ids.clone()
+        },
+    };
+    item_ids.sort_unstable();
+    item_ids.dedup();
+    if item_ids.is_empty() {
+        None
+    } else {
+        Some(item_ids)
+    }
+}
+
+//item ids with their new prices, empty if the selection is not valid
+pub fn new_prices(store: &Datastore, selection: &PriceSelection, change: &PriceChange) -> Vec<(u32, u32)> {
+    selected_item_ids(store, selection)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|item_id| (item_id, change.apply_to(store.items[&item_id].cost_cents)))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use price_adjustments::*;
+    use rustix_backend::WriteBackend;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn bulk_price_updates_change_all_selected_items_at_once() {
+        assert_eq!(PriceChange::ByBasisPoints { basis_points: 1_000, rounding: PriceRounding::FiveCents }.apply_to(123), 135);
+        assert_eq!(PriceChange::ByBasisPoints { basis_points: 1_000, rounding: PriceRounding::TenCents }.apply_to(123), 140);
+        assert_eq!(PriceChange::ByBasisPoints { basis_points: -250, rounding: PriceRounding::Cent }.apply_to(150), 146);
+        assert_eq!(PriceChange::ByCents { cents: -500 }.apply_to(150), 0);
+        assert_eq!(PriceChange::ByCents { cents: i64::MAX }.apply_to(150), u32::MAX);
+        assert_eq!(PriceChange::ByCents { cents: i64::MIN }.apply_to(150), 0);
+        assert_eq!(PriceChange::ByBasisPoints { basis_points: i64::MAX, rounding: PriceRounding::TenCents }.apply_to(u32::MAX), u32::MAX);
+        assert_eq!(PriceChange::ByBasisPoints { basis_points: i64::MIN, rounding: PriceRounding::Cent }.apply_to(150), 0);
+        assert!(PriceChange::ByBasisPoints { basis_points: MAX_BASIS_POINTS, rounding: PriceRounding::Cent }.is_valid());
+        assert!(!PriceChange::ByBasisPoints { basis_points: -10_001, rounding: PriceRounding::Cent }.is_valid());
+        assert!(!PriceChange::ByCents { cents: i64::from(u32::MAX) + 1 }.is_valid());
+        assert!(!PriceChange::ByCents { cents: i64::MIN }.is_valid());
+
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.create_item("beer".to_string(), 100, Some("alcohol".to_string()));
+        backend.create_item("wine".to_string(), 210, Some("alcohol".to_string()));
+        backend.create_item("mate".to_string(), 150, None);
+        backend.purchase(0, 0, 10);
+
+        let alcohol = PriceSelection::Category { category: "alcohol".to_string() };
+        let raise = PriceChange::ByBasisPoints { basis_points: 1_000, rounding: PriceRounding::TenCents };
+        assert_eq!(new_prices(&backend.datastore, &alcohol, &raise), vec![(0, 110), (1, 230)]);
+        assert!(backend.bulk_update_prices(alcohol, raise));
+        assert_eq!(backend.datastore.items.values().map(|i| (i.item_id, i.cost_cents)).collect::<BTreeMap<_, _>>(), vec![(0, 110), (1, 230), (2, 150)].into_iter().collect());
+        //earlier purchases keep their price
+        backend.purchase(0, 0, 20);
+        assert_eq!(backend.datastore.ledgers[&0].total_cost_cents(), 100 + 110);
+
+        //lists with unknown items are rejected as a whole
+        let with_unknown = PriceSelection::Items { item_ids: vec![2, 9] };
+        assert!(!backend.bulk_update_prices(with_unknown, PriceChange::Fixed { price_cents: 200 }));
+        assert!(!backend.bulk_update_prices(PriceSelection::Category { category: "nothing".to_string() }, PriceChange::Fixed { price_cents: 200 }));
+        assert!(!backend.bulk_update_prices(PriceSelection::Items { item_ids: vec![2] }, PriceChange::ByCents { cents: i64::MAX }));
+        assert_eq!(backend.datastore.items[&2].cost_cents, 150);
+
+        assert!(backend.bulk_update_prices(PriceSelection::Items { item_ids: vec![2, 1] }, PriceChange::Fixed { price_cents: 200 }));
+        assert_eq!((backend.datastore.items[&1].cost_cents, backend.datastore.items[&2].cost_cents), (200, 200));
+    }
+}
@@ -0,0 +1,229 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use calendar;
+use calendar::TimeZone;
+use datastore::Datastore;
+use datastore::Item;
+use datastore::PriceTier;
+use datastore::user_price_tier;
+use price_adjustments::PriceChange;
+use price_adjustments::PriceSelection;
+use typescriptify::TypeScriptifyTrait;
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub enum PricingWindow {
+    //weekdays 0 = monday, ..., 6 = sunday. hours from from_hour to to_hour, wrapping past midnight if
+    //to_hour is not after from_hour, so weekdays [4] from 22 to 2 is friday night until saturday 2:00
+    Weekly { weekdays: Vec<u32>, from_hour: u32, to_hour: u32 },
+    //specific dates or event nights
+    Span { from_millis: i64, to_millis: i64 },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct PricingRule {
+    pub rule_id: u64,
+    pub name: String,
+    pub selection: PriceSelection,
+    pub change: PriceChange,
+    pub window: PricingWindow,
+}
+
+//an item as listed at a given time
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct ItemPrice {
+    pub item_id: u32,
+    pub itemname: String,
+    pub base_cents: u32,
+    pub effective_cents: u32,
+    pub rule_id: Option<u64>,
+    pub rule_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct PricingRules {
+    pub rules: Vec<PricingRule>,
+    pub rule_id_counter: u64,
+}
+
+impl PricingWindow {
+    pub fn is_valid(&self) -> bool {
+        match self {
+            PricingWindow::Weekly { weekdays, from_hour, to_hour } => !weekdays.is_empty() && weekdays.iter().all(|d| *d < 7) && *from_hour < 24 && *to_hour < 24,
+            PricingWindow::Span { from_millis, to_millis } => from_millis < to_millis,
+        }
+    }
+
+    //weekly windows are in the shop's local time
+    pub fn contains(&self, timestamp_epoch_millis: i64, time_zone: &TimeZone) -> bool {
+        match self {
+            PricingWindow::Weekly { weekdays, from_hour, to_hour } => {
+                let local = time_zone.to_local(timestamp_epoch_millis);
+                let hour = calendar::hour_of_day(local);
+                let day = calendar::weekday(local);
+                if from_hour < to_hour {
+                    weekdays.contains(&day) && *from_hour <= hour && hour < *to_hour
+                } else {
+                    (weekdays.contains(&day) && hour >= *from_hour) || (weekdays.contains(&((day + 6) % 7)) && hour < *to_hour)
+                }
+            },
+            PricingWindow::Span { from_millis, to_millis } => *from_millis <= timestamp_epoch_millis && timestamp_epoch_millis < *to_millis,
+        }
+    }
+}
+
+impl PricingRules {
+    pub fn create(&mut self, name: String, selection: PriceSelection, change: PriceChange, window: PricingWindow) -> u64 {
+        self.rule_id_counter += 1;
+        self.rules.push(PricingRule {
+            rule_id: self.rule_id_counter,
+            name,
+            selection,
+            change,
+            window,
+        });
+        self.rule_id_counter
+    }
+
+    pub fn get_mut(&mut self, rule_id: u64) -> Option<&mut PricingRule> {
+        self.rules.iter_mut().find(|r| r.rule_id == rule_id)
+    }
+
+    pub fn contains(&self, rule_id: u64) -> bool {
+        self.rules.iter().any(|r| r.rule_id == rule_id)
+    }
+
+    pub fn remove(&mut self, rule_id: u64) {
+        self.rules.retain(|r| r.rule_id != rule_id);
+    }
+}
+
+fn selects(selection: &PriceSelection, item: &Item) -> bool {
+    match selection {
+        PriceSelection::Category { category } => item.category.as_ref() == Some(category),
+        PriceSelection::Items { item_ids } => item_ids.contains(&item.item_id),
+    }
+}
+
+//categories have to be known, listed items have to exist
+pub fn is_valid_selection(store: &Datastore, selection: &PriceSelection) -> bool {
+    match selection {
+        PriceSelection::Category { category } => store.categories.contains(category),
+        PriceSelection::Items { item_ids } => !item_ids.is_empty() && item_ids.iter().all(|id| store.items.get(id).map(|i| !i.deleted).unwrap_or(false)),
+    }
+}
+
+//the item's own price for the tier, including scheduled changes that are due by then but wait for the next purchase
+pub fn base_price(store: &Datastore, item: &Item, tier: PriceTier, timestamp_epoch_millis: i64) -> u32 {
+    let due = store.item_change_schedule.due_for(item.item_id, timestamp_epoch_millis);
+    if due.is_empty() {
+        return item.price_for(tier, timestamp_epoch_millis);
+    }
+    let mut changed = item.clone();
+    for change in due {
+        changed.set_price(change.price_cents, change.effective_timestamp);
+        for tier_price in &change.tier_prices {
+            changed.set_tier_price(tier_price.tier, Some(tier_price.cost_cents), change.effective_timestamp);
+        }
+    }
+    changed.price_for(tier, timestamp_epoch_millis)
+}
+
+//if several rules are active, the lowest price wins, then the oldest rule
+pub fn active_rule<'a>(store: &'a Datastore, item: &Item, tier: PriceTier, timestamp_epoch_millis: i64) -> Option<(&'a PricingRule, u32)> {
+    let base = base_price(store, item, tier, timestamp_epoch_millis);
+    store.pricing_rules.rules.iter()
+        .filter(|r| selects(&r.selection, item) && r.window.contains(timestamp_epoch_millis, &store.time_zone))
+        .map(|r| (r, r.change.apply_to(base)))
+        .min_by_key(|(r, price)| (*price, r.rule_id))
+}
+
+//what a purchase paid by the user at that time records
+pub fn effective_price(store: &Datastore, item_id: u32, user_id: u32, timestamp_epoch_millis: i64) -> u32 {
+    let tier = user_price_tier(store, user_id, timestamp_epoch_millis);
+    match store.items.get(&item_id) {
+        Some(item) => active_rule(store, item, tier, timestamp_epoch_millis).map(|(_, price)| price).unwrap_or_else(|| base_price(store, item, tier, timestamp_epoch_millis)),
+        None => 0,
+    }
+}
+
+//all items that are not deleted, by id
+pub fn item_prices(store: &Datastore, timestamp_epoch_millis: i64, tier: PriceTier) -> Vec<ItemPrice> {
+    let mut prices: Vec<ItemPrice> = store.items.values()
+        .filter(|i| !i.deleted)
+        .map(|i| {
+            let base_cents = base_price(store, i, tier, timestamp_epoch_millis);
+            let rule = active_rule(store, i, tier, timestamp_epoch_millis);
+            ItemPrice {
+                item_id: i.item_id,
+                itemname: i.name.to_string(),
+                base_cents,
+                effective_cents: rule.map(|(_, price)| price).unwrap_or(base_cents),
+                rule_id: rule.map(|(r, _)| r.rule_id),
+                rule_name: rule.map(|(r, _)| r.name.to_string()),
+            }
+        })
+        .collect();
+    prices.sort_by_key(|p| p.item_id);
+    prices
+}
+
+
+#[cfg(test)]
+mod tests {
+    use pricing_rules::*;
+    use datastore::DatastoreQueries;
+    use datastore::PurchaseFunctions;
+    use price_adjustments::PriceRounding;
+    use rustix_backend::WriteBackend;
+
+    #[test]
+    fn purchases_inside_a_pricing_window_record_the_discounted_price() {
+        let hour = calendar::MILLIS_PER_HOUR;
+        //1970-01-02 was a friday
+        let friday = calendar::MILLIS_PER_DAY;
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.create_item("beer".to_string(), 100, Some("alcohol".to_string()));
+        backend.create_item("mate".to_string(), 150, None);
+
+        let friday_night = PricingWindow::Weekly { weekdays: vec![4], from_hour: 22, to_hour: 2 };
+        let alcohol = PriceSelection::Category { category: "alcohol".to_string() };
+        let half_price = PriceChange::ByBasisPoints { basis_points: -5_000, rounding: PriceRounding::TenCents };
+        assert!(backend.create_pricing_rule("happy hour".to_string(), alcohol.clone(), half_price.clone(), friday_night.clone()));
+        assert!(!backend.create_pricing_rule("".to_string(), PriceSelection::Category { category: "nothing".to_string() }, half_price.clone(), friday_night.clone()));
+        assert!(!backend.create_pricing_rule("".to_string(), alcohol.clone(), half_price, PricingWindow::Weekly { weekdays: vec![7], from_hour: 22, to_hour: 2 }));
+
+        //saturday 1:00 still belongs to friday night, saturday 22:00 does not
+        backend.purchase(0, 0, friday + 21 * hour);
+        backend.purchase(0, 0, friday + 25 * hour);
+        backend.purchase(0, 0, friday + 46 * hour);
+        backend.purchase(0, 1, friday + 23 * hour);
+        assert_eq!(backend.datastore.purchases.iter().map(|p| p.get_unit_price()).collect::<Vec<_>>(), vec![Some(100), Some(150), Some(50), Some(100)]);
+
+        //an event night makes mate cheaper, the cheapest active rule wins
+        assert!(backend.create_pricing_rule("lan party".to_string(), PriceSelection::Items { item_ids: vec![0, 1] }, PriceChange::Fixed { price_cents: 80 }, PricingWindow::Span { from_millis: friday + 20 * hour, to_millis: friday + 30 * hour }));
+        let listed = backend.datastore.item_prices_at(friday + 23 * hour, PriceTier::Member);
+        assert_eq!((listed[0].effective_cents, listed[0].rule_name.as_deref()), (50, Some("happy hour")));
+        assert_eq!((listed[1].base_cents, listed[1].effective_cents, listed[1].rule_id), (150, 80, Some(2)));
+        assert_eq!(backend.datastore.item_prices_at(friday, PriceTier::Member)[1].rule_id, None);
+
+        assert!(backend.update_pricing_rule(2, "lan party".to_string(), PriceSelection::Items { item_ids: vec![1] }, PriceChange::ByCents { cents: -20 }, PricingWindow::Span { from_millis: friday + 20 * hour, to_millis: friday + 30 * hour }));
+        assert!(!backend.update_pricing_rule(2, "lan party".to_string(), PriceSelection::Items { item_ids: vec![1] }, PriceChange::ByCents { cents: i64::MIN }, PricingWindow::Span { from_millis: friday + 20 * hour, to_millis: friday + 30 * hour }));
+        assert!(!backend.create_pricing_rule("".to_string(), PriceSelection::Items { item_ids: vec![1] }, PriceChange::ByBasisPoints { basis_points: i64::MAX, rounding: PriceRounding::Cent }, PricingWindow::Span { from_millis: friday, to_millis: friday + hour }));
+        assert_eq!(backend.datastore.item_prices_at(friday + 23 * hour, PriceTier::Member)[1].effective_cents, 130);
+        assert!(backend.remove_pricing_rule(2));
+        assert!(!backend.remove_pricing_rule(2));
+        assert_eq!(backend.datastore.item_prices_at(friday + 23 * hour, PriceTier::Member)[1].effective_cents, 150);
+
+        //in central europe, friday 22:00 is 21:00 UTC in winter and 20:00 UTC in summer
+        let berlin = TimeZone { utc_offset_minutes: 60, daylight_saving: calendar::DaylightSaving::European };
+        assert!(backend.set_time_zone(berlin));
+        assert!(!backend.set_time_zone(TimeZone { utc_offset_minutes: 24 * 60, daylight_saving: calendar::DaylightSaving::Never }));
+        assert_eq!(backend.datastore.item_prices_at(friday + 21 * hour, PriceTier::Member)[0].effective_cents, 50);
+        let summer_friday = calendar::millis_from_civil(1970, 7, 3);
+        assert_eq!(backend.datastore.item_prices_at(summer_friday + 19 * hour, PriceTier::Member)[0].effective_cents, 100);
+        assert_eq!(backend.datastore.item_prices_at(summer_friday + 20 * hour, PriceTier::Member)[0].effective_cents, 50);
+    }
+}
@@ -0,0 +1,219 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fmt;
+use datastore::Datastore;
+use rustix_event_shop::BLEvents;
+use serde_json;
+
+
+//a read model maintained outside of the datastore, e.g. a weekly leaderboard.
+//the backend feeds it every event that got applied, both live and while replaying
+pub trait Projection {
+    //unique name under which the projection is registered and queried
+    fn name(&self) -> String;
+
+    //called after the event was applied, store already contains its effects
+    fn apply(&mut self, event: &BLEvents, store: &Datastore);
+
+    //forget everything, before being rebuilt from the first event
+    fn reset(&mut self);
+
+    //current read model, also used for snapshots
+    fn state(&self) -> serde_json::Value;
+
+    //returns false if the snapshotted state cannot be used, the projection is then rebuilt by replay
+    fn restore(&mut self, state: &serde_json::Value) -> bool;
+}
+
+//written next to the datastore snapshot
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ProjectionSnapshot {
+    pub version: u64,
+    pub states: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Default)]
+pub struct ProjectionRegistry {
+    projections: Vec<Box<dyn Projection>>,
+}
+
+impl fmt::Debug for ProjectionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProjectionRegistry {:?}", self.names())
+    }
+}
+
+impl ProjectionRegistry {
+    //returns false if a projection with the same name is already registered
+    pub fn register(&mut self, projection: Box<dyn Projection>) -> bool {
+        if self.get(&projection.name()).is_some() {
+            return false;
+        }
+        self.projections.push(projection);
+        true
+    }
+
+    pub fn unregister(&mut self, name: &str) -> Option<Box<dyn Projection>> {
+        let idx = self.projections.iter().position(|p| p.name() == name)?;
+        Some(self.projections.remove(idx))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Projection> {
+        self.projections.iter().find(|p| p.name() == name).map(|p| p.as_ref())
+    }
+
+    pub fn state_of(&self, name: &str) -> Option<serde_json::Value> {
+        self.get(name).map(|p| p.state())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.projections.iter().map(|p| p.name()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.projections.is_empty()
+    }
+
+    pub fn apply(&mut self, event: &BLEvents, store: &Datastore) {
+        for projection in &mut self.projections {
+            projection.apply(event, store);
+        }
+    }
+
+    //only feeds the named projections, used while rebuilding some of them
+    pub fn apply_to(&mut self, names: &[String], event: &BLEvents, store: &Datastore) {
+        for projection in self.projections.iter_mut().filter(|p| names.contains(&p.name())) {
+            projection.apply(event, store);
+        }
+    }
+
+    pub fn snapshot(&self, version: u64) -> ProjectionSnapshot {
+        ProjectionSnapshot {
+            version,
+            states: self.projections.iter().map(|p| (p.name(), p.state())).collect(),
+        }
+    }
+
+    //restores every projection found in a snapshot of the given version.
+    //all others are reset, and their names are returned so that they can be rebuilt
+    pub fn restore(&mut self, snapshot: Option<&ProjectionSnapshot>, version: u64) -> Vec<String> {
+        let mut to_rebuild: Vec<String> = vec![];
+        for projection in &mut self.projections {
+            let restored = snapshot
+                .filter(|s| s.version == version)
+                .and_then(|s| s.states.get(&projection.name()))
+                .map(|state| projection.restore(state))
+                .unwrap_or(false);
+            if !restored {
+                projection.reset();
+                to_rebuild.push(projection.name());
+            }
+        }
+        to_rebuild
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use projections::*;
+    use datastore::Purchase;
+    use rustix_backend::WriteBackend;
+    use std::collections::BTreeMap;
+
+    //number of purchases per user
+    #[derive(Default)]
+    struct PurchaseCounter {
+        counts: BTreeMap<u32, u32>,
+        can_restore: bool,
+    }
+
+    impl Projection for PurchaseCounter {
+        fn name(&self) -> String {
+            "purchase counter".to_string()
+        }
+
+        fn apply(&mut self, event: &BLEvents, store: &Datastore) {
+            if let BLEvents::MakeSimplePurchase { user_id, .. } = *event {
+                *self.counts.entry(user_id).or_insert(0) += 1;
+            }
+            if let BLEvents::UndoPurchase { .. } = *event {
+                //the purchase is gone by now, so just recount
+                self.counts.clear();
+                for purchase in store.purchases.iter() {
+                    if let Purchase::SimplePurchase { consumer_id, .. } = *purchase {
+                        *self.counts.entry(consumer_id).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+
+        fn reset(&mut self) {
+            self.counts.clear();
+        }
+
+        fn state(&self) -> serde_json::Value {
+            serde_json::to_value(&self.counts).unwrap()
+        }
+
+        fn restore(&mut self, state: &serde_json::Value) -> bool {
+            if !self.can_restore {
+                return false;
+            }
+            match serde_json::from_value(state.clone()) {
+                Ok(counts) => {
+                    self.counts = counts;
+                    true
+                },
+                Err(_) => false,
+            }
+        }
+    }
+
+    fn counter(can_restore: bool) -> Box<dyn Projection> {
+        Box::new(PurchaseCounter {
+            counts: BTreeMap::new(),
+            can_restore,
+        })
+    }
+
+    #[test]
+    fn projections_are_fed_live_and_by_replay() {
+        let dir = tempdir::TempDir::new("projectiontestdir").unwrap();
+        let mut backend = ::build_persistent_backend(dir.as_ref());
+        assert!(backend.projections.register(counter(true)));
+        assert!(!backend.projections.register(counter(true)));
+
+        backend.create_user("klaus".to_string());
+        backend.create_user("dieter".to_string());
+        backend.create_item("beer".to_string(), 100, None);
+        backend.purchase(0, 0, 10);
+        backend.purchase(1, 0, 20);
+        //rejected events are not fed
+        backend.purchase(5, 0, 30);
+        assert!(backend.snapshot().is_some());
+        backend.purchase(1, 0, 40);
+
+        let expected: serde_json::Value = serde_json::from_str(r#"{"0": 1, "1": 2}"#).unwrap();
+        assert_eq!(backend.projections.state_of("purchase counter"), Some(expected.clone()));
+        assert!(backend.projections.state_of("leaderboard").is_none());
+
+        //restored from the snapshot, the last purchase comes from the event log
+        backend.projections.unregister("purchase counter");
+        backend.projections.register(counter(true));
+        backend.datastore = Datastore::default();
+        assert_eq!(backend.reload().unwrap(), 6);
+        assert_eq!(backend.projections.state_of("purchase counter"), Some(expected.clone()));
+
+        //rebuilt by replaying the events up to the snapshot
+        backend.projections.unregister("purchase counter");
+        backend.projections.register(counter(false));
+        backend.datastore = Datastore::default();
+        assert_eq!(backend.reload().unwrap(), 6);
+        assert_eq!(backend.projections.state_of("purchase counter"), Some(expected));
+    }
+}
@@ -0,0 +1,199 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::iter::FromIterator;
+use std::ops::Deref;
+use std::slice;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use datastore::Purchase;
+use datastore::PurchaseFunctions;
+use datastore::find_purchase_indices;
+
+
+//all open purchases, sorted by (timestamp, unique id), plus an index from unique id to timestamp.
+//purchases with a client supplied timestamp in the past are inserted at their place in time,
+//so that billing ranges and lookups by id stay correct
+#[derive(Debug, Clone, Default)]
+pub struct PurchaseLog {
+    purchases: Vec<Purchase>,
+    timestamps: HashMap<u64, i64>,
+}
+
+fn sort_key(purchase: &Purchase) -> (i64, u64) {
+    (*purchase.get_timestamp(), purchase.get_unique_id())
+}
+
+impl PurchaseLog {
+    pub fn new() -> Self {
+        PurchaseLog::default()
+    }
+
+    //returns the index the purchase ended up at; a purchase with the same unique id is replaced
+    pub fn insert(&mut self, purchase: Purchase) -> usize {
+        let _ = self.remove(purchase.get_unique_id());
+        let key = sort_key(&purchase);
+        let idx = self.purchases.partition_point(|p| sort_key(p) < key);
+        self.timestamps.insert(key.1, key.0);
+        self.purchases.insert(idx, purchase);
+        idx
+    }
+
+    pub fn position(&self, unique_id: u64) -> Option<usize> {
+        let timestamp = *self.timestamps.get(&unique_id)?;
+        self.purchases.binary_search_by(|p| sort_key(p).cmp(&(timestamp, unique_id))).ok()
+    }
+
+    pub fn contains(&self, unique_id: u64) -> bool {
+        self.timestamps.contains_key(&unique_id)
+    }
+
+    pub fn get(&self, unique_id: u64) -> Option<&Purchase> {
+        self.position(unique_id).map(|idx| &self.purchases[idx])
+    }
+
+    //timestamp and unique id must not be changed through the returned reference
+    pub fn get_mut(&mut self, unique_id: u64) -> Option<&mut Purchase> {
+        match self.position(unique_id) {
+            Some(idx) => self.purchases.get_mut(idx),
+            None => None,
+        }
+    }
+
+    pub fn remove(&mut self, unique_id: u64) -> Option<Purchase> {
+        self.position(unique_id).map(|idx| self.remove_at(idx))
+    }
+
+    pub fn remove_at(&mut self, index: usize) -> Purchase {
+        let purchase = self.purchases.remove(index);
+        self.timestamps.remove(&purchase.get_unique_id());
+        purchase
+    }
+
+    pub fn remove_indices(&mut self, indices: &[usize]) {
+        let indices: HashSet<usize> = indices.iter().cloned().collect();
+        let mut idx = 0;
+        let timestamps = &mut self.timestamps;
+        self.purchases.retain(|p| {
+            let keep = !indices.contains(&idx);
+            idx += 1;
+            if !keep {
+                timestamps.remove(&p.get_unique_id());
+            }
+            keep
+        });
+    }
+
+    //purchases with millis_start_inclusive <= timestamp < millis_end_exclusive
+    pub fn range(&self, millis_start_inclusive: i64, millis_end_exclusive: i64) -> &[Purchase] {
+        let (from, to) = find_purchase_indices(&self.purchases, millis_start_inclusive, millis_end_exclusive);
+        &self.purchases[from..to]
+    }
+}
+
+impl Deref for PurchaseLog {
+    type Target = [Purchase];
+
+    fn deref(&self) -> &[Purchase] {
+        &self.purchases
+    }
+}
+
+impl<'a> IntoIterator for &'a PurchaseLog {
+    type Item = &'a Purchase;
+    type IntoIter = slice::Iter<'a, Purchase>;
+
+    fn into_iter(self) -> slice::Iter<'a, Purchase> {
+        self.purchases.iter()
+    }
+}
+
+impl FromIterator<Purchase> for PurchaseLog {
+    fn from_iter<I: IntoIterator<Item = Purchase>>(iter: I) -> Self {
+        let mut purchases: Vec<Purchase> = iter.into_iter().collect();
+        purchases.sort_by_key(sort_key);
+        purchases.dedup_by_key(|p| p.get_unique_id());
+        let timestamps = purchases.iter().map(|p| (p.get_unique_id(), *p.get_timestamp())).collect();
+        PurchaseLog {
+            purchases,
+            timestamps,
+        }
+    }
+}
+
+//stored as a plain list, exactly like the Vec<Purchase> of older snapshots
+impl Serialize for PurchaseLog {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.purchases.serialize(serializer)
+    }
+}
+
+//older snapshots may contain purchases out of order, so they are sorted on load
+impl<'de> Deserialize<'de> for PurchaseLog {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let purchases: Vec<Purchase> = Vec::deserialize(deserializer)?;
+        Ok(purchases.into_iter().collect())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use purchase_log::*;
+    use serde_yaml;
+
+    fn simple(unique_id: u64, timestamp: i64) -> Purchase {
+        Purchase::SimplePurchase {
+            unique_id,
+            timestamp_epoch_millis: timestamp,
+            item_id: 0,
+            consumer_id: 0,
+            unit_price_cents: None,
+            group_id: None,
+        }
+    }
+
+    fn ids(purchases: &[Purchase]) -> Vec<u64> {
+        purchases.iter().map(|p| p.get_unique_id()).collect()
+    }
+
+    #[test]
+    fn backdated_purchases_are_kept_in_order() {
+        let mut log = PurchaseLog::new();
+        assert_eq!(log.insert(simple(1, 100)), 0);
+        assert_eq!(log.insert(simple(2, 300)), 1);
+        assert_eq!(log.insert(simple(3, 200)), 1);
+        assert_eq!(log.insert(simple(4, 200)), 2);
+        assert_eq!(log.insert(simple(5, 50)), 0);
+        assert_eq!(ids(&log), vec![5, 1, 3, 4, 2]);
+
+        assert_eq!(ids(log.range(100, 300)), vec![1, 3, 4]);
+        assert_eq!(ids(log.range(200, 201)), vec![3, 4]);
+        assert_eq!(ids(log.range(301, 400)), Vec::<u64>::new());
+        assert_eq!(log.position(4), Some(3));
+        assert_eq!(*log.get(3).unwrap().get_timestamp(), 200);
+        assert!(log.get(6).is_none());
+
+        assert_eq!(log.remove(3).map(|p| p.get_unique_id()), Some(3));
+        assert!(!log.contains(3));
+        log.remove_indices(&[0, 2]);
+        assert_eq!(ids(&log), vec![1, 2]);
+        assert_eq!(log.position(2), Some(1));
+    }
+
+    #[test]
+    fn unsorted_legacy_lists_are_sorted_on_load() {
+        let legacy: Vec<Purchase> = vec![simple(1, 100), simple(2, 300), simple(3, 200)];
+        let yaml = serde_yaml::to_string(&legacy).unwrap();
+        let log: PurchaseLog = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(ids(&log), vec![1, 3, 2]);
+        assert_eq!(log.position(2), Some(2));
+
+        let reloaded: PurchaseLog = serde_yaml::from_str(&serde_yaml::to_string(&log).unwrap()).unwrap();
+        assert_eq!(ids(&reloaded), vec![1, 3, 2]);
+    }
+}
@@ -0,0 +1,253 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use datastore::Datastore;
+use datastore::Purchase;
+use datastore::PurchaseFunctions;
+use datastore::UserGroup;
+use datastore::matches_usergroup;
+use typescriptify::TypeScriptifyTrait;
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, TypeScriptify)]
+pub enum PurchaseKind {
+    Simple,
+    Special,
+    FreeForAll,
+}
+
+impl PurchaseKind {
+    pub fn of(purchase: &Purchase) -> Self {
+        match *purchase {
+            Purchase::SimplePurchase { .. } => PurchaseKind::Simple,
+            Purchase::SpecialPurchase { .. } => PurchaseKind::Special,
+            Purchase::FFAPurchase { .. } => PurchaseKind::FreeForAll,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default, TypeScriptify)]
+pub enum PurchaseOrdering {
+    #[default]
+    OldestFirst,
+    NewestFirst,
+    ByUniqueId,
+    ByUserThenOldestFirst,
+}
+
+
+//every filter left at None matches all purchases
+//billed means that the purchase is covered by an existing (not yet finalized) bill
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, TypeScriptify)]
+pub struct PurchaseQuery {
+    pub users: Option<UserGroup>,
+    pub item_ids: Option<HashSet<u32>>,
+    pub category: Option<String>,
+    pub kinds: Option<HashSet<PurchaseKind>>,
+    pub freeby_id: Option<u64>,
+    pub donor: Option<u32>,
+    pub millis_start_inclusive: Option<i64>,
+    pub millis_end_exclusive: Option<i64>,
+    pub billed: Option<bool>,
+    pub ordering: PurchaseOrdering,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl PurchaseQuery {
+    pub fn new() -> Self {
+        PurchaseQuery::default()
+    }
+
+    pub fn user(self, user_id: u32) -> Self {
+        self.users(UserGroup::SingleUser { user_id })
+    }
+
+    pub fn users(mut self, users: UserGroup) -> Self {
+        self.users = Some(users);
+        self
+    }
+
+    pub fn item(self, item_id: u32) -> Self {
+        self.items(&[item_id])
+    }
+
+    pub fn items(mut self, item_ids: &[u32]) -> Self {
+        self.item_ids = Some(item_ids.iter().cloned().collect());
+        self
+    }
+
+    pub fn category(mut self, category: &str) -> Self {
+        self.category = Some(category.to_string());
+        self
+    }
+
+    pub fn kind(mut self, kind: PurchaseKind) -> Self {
+        self.kinds.get_or_insert_with(HashSet::new).insert(kind);
+        self
+    }
+
+    pub fn freeby(mut self, freeby_id: u64) -> Self {
+        self.freeby_id = Some(freeby_id);
+        self
+    }
+
+    pub fn donor(mut self, donor: u32) -> Self {
+        self.donor = Some(donor);
+        self
+    }
+
+    pub fn between(mut self, millis_start_inclusive: i64, millis_end_exclusive: i64) -> Self {
+        self.millis_start_inclusive = Some(millis_start_inclusive);
+        self.millis_end_exclusive = Some(millis_end_exclusive);
+        self
+    }
+
+    pub fn billed(mut self, billed: bool) -> Self {
+        self.billed = Some(billed);
+        self
+    }
+
+    pub fn order_by(mut self, ordering: PurchaseOrdering) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn matches(&self, store: &Datastore, purchase: &Purchase) -> bool {
+        let timestamp = *purchase.get_timestamp();
+        if self.millis_start_inclusive.map(|t| timestamp < t).unwrap_or(false)
+            || self.millis_end_exclusive.map(|t| timestamp >= t).unwrap_or(false) {
+            return false;
+        }
+        if let Some(ref users) = self.users {
+            if !matches_usergroup(&Some(*purchase.get_user_id()), users) {
+                return false;
+            }
+        }
+        if let Some(ref kinds) = self.kinds {
+            if !kinds.contains(&PurchaseKind::of(purchase)) {
+                return false;
+            }
+        }
+        if self.item_ids.is_some() || self.category.is_some() {
+            if !purchase.has_item_id() {
+                return false;
+            }
+            let item_id = *purchase.get_item_id();
+            if self.item_ids.as_ref().map(|ids| !ids.contains(&item_id)).unwrap_or(false) {
+                return false;
+            }
+            if let Some(ref category) = self.category {
+                if store.items.get(&item_id).and_then(|i| i.category.as_ref()) != Some(category) {
+                    return false;
+                }
+            }
+        }
+        if self.freeby_id.is_some() || self.donor.is_some() {
+            match *purchase {
+                Purchase::FFAPurchase { freeby_id, donor, .. } => {
+                    if self.freeby_id.map(|id| id != freeby_id).unwrap_or(false) || self.donor.map(|id| id != donor).unwrap_or(false) {
+                        return false;
+                    }
+                },
+                _ => return false,
+            }
+        }
+        if let Some(billed) = self.billed {
+            let is_billed = store.bills.iter().any(|b| {
+                b.timestamp_from <= timestamp && timestamp < b.timestamp_to && matches_usergroup(&Some(*purchase.get_user_id()), &b.users)
+            });
+            if billed != is_billed {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn execute<'a>(&self, store: &'a Datastore) -> Vec<&'a Purchase> {
+        //the purchase log keeps backdated purchases at their place in time, so only the requested window has to be scanned
+        let from = self.millis_start_inclusive.unwrap_or(i64::MIN);
+        let to = self.millis_end_exclusive.unwrap_or(i64::MAX).max(from);
+        let window: &'a [Purchase] = store.purchases.range(from, to);
+
+        let mut v: Vec<&'a Purchase> = window.iter().filter(|p| self.matches(store, p)).collect();
+        match self.ordering {
+            PurchaseOrdering::OldestFirst => v.sort_by_key(|p| (*p.get_timestamp(), p.get_unique_id())),
+            PurchaseOrdering::NewestFirst => v.sort_by_key(|p| Reverse((*p.get_timestamp(), p.get_unique_id()))),
+            PurchaseOrdering::ByUniqueId => v.sort_by_key(|p| p.get_unique_id()),
+            PurchaseOrdering::ByUserThenOldestFirst => v.sort_by_key(|p| (*p.get_user_id(), *p.get_timestamp(), p.get_unique_id())),
+        }
+        let limit = self.limit.unwrap_or(v.len());
+        v.into_iter().skip(self.offset).take(limit).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use purchase_query::*;
+    use datastore::DatastoreQueries;
+    use rustix_backend::WriteBackend;
+
+    #[test]
+    fn filters_can_be_combined() {
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.create_user("dieter".to_string());
+        backend.create_item("beer".to_string(), 100, Some("Alcohol".to_string()));
+        backend.create_item("soda".to_string(), 50, None);
+        backend.create_ffa(vec![], vec![1], 5, "round of soda".to_string(), 5, 1);
+
+        backend.purchase(0, 0, 10);
+        backend.purchase(1, 0, 20);
+        backend.purchase(0, 1, 30);
+        backend.special_purchase(0, "pizza".to_string(), 40);
+        backend.ffa_purchase(1, 1, 50);
+        backend.purchase(1, 1, 60);
+        backend.create_bill(0, 35, UserGroup::SingleUser { user_id: 0 }, "klaus only".to_string());
+
+        let store = &backend.datastore;
+        let ids = |v: Vec<&Purchase>| -> Vec<u64> { v.iter().map(|p| p.get_unique_id()).collect() };
+
+        assert_eq!(ids(PurchaseQuery::new().execute(store)), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(ids(PurchaseQuery::new().user(0).execute(store)), vec![1, 3, 4]);
+        assert_eq!(ids(PurchaseQuery::new().category("Alcohol").execute(store)), vec![1, 2]);
+        assert_eq!(ids(PurchaseQuery::new().item(1).kind(PurchaseKind::Simple).execute(store)), vec![3, 6]);
+        assert_eq!(ids(PurchaseQuery::new().kind(PurchaseKind::Special).kind(PurchaseKind::FreeForAll).execute(store)), vec![4, 5]);
+        assert_eq!(ids(PurchaseQuery::new().freeby(1).donor(1).execute(store)), vec![5]);
+        assert_eq!(ids(PurchaseQuery::new().donor(0).execute(store)), Vec::<u64>::new());
+        assert_eq!(ids(PurchaseQuery::new().between(20, 50).execute(store)), vec![2, 3, 4]);
+        assert_eq!(ids(PurchaseQuery::new().billed(true).execute(store)), vec![1, 3]);
+        assert_eq!(ids(PurchaseQuery::new().billed(false).users(UserGroup::AllUsers).execute(store)), vec![2, 4, 5, 6]);
+        assert_eq!(ids(PurchaseQuery::new().order_by(PurchaseOrdering::NewestFirst).offset(1).limit(2).execute(store)), vec![5, 4]);
+        assert_eq!(ids(PurchaseQuery::new().order_by(PurchaseOrdering::ByUserThenOldestFirst).execute(store)), vec![1, 3, 4, 2, 5, 6]);
+    }
+
+    #[test]
+    fn backdated_purchases_are_found_in_their_time_window() {
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.create_item("beer".to_string(), 100, None);
+        backend.purchase(0, 0, 50);
+        backend.purchase(0, 0, 10);
+        backend.purchase(0, 0, 30);
+
+        let store = &backend.datastore;
+        let ids = |v: Vec<&Purchase>| -> Vec<u64> { v.iter().map(|p| p.get_unique_id()).collect() };
+        assert_eq!(ids(PurchaseQuery::new().between(0, 40).execute(store)), vec![2, 3]);
+        assert_eq!(ids(PurchaseQuery::new().between(40, 60).execute(store)), vec![1]);
+        assert_eq!(store.personal_log_filtered(0, 20, 60).len(), 2);
+    }
+}
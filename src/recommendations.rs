@@ -0,0 +1,194 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use calendar;
+
+
+//purchase patterns across all users, built up incrementally from every simple purchase.
+//they are kept when purchases are billed, as they describe habits rather than balances, but undone purchases are reverted
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct PurchasePatterns {
+    //user_id => item_id => number of purchases
+    pub items_per_user: HashMap<u32, HashMap<u32, u32>>,
+    //item_id => item_id => number of users that bought both
+    pub co_purchases: HashMap<u32, HashMap<u32, u32>>,
+    //item_id => number of purchases per hour of the day in the shop's local time
+    pub purchases_per_hour: HashMap<u32, Vec<u32>>,
+    //item_id => number of purchases
+    pub popularity: HashMap<u32, u32>,
+}
+
+fn decrement_pair(pairs: &mut HashMap<u32, HashMap<u32, u32>>, item_id: u32, other: u32) {
+    if let Some(counts) = pairs.get_mut(&item_id) {
+        if let Some(count) = counts.get_mut(&other) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&other);
+            }
+        }
+        if counts.is_empty() {
+            pairs.remove(&item_id);
+        }
+    }
+}
+
+//timestamps passed to PurchasePatterns are local, see TimeZone::to_local
+impl PurchasePatterns {
+    pub fn record_purchase(&mut self, user_id: u32, item_id: u32, local_millis: i64) {
+        let history = self.items_per_user.entry(user_id).or_default();
+
+        //the first purchase of an item pairs it with everything the user bought before
+        if !history.contains_key(&item_id) {
+            for other in history.keys() {
+                *self.co_purchases.entry(item_id).or_default().entry(*other).or_insert(0) += 1;
+                *self.co_purchases.entry(*other).or_default().entry(item_id).or_insert(0) += 1;
+            }
+        }
+        *history.entry(item_id).or_insert(0) += 1;
+
+        let hours = self.purchases_per_hour.entry(item_id).or_insert_with(|| vec![0; 24]);
+        hours[calendar::hour_of_day(local_millis) as usize] += 1;
+        *self.popularity.entry(item_id).or_insert(0) += 1;
+    }
+
+    //the exact opposite of record_purchase, entries that drop to 0 are removed
+    pub fn revert_purchase(&mut self, user_id: u32, item_id: u32, local_millis: i64) {
+        let history = match self.items_per_user.get_mut(&user_id) {
+            Some(history) => history,
+            None => return,
+        };
+        match history.get_mut(&item_id) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => {
+                history.remove(&item_id);
+                for other in history.keys() {
+                    decrement_pair(&mut self.co_purchases, item_id, *other);
+                    decrement_pair(&mut self.co_purchases, *other, item_id);
+                }
+            },
+            None => return,
+        }
+        if history.is_empty() {
+            self.items_per_user.remove(&user_id);
+        }
+
+        if let Some(hours) = self.purchases_per_hour.get_mut(&item_id) {
+            let hour = &mut hours[calendar::hour_of_day(local_millis) as usize];
+            *hour = hour.saturating_sub(1);
+        }
+        if let Some(count) = self.popularity.get_mut(&item_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.popularity.remove(&item_id);
+            }
+        }
+    }
+
+    pub fn co_purchase_score(&self, user_id: u32, item_id: u32) -> u32 {
+        let history = match self.items_per_user.get(&user_id) {
+            Some(history) => history,
+            None => return 0,
+        };
+        match self.co_purchases.get(&item_id) {
+            Some(pairs) => history.keys().filter_map(|other| pairs.get(other)).sum(),
+            None => 0,
+        }
+    }
+
+    pub fn hourly_score(&self, item_id: u32, local_millis: i64) -> u32 {
+        self.purchases_per_hour.get(&item_id).map(|hours| hours[calendar::hour_of_day(local_millis) as usize]).unwrap_or(0)
+    }
+
+    pub fn popularity_of(&self, item_id: u32) -> u32 {
+        *self.popularity.get(&item_id).unwrap_or(&0)
+    }
+
+    pub fn has_bought(&self, user_id: u32, item_id: u32) -> bool {
+        self.items_per_user.get(&user_id).map(|history| history.contains_key(&item_id)).unwrap_or(false)
+    }
+
+    //ranks the candidates the user has not bought yet: first by how often they were bought together
+    //with the user's items, then by how often they are bought at this hour, then by overall popularity.
+    //users without history therefore get the items popular at this time of day
+    pub fn recommend<I: IntoIterator<Item = u32>>(&self, user_id: u32, local_millis: i64, candidates: I, n: usize) -> Vec<u32> {
+        let mut ranked: Vec<(u32, u32, u32, u32)> = candidates
+            .into_iter()
+            .filter(|item_id| !self.has_bought(user_id, *item_id))
+            .map(|item_id| (
+                item_id,
+                self.co_purchase_score(user_id, item_id),
+                self.hourly_score(item_id, local_millis),
+                self.popularity_of(item_id),
+            ))
+            .collect();
+        ranked.sort_by_key(|&(item_id, co, hourly, popularity)| (Reverse(co), Reverse(hourly), Reverse(popularity), item_id));
+        ranked.into_iter().take(n).map(|(item_id, _, _, _)| item_id).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use recommendations::*;
+    use calendar::MILLIS_PER_HOUR;
+    use calendar::TimeZone;
+    use datastore::DatastoreQueries;
+    use rustix_backend::WriteBackend;
+
+    const EVENING: i64 = 20 * MILLIS_PER_HOUR;
+    const MORNING: i64 = 8 * MILLIS_PER_HOUR;
+
+    #[test]
+    fn recommendations_use_co_purchases_time_of_day_and_popularity() {
+        let mut backend = ::build_transient_backend();
+        for name in &["klaus", "dieter", "heinz", "neu"] {
+            backend.create_user(name.to_string());
+        }
+        for name in &["beer", "chips", "coffee", "mate", "water"] {
+            backend.create_item(name.to_string(), 100, None);
+        }
+
+        //beer and chips go together, coffee is bought in the morning, mate is popular in the evening
+        backend.purchase(0, 0, EVENING);
+        backend.purchase(0, 1, EVENING);
+        backend.purchase(1, 0, EVENING);
+        backend.purchase(1, 1, EVENING);
+        backend.purchase(1, 2, MORNING);
+        backend.purchase(2, 2, MORNING);
+        backend.purchase(2, 2, MORNING);
+        backend.purchase(2, 3, EVENING);
+        assert_eq!(calendar::hour_of_day(EVENING + 24 * MILLIS_PER_HOUR), 20);
+        assert_eq!(calendar::hour_of_day(-1), 23);
+
+        let patterns = &backend.datastore.purchase_patterns;
+        assert_eq!(patterns.co_purchase_score(2, 0), 1);
+        assert_eq!(patterns.co_purchase_score(2, 1), 1);
+        assert_eq!(patterns.popularity_of(2), 3);
+
+        //heinz bought coffee like dieter, who also bought beer and chips
+        assert_eq!(backend.datastore.recommended_item_ids(2, EVENING, 2), vec![0, 1]);
+
+        //new users get what is bought at this time of day, then what is popular overall
+        assert_eq!(backend.datastore.recommended_item_ids(3, EVENING, 5), vec![0, 1, 3, 2, 4]);
+        assert_eq!(backend.datastore.recommended_item_ids(3, MORNING, 2), vec![2, 0]);
+
+        //undone purchases do not count, dieter's coffee no longer pairs with beer and chips
+        backend.undo_purchase(5);
+        let patterns = &backend.datastore.purchase_patterns;
+        assert_eq!((patterns.co_purchase_score(2, 0), patterns.popularity_of(2)), (0, 2));
+        assert!(!patterns.has_bought(1, 2));
+
+        //deleted items are never recommended
+        backend.delete_item(2);
+        assert_eq!(backend.datastore.recommended_item_ids(3, MORNING, 2), vec![0, 1]);
+
+        //hours are counted in the shop's time zone, 18:00 UTC is a local evening two hours east
+        assert!(backend.set_time_zone(TimeZone { utc_offset_minutes: 120, ..TimeZone::default() }));
+        backend.purchase(3, 4, EVENING - 2 * MILLIS_PER_HOUR);
+        backend.purchase(3, 4, EVENING - 2 * MILLIS_PER_HOUR);
+        assert_eq!(backend.datastore.purchase_patterns.hourly_score(4, EVENING), 2);
+        assert_eq!(backend.datastore.recommended_item_ids(0, EVENING - 2 * MILLIS_PER_HOUR, 2), vec![4, 3]);
+    }
+}
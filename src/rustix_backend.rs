@@ -10,11 +10,73 @@ use std;
 use std::fs::File;
 use std::io::prelude::*;
 use datastore::Datastore;
+use datastore::SuffixTreeRebuildable;
+use invariants::DatastoreInvariants;
+use invariants::InvariantReport;
+use projections::ProjectionRegistry;
+use projections::ProjectionSnapshot;
+use rustix_event_shop::BLEvents;
+use ledger;
+use price_adjustments::PriceChange;
+use price_adjustments::PriceSelection;
+use prepaid::PrepaidTransactionKind;
+use consumption_limits::ConsumptionRule;
+use pricing_rules::PricingWindow;
+use datastore::PriceTier;
+use datastore::TierPrice;
+use calendar::TimeZone;
+use bundles::BundleComponent;
+use std::collections::HashMap;
 
 #[derive(Debug)]
 pub struct RustixBackend {
     pub datastore: datastore::Datastore,
     pub persistencer: persistencer::FilePersister,
+    pub projections: ProjectionRegistry,
+}
+
+impl RustixBackend {
+    //only events that were stored and applied reach the projections
+    fn persist_and_apply(&mut self, event: &BLEvents) -> bool {
+        let version = self.datastore.version;
+        let result = self.persistencer.test_store_apply(event, &mut self.datastore);
+        if self.datastore.version != version {
+            self.projections.apply(event, &self.datastore);
+        }
+        result
+    }
+
+    //stored next to snapshot.yaml, as the projections are not part of the datastore
+    fn projection_snapshot_path(&self) -> String {
+        self.persistencer.config.persistence_file_path.to_owned() + "/projections.yaml"
+    }
+
+    fn snapshot_projections(&self) {
+        let snapshot = self.projections.snapshot(self.datastore.version);
+        let result = serde_yaml::to_string(&snapshot)
+            .map_err(|e| format!("{:?}", e))
+            .and_then(|yaml| std::fs::write(self.projection_snapshot_path(), yaml).map_err(|e| format!("{:?}", e)));
+        if let Err(e) = result {
+            println!("Error writing projections on snapshot(): {}", e);
+        }
+    }
+
+    fn load_projection_snapshot(&self) -> Option<ProjectionSnapshot> {
+        let contents = std::fs::read_to_string(self.projection_snapshot_path()).ok()?;
+        serde_yaml::from_str(&contents).ok()
+    }
+
+    //resets the named projections and replays the event log up to the current version into them
+    pub fn rebuild_projections(&mut self, names: &[String]) -> Result<u64, persistencer::RustixError> {
+        let version = self.datastore.version;
+        let mut scratch = Datastore::default();
+        let projections = &mut self.projections;
+        self.persistencer.reload_from_filepath_observed(&mut scratch, &mut |event, store| {
+            if store.version <= version {
+                projections.apply_to(names, event, store);
+            }
+        })
+    }
 }
 /*
 
@@ -34,18 +96,29 @@ pub trait WriteBackend {
 
     fn load_snapshot(&mut self) -> Option<u64>;
 
+    //recomputes all derived state, e.g. after load_snapshot, and reports what changed
+    fn rebuild_derived_state(&mut self) -> InvariantReport;
+
+    //items running low at the configured StaticConfig::low_stock_threshold
+    fn low_stock_item_ids(&self) -> Vec<u32>;
+
     fn create_bill(&mut self, timestamp_from: i64, timestamp_to: i64, user_ids: UserGroup, comment: String) -> bool;
     fn create_item(&mut self, itemname: String, price_cents: u32, category: Option<String>)
                    -> bool;
     fn create_user(&mut self, username: String) -> bool;
     fn update_item(&mut self, item_id: u32, itemname: String, price_cents: u32, category: Option<String>)
                    -> bool;
+    //like update_item, with the new price applying to purchases from millis_timestamp on
+    //tier_prices change the prices of other tiers along with the member price
+    fn update_item_at(&mut self, item_id: u32, itemname: String, price_cents: u32, tier_prices: Vec<TierPrice>, category: Option<String>, millis_timestamp: i64)
+                   -> bool;
     fn update_user(&mut self, user_id: u32, username: String, is_billed: bool, is_highlighted: bool, external_user_id: Option<String>, is_sepa: bool) -> bool;
 
     fn delete_user(&mut self, user_id: u32) -> bool;
     fn delete_item(&mut self, item_id: u32) -> bool;
 
     fn purchase(&mut self, user_id: u32, item_id: u32, millis_timestamp: i64) -> bool;
+    fn purchase_quantity(&mut self, user_id: u32, item_id: u32, quantity: u32, millis_timestamp: i64) -> bool;
 
     fn special_purchase(&mut self, user_id: u32, special_name: String, millis_timestamp: i64) -> bool;
 
@@ -76,20 +149,64 @@ pub trait WriteBackend {
 
     fn undo_purchase(&mut self, unique_id: u64) -> bool;
 
+    //the change is applied with the first purchase at or after millis_timestamp
+    fn schedule_item_update(&mut self, item_id: u32, itemname: String, price_cents: u32, tier_prices: Vec<TierPrice>, category: Option<String>, millis_timestamp: i64) -> bool;
+
+    fn cancel_scheduled_item_update(&mut self, schedule_id: u64) -> bool;
+
+    //one event for all selected items, rejected as a whole if any of them does not exist
+    fn bulk_update_prices(&mut self, selection: PriceSelection, change: PriceChange) -> bool;
+
+    //the first delivery or correction of an item starts tracking its stock
+    fn deliver_stock(&mut self, item_id: u32, count: u32, millis_timestamp: i64, comment: String) -> bool;
+    fn correct_stock(&mut self, item_id: u32, level: i64, millis_timestamp: i64, comment: String) -> bool;
+    fn write_off_stock(&mut self, item_id: u32, count: u32, millis_timestamp: i64, comment: String) -> bool;
+
+    //counted units per item id, the stock of every counted item is set to its count
+    fn take_stock(&mut self, counted: HashMap<u32, i64>, millis_timestamp: i64, comment: String) -> bool;
+
+    fn set_acquisition_cost(&mut self, item_id: u32, cost_cents: u32, millis_timestamp: i64) -> bool;
+
+    fn create_supplier(&mut self, name: String, contact: String) -> bool;
+    fn set_supplier_item(&mut self, supplier_id: u32, item_id: u32, pack_size: u32, pack_cost_cents: Option<u32>) -> bool;
+    fn draft_supplier_order(&mut self, supplier_id: u32, millis_timestamp: i64, lookback_days: u32, days_of_cover: u32) -> bool;
+    fn set_supplier_order_line(&mut self, order_id: u64, item_id: u32, packs: u32) -> bool;
+    fn receive_supplier_order(&mut self, order_id: u64, millis_timestamp: i64) -> bool;
+    fn cancel_supplier_order(&mut self, order_id: u64) -> bool;
+
+    fn set_item_deposit(&mut self, item_id: u32, deposit_cents: u32) -> bool;
+    fn return_deposit(&mut self, user_id: u32, item_id: u32, count: u32, millis_timestamp: i64) -> bool;
+
+    fn open_prepaid_account(&mut self, user_id: u32, overdraft_limit_cents: u64) -> bool;
+    fn top_up_prepaid(&mut self, user_id: u32, cents: u64, method: PrepaidTransactionKind, millis_timestamp: i64, comment: String) -> bool;
+
+    fn set_consumption_rules(&mut self, user_id: u32, rules: Vec<ConsumptionRule>) -> bool;
+    fn override_consumption_limits(&mut self, user_id: u32, millis_timestamp: i64, valid_until: i64, comment: String) -> bool;
+
+    fn create_pricing_rule(&mut self, name: String, selection: PriceSelection, change: PriceChange, window: PricingWindow) -> bool;
+    fn update_pricing_rule(&mut self, rule_id: u64, name: String, selection: PriceSelection, change: PriceChange, window: PricingWindow) -> bool;
+    fn remove_pricing_rule(&mut self, rule_id: u64) -> bool;
+    fn set_time_zone(&mut self, time_zone: TimeZone) -> bool;
+
+    fn set_user_price_tier(&mut self, user_id: u32, price_tier: PriceTier) -> bool;
+    fn set_item_tier_price(&mut self, item_id: u32, price_tier: PriceTier, cost_cents: Option<u32>) -> bool;
+
+    fn create_bundle(&mut self, itemname: String, price_cents: u32, category: Option<String>, components: Vec<BundleComponent>) -> bool;
+    fn set_bundle_components(&mut self, item_id: u32, components: Vec<BundleComponent>) -> bool;
+
     fn reload(&mut self) -> Result<u64, persistencer::RustixError>;
 }
 
 
 impl WriteBackend for RustixBackend {
     fn create_bill(&mut self, timestamp_from: i64, timestamp_to: i64, user_ids: UserGroup, comment: String) -> bool {
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::CreateBill {
                 timestamp_from: timestamp_from,
                 timestamp_to: timestamp_to,
                 user_ids: user_ids,
                 comment: comment,
             },
-            &mut self.datastore,
         );
     }
 
@@ -99,85 +216,93 @@ impl WriteBackend for RustixBackend {
         price_cents: u32,
         category: Option<String>,
     ) -> bool {
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::CreateItem {
                 itemname: itemname,
                 price_cents: price_cents,
                 category: category,
             },
-            &mut self.datastore,
         );
     }
 
     fn create_user(&mut self, username: String) -> bool {
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::CreateUser { username: username },
-            &mut self.datastore,
         );
     }
 
     fn delete_user(&mut self, user_id: u32) -> bool {
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::DeleteUser { user_id: user_id },
-            &mut self.datastore,
         );
     }
 
     fn delete_item(&mut self, item_id: u32) -> bool {
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::DeleteItem { item_id: item_id },
-            &mut self.datastore,
         );
     }
 
     fn purchase(&mut self, user_id: u32, item_id: u32, millis_timestamp: i64) -> bool {
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::MakeSimplePurchase {
                 user_id: user_id,
                 item_id: item_id,
                 timestamp: millis_timestamp,
+                quantity: 1,
             },
-            &mut self.datastore,
         );
     }
 
+    fn purchase_quantity(&mut self, user_id: u32, item_id: u32, quantity: u32, millis_timestamp: i64) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::MakeSimplePurchase { user_id, item_id, timestamp: millis_timestamp, quantity })
+    }
+
 
+    fn rebuild_derived_state(&mut self) -> InvariantReport {
+        self.datastore.rebuild_derived_state(&self.persistencer.config)
+    }
+    fn low_stock_item_ids(&self) -> Vec<u32> {
+        datastore::DatastoreQueries::low_stock_item_ids(&self.datastore, self.persistencer.config.low_stock_threshold)
+    }
     fn reload(&mut self) -> Result<u64, persistencer::RustixError> {
         let counter = self.load_snapshot();
-        return self.persistencer.reload_from_filepath(&mut self.datastore);
+        if counter.is_none() && self.datastore.version == 0 {
+            //replaying from the very first event
+            self.projections.restore(None, 0);
+        }
+        let projections = &mut self.projections;
+        return self.persistencer.reload_from_filepath_observed(&mut self.datastore, &mut |event, store| projections.apply(event, store));
     }
     fn undo_purchase(&mut self, unique_id: u64) -> bool {
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::UndoPurchase {
                 unique_id: unique_id,
             },
-            &mut self.datastore,
         );
     }
     fn special_purchase(&mut self, user_id: u32, special_name: String, millis_timestamp: i64) -> bool {
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::MakeSpecialPurchase {
                 user_id: user_id,
                 special_name: special_name,
                 timestamp: millis_timestamp,
             },
-            &mut self.datastore,
         );
     }
 
     fn ffa_purchase(&mut self, ffa_id: u64, item_id: u32, millis_timestamp: i64) -> bool {
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::MakeFreeForAllPurchase {
             ffa_id: ffa_id,
                 item_id: item_id,
             timestamp: millis_timestamp,
             },
-            &mut self.datastore,
         );
     }
 
     fn create_ffa(&mut self, allowed_categories: Vec<String>, allowed_drinks: Vec<u32>, allowed_number_total: u16, text_message: String, created_timestamp: i64, donor: u32) -> bool {
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::CreateFreeForAll {
                 allowed_categories: allowed_categories,
                 allowed_drinks: allowed_drinks,
@@ -186,13 +311,12 @@ impl WriteBackend for RustixBackend {
                 created_timestamp: created_timestamp,
                 donor: donor,
             },
-            &mut self.datastore,
         );
     }
 
     fn create_free_budget(&mut self, cents_worth_total: u64, text_message: String, created_timestamp: i64, donor: u32, recipient: u32) -> bool {
 
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::CreateFreeBudget {
                 cents_worth_total: cents_worth_total,
                 text_message: text_message,
@@ -200,13 +324,12 @@ impl WriteBackend for RustixBackend {
                 donor: donor,
                 recipient: recipient,
             },
-            &mut self.datastore,
         );
     }
 
     fn create_free_count(&mut self, allowed_categories: Vec<String>, allowed_drinks: Vec<u32>, allowed_number_total: u16, text_message: String, created_timestamp: i64, donor: u32, recipient: u32) -> bool {
 
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::CreateFreeCount {
                 allowed_categories: allowed_categories,
                 allowed_drinks: allowed_drinks,
@@ -216,34 +339,167 @@ impl WriteBackend for RustixBackend {
                 donor: donor,
                 recipient: recipient,
             },
-            &mut self.datastore,
         );
     }
     fn cart_purchase(&mut self, user_id: u32, specials: Vec<String>, item_ids: Vec<u32>, millis_timestamp: i64) -> bool {
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::MakeShoppingCartPurchase {
                 user_id: user_id,
                 specials: specials,
                 item_ids: item_ids,
                 timestamp: millis_timestamp,
             },
-            &mut self.datastore,
         );
     }
     fn update_item(&mut self, item_id: u32, itemname: String, price_cents: u32, category: Option<String>) -> bool {
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::UpdateItem {
                 item_id: item_id,
                 itemname: itemname,
                 price_cents: price_cents,
                 category: category,
+                timestamp: None,
+                tier_prices: Vec::new(),
             },
-            &mut self.datastore,
         );
     }
 
+    fn update_item_at(&mut self, item_id: u32, itemname: String, price_cents: u32, tier_prices: Vec<TierPrice>, category: Option<String>, millis_timestamp: i64) -> bool {
+        self.persist_and_apply(
+            &rustix_event_shop::BLEvents::UpdateItem {
+                item_id,
+                itemname,
+                price_cents,
+                category,
+                timestamp: Some(millis_timestamp),
+                tier_prices,
+            },
+        )
+    }
+
+    fn schedule_item_update(&mut self, item_id: u32, itemname: String, price_cents: u32, tier_prices: Vec<TierPrice>, category: Option<String>, millis_timestamp: i64) -> bool {
+        self.persist_and_apply(
+            &rustix_event_shop::BLEvents::ScheduleItemUpdate {
+                item_id,
+                itemname,
+                price_cents,
+                category,
+                effective_timestamp: millis_timestamp,
+                tier_prices,
+            },
+        )
+    }
+
+    fn cancel_scheduled_item_update(&mut self, schedule_id: u64) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::CancelScheduledItemUpdate { schedule_id })
+    }
+
+    fn bulk_update_prices(&mut self, selection: PriceSelection, change: PriceChange) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::BulkUpdatePrices { selection, change, timestamp: None })
+    }
+
+    fn deliver_stock(&mut self, item_id: u32, count: u32, millis_timestamp: i64, comment: String) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::DeliverStock { item_id, count, timestamp: millis_timestamp, comment })
+    }
+
+    fn correct_stock(&mut self, item_id: u32, level: i64, millis_timestamp: i64, comment: String) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::CorrectStock { item_id, level, timestamp: millis_timestamp, comment })
+    }
+
+    fn write_off_stock(&mut self, item_id: u32, count: u32, millis_timestamp: i64, comment: String) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::WriteOffStock { item_id, count, timestamp: millis_timestamp, comment })
+    }
+
+    fn take_stock(&mut self, counted: HashMap<u32, i64>, millis_timestamp: i64, comment: String) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::TakeStock { counted, timestamp: millis_timestamp, comment })
+    }
+
+    fn set_acquisition_cost(&mut self, item_id: u32, cost_cents: u32, millis_timestamp: i64) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::SetAcquisitionCost { item_id, cost_cents, timestamp: millis_timestamp })
+    }
+
+    fn create_supplier(&mut self, name: String, contact: String) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::CreateSupplier { name, contact })
+    }
+
+    fn set_supplier_item(&mut self, supplier_id: u32, item_id: u32, pack_size: u32, pack_cost_cents: Option<u32>) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::SetSupplierItem { supplier_id, item_id, pack_size, pack_cost_cents })
+    }
+
+    fn draft_supplier_order(&mut self, supplier_id: u32, millis_timestamp: i64, lookback_days: u32, days_of_cover: u32) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::DraftSupplierOrder { supplier_id, timestamp: millis_timestamp, lookback_days, days_of_cover })
+    }
+
+    fn set_supplier_order_line(&mut self, order_id: u64, item_id: u32, packs: u32) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::SetSupplierOrderLine { order_id, item_id, packs })
+    }
+
+    fn receive_supplier_order(&mut self, order_id: u64, millis_timestamp: i64) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::ReceiveSupplierOrder { order_id, timestamp: millis_timestamp })
+    }
+
+    fn cancel_supplier_order(&mut self, order_id: u64) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::CancelSupplierOrder { order_id })
+    }
+
+    fn set_item_deposit(&mut self, item_id: u32, deposit_cents: u32) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::SetItemDeposit { item_id, deposit_cents })
+    }
+
+    fn return_deposit(&mut self, user_id: u32, item_id: u32, count: u32, millis_timestamp: i64) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::ReturnDeposit { user_id, item_id, count, timestamp: millis_timestamp })
+    }
+
+    fn open_prepaid_account(&mut self, user_id: u32, overdraft_limit_cents: u64) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::OpenPrepaidAccount { user_id, overdraft_limit_cents })
+    }
+
+    fn top_up_prepaid(&mut self, user_id: u32, cents: u64, method: PrepaidTransactionKind, millis_timestamp: i64, comment: String) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::TopUpPrepaid { user_id, cents, method, timestamp: millis_timestamp, comment })
+    }
+
+    fn set_consumption_rules(&mut self, user_id: u32, rules: Vec<ConsumptionRule>) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::SetConsumptionRules { user_id, rules })
+    }
+
+    fn override_consumption_limits(&mut self, user_id: u32, millis_timestamp: i64, valid_until: i64, comment: String) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::OverrideConsumptionLimits { user_id, timestamp: millis_timestamp, valid_until, comment })
+    }
+
+    fn create_pricing_rule(&mut self, name: String, selection: PriceSelection, change: PriceChange, window: PricingWindow) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::CreatePricingRule { name, selection, change, window })
+    }
+
+    fn update_pricing_rule(&mut self, rule_id: u64, name: String, selection: PriceSelection, change: PriceChange, window: PricingWindow) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::UpdatePricingRule { rule_id, name, selection, change, window })
+    }
+
+    fn remove_pricing_rule(&mut self, rule_id: u64) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::RemovePricingRule { rule_id })
+    }
+
+    fn set_time_zone(&mut self, time_zone: TimeZone) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::SetTimeZone { time_zone })
+    }
+
+    fn set_user_price_tier(&mut self, user_id: u32, price_tier: PriceTier) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::SetUserPriceTier { user_id, price_tier, timestamp: None })
+    }
+
+    fn set_item_tier_price(&mut self, item_id: u32, price_tier: PriceTier, cost_cents: Option<u32>) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::SetItemTierPrice { item_id, price_tier, cost_cents, timestamp: None })
+    }
+
+    fn create_bundle(&mut self, itemname: String, price_cents: u32, category: Option<String>, components: Vec<BundleComponent>) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::CreateBundle { itemname, price_cents, category, components })
+    }
+
+    fn set_bundle_components(&mut self, item_id: u32, components: Vec<BundleComponent>) -> bool {
+        self.persist_and_apply(&rustix_event_shop::BLEvents::SetBundleComponents { item_id, components })
+    }
+
     fn update_user(&mut self, user_id: u32, username: String, is_billed: bool, is_highlighted: bool, external_user_id: Option<String>, is_sepa: bool) -> bool {
-        return self.persistencer.test_store_apply(
+        return self.persist_and_apply(
             &rustix_event_shop::BLEvents::UpdateUser {
                 user_id: user_id,
                 username: username,
@@ -252,11 +508,10 @@ impl WriteBackend for RustixBackend {
                 external_user_id: external_user_id,
                 is_sepa: is_sepa,
             },
-            &mut self.datastore,
         );
     }
     fn apply(&mut self, event: &rustix_event_shop::BLEvents) -> bool {
-        return self.persistencer.test_store_apply(event, &mut self.datastore);
+        return self.persist_and_apply(event);
     }
 
     fn snapshot(&mut self) -> Option<u64> {
@@ -286,6 +541,7 @@ impl WriteBackend for RustixBackend {
                             Ok(e) => {
                                 //if successful, return version of aggregate
                                 println!("Success on snapshot()");
+                                self.snapshot_projections();
                                 return Some(self.datastore.version);
                             },
                             Err(e) => {
@@ -337,6 +593,19 @@ impl WriteBackend for RustixBackend {
         let version: u64 = ds.version;
         self.datastore = ds;
 
+        //search indices of older snapshots are not compatible, so always rebuild them
+        self.datastore.rebuild_user_tree();
+        self.datastore.rebuild_item_tree();
+        ledger::migrate_legacy_balances(&mut self.datastore);
+
+        //projections missing from the projection snapshot are rebuilt from the event log
+        let to_rebuild = self.projections.restore(self.load_projection_snapshot().as_ref(), version);
+        if !to_rebuild.is_empty() {
+            if let Err(e) = self.rebuild_projections(&to_rebuild) {
+                println!("Error rebuilding projections on load_snapshot(): {:?}", e);
+            }
+        }
+
         //if successful, return counter / version
         return Some(version);
     }
@@ -364,12 +633,15 @@ mod tests {
     use datastore::DatastoreQueries;
     use rustix_backend::WriteBackend;
     use rustix_event_shop::BLEvents::SetPriceForSpecial;
+    use price_adjustments::PriceChange;
+    use price_adjustments::PriceSelection;
 
     fn build_test_backend() -> RustixBackend {
         let config = config::StaticConfig::default();
         return RustixBackend {
             datastore: datastore::Datastore::default(),
             persistencer: persistencer::FilePersister::new(config).unwrap(),
+            projections: Default::default(),
         };
     }
 
@@ -683,41 +955,41 @@ mod tests {
 
         assert_eq!(backend.ffa_purchase(ffa_id, 1, 70), false);
 
-        let user_key = (0, (&backend).datastore.users.get(&0).unwrap().username.to_string());
-        let user_1_key = (1, (&backend).datastore.users.get(&1).unwrap().username.to_string());
-        let item_0_key = (0, (&backend).datastore.items.get(&0).unwrap().name.to_string());
-        let item_1_key = (1, (&backend).datastore.items.get(&1).unwrap().name.to_string());
-        println!("Testoutput = {:?}\nwith user key = {:?}\nand item key = {:?}", backend.datastore, &user_key, &item_0_key);
+        println!("Testoutput = {:?}", backend.datastore);
         assert!(backend
             .datastore
-            .balance_cost_per_user
-            .get(&user_key)
+            .ledgers
+            .get(&0)
             .is_some());
         assert_eq!(
             backend
                 .datastore
-                .balance_cost_per_user
-                .get(&user_key)
+                .ledgers
+                .get(&0)
                 .unwrap()
-                .get(&item_0_key)
-                .unwrap(),
-            &180u32
+                .items
+                .get(&0)
+                .unwrap()
+                .cost_cents,
+            180u32
         );
 
         assert_eq!(
             backend
                 .datastore
-                .balance_cost_per_user
-                .get(&user_key)
+                .ledgers
+                .get(&0)
                 .unwrap()
-                .get(&item_1_key)
-                .unwrap(),
-            &55u32
+                .items
+                .get(&1)
+                .unwrap()
+                .cost_cents,
+            55u32
         );
 
 
         assert_eq!(
-            backend.datastore.balance_cost_per_user.get(&user_1_key).is_none(),
+            backend.datastore.ledgers.get(&1).is_none(),
             true
         );
 
@@ -817,17 +1089,17 @@ mod tests {
 
 
         //control that current balance is down to zero for all users
-//
-//        assert_eq!(
-//            backend.datastore.balance_cost_per_user.get(&user_key).is_none(),
-//            true
-//        );
-//
-//
-//        assert_eq!(
-//            backend.datastore.balance_cost_per_user.get(&user_1_key).is_none(),
-//            true
-//        );
+
+        assert_eq!(
+            backend.datastore.ledgers.get(&0).is_none(),
+            true
+        );
+
+
+        assert_eq!(
+            backend.datastore.ledgers.get(&1).is_none(),
+            true
+        );
 
 
         //control that bill contains correct data
@@ -964,6 +1236,156 @@ mod tests {
 
 
 
+    #[test]
+    fn ranked_search_for_users_and_items() {
+        let mut backend = build_test_backend();
+        backend.create_user("Jürgen".to_string());
+        backend.create_user("Jürgens Freund".to_string());
+        backend.create_user("Lisa".to_string());
+        backend.update_user(2, "Lisa".to_string(), true, false, Some("jurgen-ext".to_string()), true);
+        backend.create_item("Club-Mate".to_string(), 150, Some("Softdrinks".to_string()));
+        backend.create_item("Mate Eistee".to_string(), 150, Some("Softdrinks".to_string()));
+        backend.create_item("Kölsch".to_string(), 120, Some("Bier".to_string()));
+
+        backend.purchase(1, 1, 10);
+        backend.purchase(1, 1, 11);
+        backend.purchase(0, 0, 12);
+
+        //popularity breaks ties between prefix hits, external ids are searched too
+        let hits = backend.datastore.users_ranked_search("jurgen");
+        let ids: Vec<u32> = hits.iter().map(|h| h.id).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(hits[0].kind, ::search::MatchKind::Exact);
+        assert_eq!(hits[2].field, ::search::SearchField::ExternalUserId);
+
+        //typo
+        let hits = backend.datastore.users_ranked_search("jürgne");
+        assert_eq!(hits.len(), 3);
+        assert_eq!(hits[0].kind, ::search::MatchKind::Fuzzy);
+
+        let hits = backend.datastore.items_ranked_search("mate");
+        let ids: Vec<u32> = hits.iter().map(|h| h.id).collect();
+        assert_eq!(ids, vec![1, 0]);
+        assert_eq!(hits[1].highlights[0].start, 5);
+
+        let hits = backend.datastore.items_ranked_search("bier");
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].field, ::search::SearchField::Category);
+
+        //plain item search folds diacritics as well
+        assert_eq!(backend.datastore.items_searchhit_ids("kolsch"), vec![2]);
+        assert_eq!(backend.datastore.items_searchhit_ids("Kölsch"), vec![2]);
+    }
+
+    #[test]
+    fn search_index_follows_updates_and_deletes() {
+        let mut backend = build_test_backend();
+        backend.create_user("klaus".to_string());
+        backend.create_user("dieter".to_string());
+        backend.create_item("beer".to_string(), 95, None);
+        backend.create_item("soda".to_string(), 75, None);
+
+        backend.update_item(0, "pils".to_string(), 95, Some("Alcohol".to_string()));
+        assert_eq!(backend.datastore.items_searchhit_ids("beer").len(), 0);
+        assert_eq!(backend.datastore.items_searchhit_ids("pils"), vec![0]);
+        assert_eq!(backend.datastore.items_ranked_search("alcohol")[0].id, 0);
+
+        backend.delete_item(1);
+        assert_eq!(backend.datastore.items_searchhit_ids(""), vec![0]);
+
+        backend.update_user(0, "klaus-peter".to_string(), true, false, None, true);
+        backend.delete_user(1);
+        assert_eq!(backend.datastore.users_searchhit_ids("peter"), vec![0]);
+        assert_eq!(backend.datastore.users_searchhit_ids(""), vec![0]);
+
+        //a full rebuild yields the same index
+        let users_before = backend.datastore.users_suffix_tree.clone();
+        let items_before = backend.datastore.items_suffix_tree.clone();
+        backend.datastore.users_suffix_tree = Default::default();
+        backend.datastore.items_suffix_tree = Default::default();
+        backend.datastore.rebuild_user_tree();
+        backend.datastore.rebuild_item_tree();
+        assert_eq!(backend.datastore.users_suffix_tree, users_before);
+        assert_eq!(backend.datastore.items_suffix_tree, items_before);
+    }
+
+    #[test]
+    fn search_index_is_rebuilt_after_snapshot_load() {
+        extern crate tempdir;
+        let dir = tempdir::TempDir::new("snapshottestdir").unwrap();
+        let mut backend = ::build_persistent_backend(dir.as_ref());
+        backend.create_user("klaus".to_string());
+        backend.create_item("Kölsch".to_string(), 120, None);
+        assert!(backend.snapshot().is_some());
+
+        backend.datastore.users_suffix_tree = Default::default();
+        backend.datastore.items_suffix_tree = Default::default();
+        assert!(backend.load_snapshot().is_some());
+        assert_eq!(backend.datastore.users_searchhit_ids("kla"), vec![0]);
+        assert_eq!(backend.datastore.items_searchhit_ids("kolsch"), vec![0]);
+    }
+
+    #[test]
+    fn backdated_purchases_are_billed_correctly() {
+        let mut backend = build_test_backend();
+        backend.create_user("klaus".to_string());
+        backend.update_user(0, "klaus".to_string(), true, false, Some("ext".to_string()), true);
+        backend.create_item("beer".to_string(), 100, None);
+        backend.purchase(0, 0, 100);
+        backend.purchase(0, 0, 300);
+        backend.purchase(0, 0, 200);
+        //entered by an admin the day after
+        backend.purchase(0, 0, 50);
+
+        let ids: Vec<u64> = backend.datastore.global_log_filtered(0, 200).iter().map(|p| p.get_unique_id()).collect();
+        assert_eq!(ids, vec![4, 1]);
+        assert_eq!(backend.datastore.get_purchase_timestamp(4), Some(50));
+        assert_eq!(backend.datastore.get_purchase_timestamp(3), Some(200));
+
+        backend.create_bill(0, 200, AllUsers, "".to_string());
+        assert!(backend.apply(&BLEvents::FinalizeBill { timestamp_from: 0, timestamp_to: 200 }));
+        let ids: Vec<u64> = backend.datastore.purchases.iter().map(|p| p.get_unique_id()).collect();
+        assert_eq!(ids, vec![3, 2]);
+        assert!(backend.undo_purchase(2));
+        assert!(backend.datastore.get_purchase(2).is_none());
+        assert!(backend.datastore.get_purchase(3).is_some());
+    }
+
+    #[test]
+    fn derived_state_is_rebuilt_after_snapshot_load() {
+        extern crate tempdir;
+        use invariants::DerivedStructure;
+        use invariants::ALL_DERIVED_STRUCTURES;
+        use left_threaded_avl_tree::AVLTree;
+        let dir = tempdir::TempDir::new("rebuildtestdir").unwrap();
+        let mut backend = ::build_persistent_backend(dir.as_ref());
+        backend.create_user("klaus".to_string());
+        backend.create_user("dieter".to_string());
+        backend.create_item("beer".to_string(), 100, None);
+        backend.purchase(0, 0, 10);
+        backend.purchase(1, 0, 20);
+        backend.purchase(1, 0, 30);
+
+        //a snapshot written by an older, buggy version
+        backend.datastore.drink_scores_per_user.clear();
+        backend.datastore.top_drinks_per_user.clear();
+        backend.datastore.ledgers.values_mut().for_each(|l| l.items.values_mut().for_each(|e| e.count = 0));
+        assert!(backend.snapshot().is_some());
+
+        assert!(backend.load_snapshot().is_some());
+        let summary = backend.rebuild_derived_state();
+        assert_eq!(summary.count_per_structure(), vec![
+            (DerivedStructure::DrinkScores, 2),
+            (DerivedStructure::TopDrinks, 2),
+            (DerivedStructure::CountBalances, 2),
+        ]);
+        assert_eq!(summary.repaired.len(), ALL_DERIVED_STRUCTURES.len());
+        assert_eq!(backend.datastore.top_item_ids(1, 1), vec![0]);
+        assert_eq!(backend.datastore.drink_scores_per_user[&1].get_score(0), Some(2));
+
+        assert!(backend.rebuild_derived_state().is_consistent());
+    }
+
     #[test]
     fn simple_ffa_purchase() {
         let mut backend = build_test_backend();
@@ -994,5 +1416,104 @@ mod tests {
 
     }
 
+    #[test]
+    fn purchases_keep_the_price_they_were_made_at() {
+        let mut backend = build_test_backend();
+        backend.create_user("klaus".to_string());
+        backend.update_user(0, "klaus".to_string(), true, false, Some("klaus".to_string()), true);
+        backend.create_item("beer".to_string(), 100, None);
+
+        backend.purchase(0, 0, 10);
+        //without a date the new price applies after the latest purchase
+        backend.update_item(0, "beer".to_string(), 150, None);
+        backend.purchase(0, 0, 20);
+        backend.update_item_at(0, "beer".to_string(), 200, vec![], None, 30);
+        backend.purchase(0, 0, 40);
+        //purchases entered late get the price of their time
+        backend.purchase(0, 0, 25);
+
+        let item = &backend.datastore.items[&0];
+        assert_eq!(item.cost_cents, 200);
+        assert_eq!(item.price_history.len(), 3);
+        assert_eq!((item.price_at(-5), item.price_at(11), item.price_at(29), item.price_at(30)), (100, 150, 150, 200));
+        let prices: Vec<Option<u32>> = backend.datastore.purchases.iter().map(|p| p.get_unit_price()).collect();
+        assert_eq!(prices, vec![Some(100), Some(150), Some(150), Some(200)]);
+        assert_eq!(backend.datastore.ledgers[&0].total_cost_cents(), 600);
+        assert_eq!(backend.datastore.running_balance(0).total_cents, 600);
+
+        //purchases of older versions are priced with the history
+        let legacy = Purchase::SimplePurchase { unique_id: 9, timestamp_epoch_millis: 15, item_id: 0, consumer_id: 0, unit_price_cents: None, group_id: None };
+        assert_eq!(purchase_unit_price(&backend.datastore, &legacy), 150);
+
+        backend.create_bill(0, 100, AllUsers, "".to_string());
+        assert!(backend.apply(&BLEvents::FinalizeBill { timestamp_from: 0, timestamp_to: 100 }));
+        assert_eq!(backend.datastore.bills[0].total_cents_of(0), 600);
+    }
+
+    #[test]
+    fn guests_pay_the_guest_price_everywhere() {
+        let mut backend = build_test_backend();
+        for (idx, name) in ["klaus", "gast"].iter().enumerate() {
+            backend.create_user(name.to_string());
+            backend.update_user(idx as u32, name.to_string(), true, false, Some(name.to_string()), true);
+        }
+        backend.create_item("beer".to_string(), 100, None);
+        backend.create_item("mate".to_string(), 150, None);
+        assert!(backend.set_user_price_tier(1, PriceTier::Guest));
+        assert!(backend.set_item_tier_price(0, PriceTier::Guest, Some(140)));
+        assert!(!backend.set_item_tier_price(0, PriceTier::Member, Some(90)));
+        assert!(!backend.set_user_price_tier(7, PriceTier::Guest));
+
+        //klaus gives the guest a budget of 200 cents
+        backend.create_free_budget(200, "welcome".to_string(), 1, 0, 1);
+        backend.purchase(0, 0, 10);
+        backend.purchase(1, 0, 11);
+        backend.purchase(1, 0, 12);
+        backend.purchase(1, 1, 13);
+        let prices: Vec<Option<u32>> = backend.datastore.purchases.iter().map(|p| p.get_unit_price()).collect();
+        assert_eq!(prices, vec![Some(100), Some(140), Some(140), Some(150)]);
+        assert_eq!(backend.datastore.item_prices_at(20, PriceTier::Guest).iter().map(|p| p.base_cents).collect::<Vec<_>>(), vec![140, 150]);
+
+        //purchases of older versions use the tier of their user
+        let legacy = Purchase::SimplePurchase { unique_id: 9, timestamp_epoch_millis: 15, item_id: 0, consumer_id: 1, unit_price_cents: None, group_id: None };
+        assert_eq!(purchase_unit_price(&backend.datastore, &legacy), 140);
+
+        let guest = backend.datastore.running_balance(1);
+        assert_eq!(guest.total_cents, 140 + 140 + 150 - 200);
+        backend.create_bill(0, 100, AllUsers, "".to_string());
+        assert!(backend.apply(&BLEvents::FinalizeBill { timestamp_from: 0, timestamp_to: 100 }));
+        let bill = &backend.datastore.bills[0];
+        assert_eq!(bill.total_cents_of(1), guest.total_cents);
+        assert_eq!(bill.total_cents_of(0), 100 + 200);
+        assert_eq!(bill.finalized_data.all_users[&1].price_tier, PriceTier::Guest);
+        assert_eq!(bill.finalized_data.all_items[&0].price_for(PriceTier::Guest, 11), 140);
+
+        //without a tier price guests pay what members pay
+        backend.set_item_tier_price(0, PriceTier::Guest, None);
+        backend.purchase(1, 0, 200);
+        assert_eq!(backend.datastore.purchases.iter().map(|p| p.get_unit_price()).collect::<Vec<_>>(), vec![Some(100)]);
+
+        //updates change the guest price along with the member price, bulk updates change both
+        assert!(backend.update_item_at(1, "mate".to_string(), 160, vec![TierPrice { tier: PriceTier::Guest, cost_cents: 180 }], None, 300));
+        assert!(!backend.update_item_at(1, "mate".to_string(), 160, vec![TierPrice { tier: PriceTier::Member, cost_cents: 1 }], None, 300));
+        backend.purchase(1, 1, 300);
+        assert!(backend.bulk_update_prices(PriceSelection::Items { item_ids: vec![1] }, PriceChange::ByCents { cents: 10 }));
+        backend.purchase(1, 1, 310);
+        assert_eq!(backend.datastore.purchases.iter().map(|p| p.get_unit_price()).collect::<Vec<_>>(), vec![Some(100), Some(180), Some(190)]);
+        assert_eq!(backend.datastore.items[&1].price_for(PriceTier::Guest, 300), 180);
+
+        //a later tier change does not reprice older purchases
+        assert!(backend.set_user_price_tier(1, PriceTier::Member));
+        let legacy = Purchase::SimplePurchase { unique_id: 9, timestamp_epoch_millis: 12, item_id: 0, consumer_id: 1, unit_price_cents: None, group_id: None };
+        assert_eq!(purchase_unit_price(&backend.datastore, &legacy), 140);
+        let later = Purchase::SimplePurchase { unique_id: 10, timestamp_epoch_millis: 320, item_id: 1, consumer_id: 1, unit_price_cents: None, group_id: None };
+        assert_eq!(purchase_unit_price(&backend.datastore, &later), 170);
+
+        //a fixed price is set for members, guests keep their premium
+        assert!(backend.bulk_update_prices(PriceSelection::Items { item_ids: vec![1] }, PriceChange::Fixed { price_cents: 200 }));
+        assert_eq!(backend.datastore.items[&1].cost_cents, 200);
+        assert_eq!(backend.datastore.items[&1].tier_prices, vec![TierPrice { tier: PriceTier::Guest, cost_cents: 190 }]);
+    }
+
 
 }
@@ -9,6 +9,7 @@ use datastore::Itemable;
 use datastore::Userable;
 
 use std::cmp;
+use std::convert::TryFrom;
 use config::StaticConfig;
 
 use left_threaded_avl_tree::AVLTree;
@@ -25,6 +26,34 @@ use datastore::*;
 use suffix_rs::*;
 use datastore::PurchaseFunctions;
 use unidecode::unidecode;
+use search::SearchDocument;
+use ledger::add_to_ledger;
+use ledger::remove_from_ledger;
+use price_adjustments::PriceChange;
+use price_adjustments::PriceSelection;
+use price_adjustments::new_prices;
+use price_adjustments::selected_item_ids;
+use inventory::StockMovementKind;
+use stocktake::take_stock;
+use suppliers::SupplierItem;
+use suppliers::SupplierOrderState;
+use suppliers::restock_suggestions;
+use deposits::DepositKind;
+use prepaid::PrepaidTransactionKind;
+use prepaid::can_afford_purchases;
+use consumption_limits::ConsumptionRule;
+use consumption_limits::LimitOverride;
+use consumption_limits::limit_violation;
+use pricing_rules::PricingWindow;
+use calendar::TimeZone;
+use pricing_rules::effective_price;
+use pricing_rules::is_valid_selection;
+use bundles::BundleComponent;
+use bundles::is_valid_bundle;
+use bundles::purchase_units;
+use bundles::has_deleted_components;
+use bundles::unit_count;
+use bundles::MAX_PURCHASE_UNITS;
 
 
 pub trait Event {
@@ -36,6 +65,10 @@ pub fn default_true() -> bool {
     true
 }
 
+pub fn default_quantity() -> u32 {
+    1
+}
+
 
 pub fn deunicodify(input: &Vec<datastore::User>) -> Vec<datastore::User> {
 
@@ -53,7 +86,9 @@ pub fn deunicodify(input: &Vec<datastore::User>) -> Vec<datastore::User> {
             is_billed: x.is_billed,
             is_sepa: x.is_sepa,
             highlight_in_ui: x.highlight_in_ui,
-            deleted: x.deleted
+            deleted: x.deleted,
+            price_tier: x.price_tier,
+            price_tier_history: x.price_tier_history.clone(),
         }
     }).collect();
     return output;
@@ -75,13 +110,21 @@ pub enum BLEvents {
         item_id: u32,
         itemname: String,
         price_cents: u32,
-        category: Option<String>,},
+        category: Option<String>,
+        //when the new price starts to apply, right after the latest purchase if missing
+        #[serde(default)]
+        timestamp: Option<i64>,
+        //prices of other tiers changed along with the member price, the others keep theirs
+        #[serde(default)]
+        tier_prices: Vec<TierPrice>,},
     DeleteItem { item_id: u32 },
     DeleteUser { user_id: u32 },
     MakeSimplePurchase {
         user_id: u32,
         item_id: u32,
         timestamp: i64,
+        #[serde(default = "default_quantity")]
+        quantity: u32,
     },
     MakeShoppingCartPurchase {
         user_id: u32,
@@ -153,6 +196,76 @@ pub enum BLEvents {
         users: UserGroup,
         users_that_will_not_be_billed: HashSet<u32>,
     },
+    ScheduleItemUpdate {
+        item_id: u32,
+        itemname: String,
+        price_cents: u32,
+        category: Option<String>,
+        effective_timestamp: i64,
+        #[serde(default)]
+        tier_prices: Vec<TierPrice>,
+    },
+    CancelScheduledItemUpdate { schedule_id: u64 },
+    //all or none of the selected items get their new price
+    BulkUpdatePrices {
+        selection: PriceSelection,
+        change: PriceChange,
+        //when the new prices start to apply, right after the latest purchase if missing
+        #[serde(default)]
+        timestamp: Option<i64>,
+    },
+    DeliverStock { item_id: u32, count: u32, timestamp: i64, comment: String },
+    //sets the stock to the counted level
+    CorrectStock { item_id: u32, level: i64, timestamp: i64, comment: String },
+    //breakage and other losses
+    WriteOffStock { item_id: u32, count: u32, timestamp: i64, comment: String },
+    //counted units per item id
+    TakeStock { counted: HashMap<u32, i64>, timestamp: i64, comment: String },
+    //what we pay per unit from timestamp on
+    SetAcquisitionCost { item_id: u32, cost_cents: u32, timestamp: i64 },
+    CreateSupplier { name: String, contact: String },
+    //adds the item to the supplier's range or replaces its conditions
+    SetSupplierItem { supplier_id: u32, item_id: u32, pack_size: u32, pack_cost_cents: Option<u32> },
+    //drafts an order with the restock suggestions at that time
+    DraftSupplierOrder { supplier_id: u32, timestamp: i64, lookback_days: u32, days_of_cover: u32 },
+    //0 packs removes the line
+    SetSupplierOrderLine { order_id: u64, item_id: u32, packs: u32 },
+    //every line becomes a stock delivery
+    ReceiveSupplierOrder { order_id: u64, timestamp: i64 },
+    CancelSupplierOrder { order_id: u64 },
+    SetItemDeposit { item_id: u32, deposit_cents: u32 },
+    //refunds the current deposit of the item per returned bottle
+    ReturnDeposit { user_id: u32, item_id: u32, count: u32, timestamp: i64 },
+    //opens the account or changes its overdraft limit
+    OpenPrepaidAccount { user_id: u32, overdraft_limit_cents: u64 },
+    //method is either Cash or BankTransfer
+    TopUpPrepaid { user_id: u32, cents: u64, method: PrepaidTransactionKind, timestamp: i64, comment: String },
+    //replaces all rules of the user
+    SetConsumptionRules { user_id: u32, rules: Vec<ConsumptionRule> },
+    OverrideConsumptionLimits { user_id: u32, timestamp: i64, valid_until: i64, comment: String },
+    //purchases inside the window record the changed price
+    CreatePricingRule { name: String, selection: PriceSelection, change: PriceChange, window: PricingWindow },
+    UpdatePricingRule { rule_id: u64, name: String, selection: PriceSelection, change: PriceChange, window: PricingWindow },
+    RemovePricingRule { rule_id: u64 },
+    SetTimeZone { time_zone: TimeZone },
+    //from timestamp on, right after the latest purchase if missing
+    SetUserPriceTier {
+        user_id: u32,
+        price_tier: PriceTier,
+        #[serde(default)]
+        timestamp: Option<i64>,
+    },
+    //the member price is the item's cost_cents, None lets the tier pay it again
+    SetItemTierPrice {
+        item_id: u32,
+        price_tier: PriceTier,
+        cost_cents: Option<u32>,
+        #[serde(default)]
+        timestamp: Option<i64>,
+    },
+    //creates an item that is sold as its components
+    CreateBundle { itemname: String, price_cents: u32, category: Option<String>, components: Vec<BundleComponent> },
+    SetBundleComponents { item_id: u32, components: Vec<BundleComponent> },
 }
 
 fn hashset(data: &[u32]) -> HashSet<u32> {
@@ -160,6 +273,169 @@ fn hashset(data: &[u32]) -> HashSet<u32> {
     return r;
 }
 
+//takes an undone or billed purchase back out of the open cost and count balances
+fn remove_from_balances(store: &mut Datastore, purchase: &Purchase) {
+    if !purchase.has_item_id() {
+        return;
+    }
+    let user_id = *purchase.get_user_id();
+    let item_id = *purchase.get_item_id();
+    let cost = purchase_unit_price(store, purchase);
+    remove_from_ledger(store, user_id, item_id, cost);
+}
+
+//one unit of a simple purchase, bundles and quantities are recorded as one call per unit of the same group
+fn record_simple_purchase(store: &mut Datastore, config: &StaticConfig, user_id: u32, item_id: u32, unit_price_cents: u32, timestamp: i64, group_id: u64) {
+    let idx: u64 = store.purchase_count + 1;
+    store.purchase_count = idx;
+
+    // add purchase to vector
+    store.purchases.insert(datastore::Purchase::SimplePurchase {
+        unique_id: idx,
+        timestamp_epoch_millis: timestamp,
+        item_id: item_id,
+        consumer_id: user_id,
+        unit_price_cents: Some(unit_price_cents),
+        group_id: Some(group_id),
+    });
+    store.inventory.record_consumption(item_id, StockMovementKind::Purchase, -1, timestamp, idx);
+    let deposit_cents = store.items.get(&item_id).map(|item| item.deposit_cents).unwrap_or(0);
+    store.deposits.charge(user_id, item_id, deposit_cents, timestamp, idx);
+    let cost = i64::from(unit_price_cents) + i64::from(deposit_cents);
+    store.prepaid.book(user_id, PrepaidTransactionKind::Purchase, -cost, timestamp, Some(idx), String::new());
+    let local_millis = store.time_zone.to_local(timestamp);
+    store.purchase_patterns.record_purchase(user_id, item_id, local_millis);
+    let category = store.items.get(&item_id).and_then(|item| item.category.clone());
+    store.item_rankings.record_purchase(item_id, category.as_deref());
+
+    // increase item score for user
+    if let Some(ref mut drinkscore) = store.drink_scores_per_user.get_mut(&user_id) {
+        drinkscore.increment_by_one(item_id);
+        // if not in top items, potentially extract new set
+        if let Some(topitems) = store.top_drinks_per_user.get_mut(&user_id) {
+            if !(topitems.contains(&item_id)) {
+                *topitems = hashset(
+                    drinkscore
+                        .extract_top(config.top_drinks_per_user)
+                        .as_slice(),
+                );
+            }
+        }
+    }
+
+    // increase user score
+    store.top_user_scores.increment_by_one(user_id);
+    let last_millis = store.last_millis_of_purchase_by_user.entry(user_id).or_insert(timestamp);
+    *last_millis = cmp::max(*last_millis, timestamp);
+
+    // if not in top users, potentially extract new set
+    if !(store.top_users.contains(&user_id)) {
+        store.top_users = hashset(
+            store
+                .top_user_scores
+                .extract_top(config.users_in_top_users)
+                .as_slice(),
+        );
+    } else {
+
+    }
+
+    //increase open balance
+    add_to_ledger(store, user_id, item_id, unit_price_cents);
+}
+
+//shared by UpdateItem and scheduled changes, the price applies from valid_from on
+fn update_item(store: &mut Datastore, item_id: u32, itemname: &str, price_cents: u32, tier_prices: &[TierPrice], category: &Option<String>, valid_from: i64) {
+    let old_category = store.items[&item_id].category.clone();
+    {
+        let e = store.items.get_mut(&item_id).unwrap();
+        e.name = itemname.to_string();
+        e.set_price(price_cents, valid_from);
+        for tier_price in tier_prices {
+            e.set_tier_price(tier_price.tier, Some(tier_price.cost_cents), valid_from);
+        }
+        e.category = category.clone();
+    }
+    if !store.items[&item_id].deleted {
+        store.item_rankings.change_category(item_id, old_category.as_deref(), category.as_deref());
+    }
+    for cat in category.iter() {
+        store.categories.insert(cat.to_string());
+    }
+
+    if !store.items[&item_id].deleted {
+        store.items_suffix_tree.upsert(SearchDocument::for_item(&store.items[&item_id]));
+    }
+}
+
+//the member price is the item's cost_cents, every other tier at most once
+fn is_valid_tier_prices(tier_prices: &[TierPrice]) -> bool {
+    tier_prices.iter().enumerate().all(|(idx, p)| p.tier != PriceTier::Member && tier_prices[..idx].iter().all(|q| q.tier != p.tier))
+}
+
+//the unique ids of all open units recorded by the same purchase, units of older versions are undone alone
+fn purchase_group(store: &Datastore, unique_id: u64) -> Vec<u64> {
+    let purchase = store.purchases.get(unique_id).unwrap();
+    match *purchase {
+        Purchase::SimplePurchase { group_id: Some(group_id), timestamp_epoch_millis, .. } => store.purchases
+            .range(timestamp_epoch_millis, timestamp_epoch_millis + 1)
+            .iter()
+            .filter(|p| matches!(**p, Purchase::SimplePurchase { group_id: Some(id), .. } if id == group_id))
+            .map(|p| p.get_unique_id())
+            .collect(),
+        _ => vec![unique_id],
+    }
+}
+
+fn undo_purchase_unit(store: &mut Datastore, config: &StaticConfig, unique_id: u64) {
+    //remove purchase from list
+    let index = store.purchases.position(unique_id).unwrap();
+    let element = store.purchases.remove_at(index);
+
+    //remove cost and count lists:
+    remove_from_balances(store, &element);
+    store.deposits.remove_purchase(unique_id);
+    let deducted = store.prepaid.deducted_for(*element.get_user_id(), &[unique_id].iter().cloned().collect());
+    store.prepaid.book(*element.get_user_id(), PrepaidTransactionKind::Undo, deducted, *element.get_timestamp(), Some(unique_id), String::new());
+    if element.has_item_id() {
+        store.inventory.record_consumption(*element.get_item_id(), StockMovementKind::Undo, 1, *element.get_timestamp(), unique_id);
+    }
+
+    //simple purchases also count towards user and item scores
+    if let Purchase::SimplePurchase { item_id, consumer_id, timestamp_epoch_millis, .. } = element {
+        let local_millis = store.time_zone.to_local(timestamp_epoch_millis);
+        store.purchase_patterns.revert_purchase(consumer_id, item_id, local_millis);
+        if let Some(drinkscore) = store.drink_scores_per_user.get_mut(&consumer_id) {
+            drinkscore.decrement_by_one(item_id);
+            if let Some(topitems) = store.top_drinks_per_user.get_mut(&consumer_id) {
+                if topitems.contains(&item_id) {
+                    *topitems = hashset(drinkscore.extract_top(config.top_drinks_per_user).as_slice());
+                }
+            }
+        }
+        let category = store.items.get(&item_id).and_then(|item| item.category.clone());
+        store.item_rankings.revert_purchase(item_id, category.as_deref());
+        store.top_user_scores.decrement_by_one(consumer_id);
+        if store.top_users.contains(&consumer_id) {
+            store.top_users = hashset(store.top_user_scores.extract_top(config.users_in_top_users).as_slice());
+        }
+    }
+}
+
+//scheduled changes take effect lazily, right before the first purchase at or after their time
+fn apply_due_item_changes(store: &mut Datastore, timestamp: i64) {
+    for change in store.item_change_schedule.take_due(timestamp) {
+        update_item(store, change.item_id, &change.itemname, change.price_cents, &change.tier_prices, &change.category, change.effective_timestamp);
+    }
+}
+
+//newest timestamp of any purchase, billed or not
+fn latest_purchase_millis(store: &Datastore) -> Option<i64> {
+    let open = store.purchases.iter().map(|p| *p.get_timestamp()).max();
+    let billed = store.last_millis_of_purchase_by_user.values().cloned().max();
+    cmp::max(open, billed)
+}
+
 impl Event for BLEvents {
     fn can_be_applied(&self, store: &Datastore) -> bool {
         return match self {
@@ -178,10 +454,7 @@ impl Event for BLEvents {
                 !store.purchases.is_empty() &&
                     !store.bills.iter().any(|b| b.timestamp_to == *timestamp_to && b.timestamp_from == *timestamp_from)
             },
-            &BLEvents::UpdateItem { ref item_id,
-                ref itemname,
-                ref price_cents,
-                ref category } => store.has_item(*item_id),
+            &BLEvents::UpdateItem { ref item_id, ref tier_prices, .. } => store.has_item(*item_id) && is_valid_tier_prices(tier_prices),
             &BLEvents::UpdateUser { ref user_id, ref username, ref is_billed, ref is_highlighted, ref external_user_id, ref is_sepa } => store.has_user(*user_id),
             &BLEvents::DeleteItem { item_id } => store.has_item(item_id),
             &BLEvents::DeleteUser { user_id } => store.has_user(user_id),
@@ -189,13 +462,20 @@ impl Event for BLEvents {
                 user_id,
                 item_id,
                 timestamp,
-            } => store.has_item(item_id) && store.has_user(user_id),
-            &BLEvents::MakeSpecialPurchase { ref user_id, ref special_name, ref timestamp } => store.has_user(*user_id),
+                quantity,
+            } => {
+                let purchases = [(item_id, quantity)];
+                quantity > 0 && store.has_item(item_id) && store.has_user(user_id) && unit_count(store, item_id, quantity) <= MAX_PURCHASE_UNITS
+                    && !has_deleted_components(store, item_id)
+                    && can_afford_purchases(store, user_id, &purchases, false, timestamp)
+                    && limit_violation(store, user_id, &purchases, timestamp).is_none()
+            },
+            &BLEvents::MakeSpecialPurchase { ref user_id, ref special_name, ref timestamp } => store.has_user(*user_id) && can_afford_purchases(store, *user_id, &[], true, *timestamp),
             &BLEvents::MakeShoppingCartPurchase { ref user_id, ref specials, ref item_ids, ref timestamp } => {
 
             let mut v : Vec<BLEvents> = Vec::new();
             for x in item_ids {
-            v.push(BLEvents::MakeSimplePurchase {user_id: *user_id, item_id: *x, timestamp: *timestamp});
+            v.push(BLEvents::MakeSimplePurchase {user_id: *user_id, item_id: *x, timestamp: *timestamp, quantity: 1});
             }
             for x in specials {
             v.push(BLEvents::MakeSpecialPurchase {user_id: *user_id, special_name: x.to_string(), timestamp: *timestamp});
@@ -205,14 +485,17 @@ impl Event for BLEvents {
             for x in v {
                 result = result & x.can_be_applied(store);
             }
-            return result;
+            let purchases: Vec<(u32, u32)> = item_ids.iter().map(|item_id| (*item_id, 1)).collect();
+            return result && can_afford_purchases(store, *user_id, &purchases, !specials.is_empty(), *timestamp)
+                && limit_violation(store, *user_id, &purchases, *timestamp).is_none();
             }
             &BLEvents::MakeFreeForAllPurchase { ffa_id, item_id, timestamp } => {
                 let mut b = false;
                 let x : Option<&Freeby> = store.open_ffa.iter().find(|x|x.get_id() == ffa_id);
                 let item: &Item = store.items.get(&item_id).unwrap();
                 match x {
-                    Some(ffa) => {return ffa.allows(item);},
+                    //bundles are not given out for free
+                    Some(ffa) => {return item.bundle.is_empty() && ffa.allows(item);},
                     None => {return false;},
                 }
             },
@@ -253,6 +536,49 @@ impl Event for BLEvents {
                 }
             },
             &BLEvents::SetPriceForSpecial { unique_id, price } => store.get_purchase(unique_id).is_some(),
+            &BLEvents::ScheduleItemUpdate { item_id, ref tier_prices, .. } => store.items.get(&item_id).map(|i| !i.deleted).unwrap_or(false) && is_valid_tier_prices(tier_prices),
+            &BLEvents::CancelScheduledItemUpdate { schedule_id } => store.item_change_schedule.contains(schedule_id),
+            BLEvents::BulkUpdatePrices { selection, change, .. } => selected_item_ids(store, selection).is_some() && change.is_valid(),
+            &BLEvents::DeliverStock { item_id, .. } => store.has_item(item_id),
+            &BLEvents::CorrectStock { item_id, .. } => store.has_item(item_id),
+            &BLEvents::WriteOffStock { item_id, .. } => store.has_item(item_id),
+            &BLEvents::SetAcquisitionCost { item_id, .. } => store.has_item(item_id),
+            &BLEvents::CreateSupplier { .. } => true,
+            &BLEvents::SetSupplierItem { supplier_id, item_id, pack_size, .. } => {
+                pack_size > 0 && store.has_item(item_id) && store.purchasing.suppliers.contains_key(&supplier_id)
+            },
+            &BLEvents::DraftSupplierOrder { supplier_id, .. } => store.purchasing.suppliers.contains_key(&supplier_id),
+            //the units of a line have to fit into a delivery
+            &BLEvents::SetSupplierOrderLine { order_id, item_id, packs } => {
+                store.purchasing.is_draft(order_id) && store.purchasing.order(order_id)
+                    .and_then(|o| store.purchasing.suppliers.get(&o.supplier_id))
+                    .and_then(|s| s.item(item_id))
+                    .map(|i| packs.checked_mul(i.pack_size).is_some())
+                    .unwrap_or(false)
+            },
+            &BLEvents::ReceiveSupplierOrder { order_id, .. } => store.purchasing.is_draft(order_id),
+            &BLEvents::CancelSupplierOrder { order_id } => store.purchasing.is_draft(order_id),
+            &BLEvents::SetItemDeposit { item_id, .. } => store.has_item(item_id),
+            &BLEvents::ReturnDeposit { user_id, item_id, count, .. } => {
+                count > 0 && store.has_user(user_id) && store.items.get(&item_id).map(|i| i.deposit_cents > 0).unwrap_or(false)
+            },
+            &BLEvents::OpenPrepaidAccount { user_id, overdraft_limit_cents } => store.has_user(user_id) && i64::try_from(overdraft_limit_cents).is_ok(),
+            &BLEvents::SetConsumptionRules { user_id, .. } => store.has_user(user_id),
+            BLEvents::CreatePricingRule { selection, change, window, .. } => is_valid_selection(store, selection) && change.is_valid() && window.is_valid(),
+            BLEvents::UpdatePricingRule { rule_id, selection, change, window, .. } => {
+                store.pricing_rules.contains(*rule_id) && is_valid_selection(store, selection) && change.is_valid() && window.is_valid()
+            },
+            &BLEvents::RemovePricingRule { rule_id } => store.pricing_rules.contains(rule_id),
+            &BLEvents::SetTimeZone { time_zone } => time_zone.is_valid(),
+            &BLEvents::SetUserPriceTier { user_id, .. } => store.has_user(user_id),
+            &BLEvents::SetItemTierPrice { item_id, price_tier, .. } => store.has_item(item_id) && price_tier != PriceTier::Member,
+            BLEvents::CreateBundle { components, .. } => is_valid_bundle(store, store.item_id_counter, components),
+            BLEvents::SetBundleComponents { item_id, components } => store.has_item(*item_id) && is_valid_bundle(store, *item_id, components),
+            &BLEvents::OverrideConsumptionLimits { user_id, timestamp, valid_until, .. } => store.has_user(user_id) && timestamp < valid_until,
+            &BLEvents::TopUpPrepaid { user_id, cents, method, .. } => {
+                cents > 0 && store.prepaid.can_top_up(user_id, cents) && matches!(method, PrepaidTransactionKind::Cash | PrepaidTransactionKind::BankTransfer)
+            },
+            BLEvents::TakeStock { counted, .. } => !counted.is_empty() && counted.keys().all(|item_id| store.has_item(*item_id)),
         };
     }
 
@@ -275,6 +601,12 @@ impl Event for BLEvents {
                         cost_cents: *price_cents,
                         category: category.clone(),
                         deleted: false,
+                        price_history: vec![PricePoint { valid_from_millis: i64::MIN, cost_cents: *price_cents }],
+                        acquisition_costs: Vec::new(),
+                        deposit_cents: 0,
+                        tier_prices: Vec::new(),
+                        tier_price_history: Vec::new(),
+                        bundle: Vec::new(),
                     },
                 );
                 store.item_id_counter = id + 1u32;
@@ -282,19 +614,9 @@ impl Event for BLEvents {
                 for (_, value) in &mut store.drink_scores_per_user {
                     (*value).insert(id);
                 }
+                store.item_rankings.insert(id, category.as_deref());
 
-
-
-                {
-                    let mut items_vec: Vec<datastore::Item> = vec![];
-
-                    for (_, v) in &store.items {
-                        let copy : datastore::Item = (v.clone());
-                        items_vec.push(copy);
-                    }
-
-                    store.items_suffix_tree = MockKDTree::build(&items_vec, false);
-                }
+                store.items_suffix_tree.upsert(SearchDocument::for_item(&store.items[&id]));
 
 
                 true
@@ -311,14 +633,18 @@ impl Event for BLEvents {
                         is_sepa: true,
                         highlight_in_ui: false,
                         deleted: false,
+                        price_tier: PriceTier::Member,
+                        price_tier_history: Vec::new(),
                     },
                 );
                 store.user_id_counter = id + 1u32;
 
                 //add per user scores and top items:
                 let mut score_tree = ScoredIdTreeMock::default();
-                for (_key, _) in &store.items {
-                    let _ = score_tree.insert(*_key);
+                for (_key, item) in &store.items {
+                    if !item.deleted {
+                        let _ = score_tree.insert(*_key);
+                    }
                 }
 
                 store.drink_scores_per_user.insert(id, score_tree);
@@ -333,14 +659,7 @@ impl Event for BLEvents {
                         .as_slice(),
                 );
 
-                {
-                    let mut users_vec: Vec<datastore::User> = vec![];
-
-                    for (_, v) in &store.users {
-                        users_vec.push(v.clone());
-                    }
-                    store.users_suffix_tree = MockKDTree::build(&deunicodify(&users_vec), false);
-                }
+                store.users_suffix_tree.upsert(SearchDocument::for_user(&store.users[&id]));
 
 
                 true
@@ -348,12 +667,11 @@ impl Event for BLEvents {
             &BLEvents::UpdateItem { ref item_id,
                 ref itemname,
                 ref price_cents,
-                ref category } => {
-                let mut e = store.items.get_mut(item_id).unwrap();
-                e.name = itemname.to_string();
-                e.cost_cents = *price_cents;
-                e.category = category.clone();
-
+                ref category,
+                ref timestamp,
+                ref tier_prices } => {
+                let valid_from = timestamp.unwrap_or_else(|| latest_purchase_millis(store).map(|t| t + 1).unwrap_or(i64::MIN));
+                update_item(store, *item_id, itemname, *price_cents, tier_prices, category, valid_from);
                 true
             },
             &BLEvents::UpdateUser { ref user_id, ref username, ref is_billed, ref is_highlighted, ref external_user_id, ref is_sepa } => {
@@ -374,13 +692,8 @@ impl Event for BLEvents {
                 }
 
 
-                {
-                    let mut users_vec: Vec<datastore::User> = vec![];
-
-                    for (_, v) in &store.users {
-                        users_vec.push(v.clone());
-                    }
-                    store.users_suffix_tree = MockKDTree::build(&deunicodify(&users_vec), false);
+                if !store.users[user_id].deleted {
+                    store.users_suffix_tree.upsert(SearchDocument::for_user(&store.users[user_id]));
                 }
 
                 true
@@ -402,12 +715,14 @@ impl Event for BLEvents {
                         all_users: HashMap::new(),
                         all_items: HashMap::new(),
                         user_consumption: HashMap::new(),
+                        prepaid_settlements: HashMap::new(),
                     },
                 });
                 true
             }
             &BLEvents::DeleteItem { item_id } => {
                 let _ = store.items.get_mut(&item_id).map(|it|{it.deleted = true});
+                store.item_change_schedule.remove_item(item_id);
                 let v = store.items.get(&item_id);
                 match v {
                     None => (),
@@ -416,11 +731,13 @@ impl Event for BLEvents {
                         match item.category {
                             None => (),
                             Some(ref category) => {
-                                if !store.categories.iter().any(|x| x.eq(category)) {
+                                if !store.items.values().any(|x| !x.deleted && x.category.as_ref() == Some(category)) {
                                     let _ = store.categories.remove(&category.clone());
                                 }
                             }
                         }
+                        store.item_rankings.remove(item_id, item.category.as_deref());
+
                         //remove from personal drink scores and possibly reextract top drinks
                         for (_key, mut value) in &mut store.drink_scores_per_user {
                             value.remove(item_id);
@@ -441,17 +758,7 @@ impl Event for BLEvents {
                     }
                 }
 
-                {
-                    let mut items_vec: Vec<datastore::Item> = vec![];
-
-                    for (_, v) in &store.items {
-                        if !v.deleted {
-                            items_vec.push(v.clone());
-                        }
-                    }
-
-                    store.items_suffix_tree = MockKDTree::build(&items_vec, false);
-                }
+                store.items_suffix_tree.remove(item_id);
                 true
             }
             &BLEvents::DeleteUser { user_id } => {
@@ -481,17 +788,7 @@ impl Event for BLEvents {
 
 
 
-                {
-                    let mut users_vec: Vec<datastore::User> = vec![];
-
-                    for (_, v) in &store.users {
-                        if !v.deleted {
-                            users_vec.push(v.clone());
-                        }
-                    }
-
-                    store.users_suffix_tree = MockKDTree::build(&deunicodify(&users_vec), false);
-                }
+                store.users_suffix_tree.remove(user_id);
 
                 //remove from top users and renew topusers if that is the case
                 if store.top_users.remove(&user_id) {
@@ -512,93 +809,18 @@ impl Event for BLEvents {
                 user_id,
                 item_id,
                 timestamp,
+                quantity,
             } => {
-                let idx: u64 = store.purchase_count + 1;
-                store.purchase_count = idx;
-
-                // add purchase to vector
-                store.purchases.push(datastore::Purchase::SimplePurchase {
-                    unique_id: idx,
-                    timestamp_epoch_millis: timestamp,
-                    item_id: item_id,
-                    consumer_id: user_id,
-                });
-
+                apply_due_item_changes(store, timestamp);
                 let was_in_before = store.top_users.contains(&user_id);
-
-                // increase item score for user
-                if let Some(ref mut drinkscore) = store.drink_scores_per_user.get_mut(&user_id) {
-                    drinkscore.increment_by_one(item_id);
-                    // if not in top items, potentially extract new set
-                    if let Some(topitems) = store.top_drinks_per_user.get_mut(&user_id) {
-                        if !(topitems.contains(&item_id)) {
-                            *topitems = hashset(
-                                drinkscore
-                                    .extract_top(config.top_drinks_per_user)
-                                    .as_slice(),
-                            );
-                        }
+                let units = purchase_units(store, item_id, user_id, timestamp);
+                let group_id = store.purchase_count + 1;
+                for _ in 0..quantity {
+                    for &(unit_item_id, unit_price_cents) in &units {
+                        record_simple_purchase(store, config, user_id, unit_item_id, unit_price_cents, timestamp, group_id);
                     }
                 }
-
-                // increase user score
-                store.top_user_scores.increment_by_one(user_id);
-                store.last_millis_of_purchase_by_user.insert(user_id, timestamp);
-
-                // if not in top users, potentially extract new set
-                if !(store.top_users.contains(&user_id)) {
-                    println!("not in top users for userid = {}", user_id);
-                    store.top_users = hashset(
-                        store
-                            .top_user_scores
-                            .extract_top(config.users_in_top_users)
-                            .as_slice(),
-                    );
-                } else {
-
-                }
-
-                //increase cost map value
-                let alt_hashmap_1 = HashMap::new();
-                let username = store.users.get(&user_id).unwrap().username.to_string();
-                let itemname = store.items.get(&item_id).unwrap().name.to_string();
-                let user_key = (user_id, store.users.get(&user_id).unwrap().username.to_string());
-                let item_key = (item_id, store.items.get(&item_id).unwrap().name.to_string());
-                let mut old_cost_map = store
-                    .balance_cost_per_user
-                    .remove(&user_key)
-                    .unwrap_or(alt_hashmap_1);
-                let old_cost_value = *old_cost_map.get(&item_key).unwrap_or(&0);
-                old_cost_map.insert(
-                    item_key,
-                    old_cost_value
-                        + store
-                        .items
-                        .get(&item_id)
-                        .map(|item| item.cost_cents)
-                        .unwrap_or(0),
-                );
-                store.balance_cost_per_user.insert(user_key, old_cost_map);
-
-                //increase count map value
-                let alt_hashmap_2 = HashMap::new();
-                let user_key2 : (u32, String) = (user_id, username.to_string());
-                let user_key3 : (u32, String) = (user_id, username.to_string());
-                let item_key2 : (u32, String) = (item_id, itemname.to_string());
-                let item_key3 : (u32, String) = (item_id, itemname.to_string());
-                let mut old_count_map = store
-                    .balance_count_per_user
-                    .remove(&user_key2)
-                    .unwrap_or(alt_hashmap_2);
-                let old_count_value = *old_count_map.get(&item_key2).unwrap_or(&0);
-                old_count_map.insert(item_key3, old_count_value + 1);
-                store.balance_count_per_user.insert(user_key3, old_count_map);
-
-
                 let is_in_now = store.top_users.contains(&user_id);
-
-
-
                 ((!was_in_before) & (is_in_now))
             }
             &BLEvents::MakeSpecialPurchase { ref user_id, ref special_name, ref timestamp } => {
@@ -607,7 +829,7 @@ impl Event for BLEvents {
                 store.purchase_count = idx;
 
 
-                store.purchases.push(datastore::Purchase::SpecialPurchase {
+                store.purchases.insert(datastore::Purchase::SpecialPurchase {
                     unique_id: idx,
                     timestamp_epoch_millis: *timestamp,
                     special_name: special_name.to_string(),
@@ -620,12 +842,13 @@ impl Event for BLEvents {
             &BLEvents::MakeShoppingCartPurchase { ref user_id, ref specials, ref item_ids, ref timestamp } => {
                 let mut v : Vec<BLEvents> = Vec::new();
                 for x in item_ids {
-                    v.push(BLEvents::MakeSimplePurchase {user_id: *user_id, item_id: *x, timestamp: *timestamp});
+                    v.push(BLEvents::MakeSimplePurchase {user_id: *user_id, item_id: *x, timestamp: *timestamp, quantity: 1});
                 }
                 for x in specials {
                     v.push(BLEvents::MakeSpecialPurchase {user_id: *user_id, special_name: x.to_string(), timestamp: *timestamp});
                 }
-                store.last_millis_of_purchase_by_user.insert(*user_id, *timestamp);
+                let last_millis = *store.last_millis_of_purchase_by_user.get(user_id).unwrap_or(timestamp);
+                store.last_millis_of_purchase_by_user.insert(*user_id, cmp::max(last_millis, *timestamp));
 
                 let mut result = true;
                 for x in v {
@@ -635,6 +858,7 @@ impl Event for BLEvents {
             },
 
             &BLEvents::MakeFreeForAllPurchase { ffa_id, item_id, timestamp } => {
+                apply_due_item_changes(store, timestamp);
 
                 //get new id
                 let idx: u64 = store.purchase_count + 1;
@@ -645,16 +869,23 @@ impl Event for BLEvents {
                 let mut freeby : Freeby = store.open_ffa.remove(index);
 
                 let user_id: u32 = freeby.get_donor();
+                let unit_price_cents = effective_price(store, item_id, user_id, timestamp);
 
                 {
                 //add to purchase vector
-                store.purchases.push(datastore::Purchase::FFAPurchase {
+                store.purchases.insert(datastore::Purchase::FFAPurchase {
                     unique_id: idx,
                     timestamp_epoch_millis: timestamp,
                     item_id: item_id,
                     freeby_id: freeby.get_id(),
                     donor: freeby.get_donor(),
+                    unit_price_cents: Some(unit_price_cents),
                 });
+                store.inventory.record_consumption(item_id, StockMovementKind::FreeForAll, -1, timestamp, idx);
+                let deposit_cents = store.items.get(&item_id).map(|item| item.deposit_cents).unwrap_or(0);
+                store.deposits.charge(user_id, item_id, deposit_cents, timestamp, idx);
+                let cost = i64::from(unit_price_cents) + i64::from(deposit_cents);
+                store.prepaid.book(user_id, PrepaidTransactionKind::Purchase, -cost, timestamp, Some(idx), String::new());
             }
 
                 {
@@ -676,43 +907,7 @@ impl Event for BLEvents {
 
                 //add to cost / count map of donor
 
-                {
-                    //increase cost map value
-                    let alt_hashmap_1 = HashMap::new();
-                    let username = store.users.get(&user_id).unwrap().username.to_string();
-                    let itemname = store.items.get(&item_id).unwrap().name.to_string();
-                    let user_key = (user_id, store.users.get(&user_id).unwrap().username.to_string());
-                    let item_key = (item_id, store.items.get(&item_id).unwrap().name.to_string());
-                    let mut old_cost_map = store
-                        .balance_cost_per_user
-                        .remove(&user_key)
-                        .unwrap_or(alt_hashmap_1);
-                    let old_cost_value = *old_cost_map.get(&item_key).unwrap_or(&0);
-                    old_cost_map.insert(
-                        item_key,
-                        old_cost_value
-                            + store
-                            .items
-                            .get(&item_id)
-                            .map(|item| item.cost_cents)
-                            .unwrap_or(0),
-                    );
-                    store.balance_cost_per_user.insert(user_key, old_cost_map);
-
-                    //increase count map value
-                    let alt_hashmap_2 = HashMap::new();
-                    let user_key2 : (u32, String) = (user_id, username.to_string());
-                    let user_key3 : (u32, String) = (user_id, username.to_string());
-                    let item_key2 : (u32, String) = (item_id, itemname.to_string());
-                    let item_key3 : (u32, String) = (item_id, itemname.to_string());
-                    let mut old_count_map = store
-                        .balance_count_per_user
-                        .remove(&user_key2)
-                        .unwrap_or(alt_hashmap_2);
-                    let old_count_value = *old_count_map.get(&item_key2).unwrap_or(&0);
-                    old_count_map.insert(item_key3, old_count_value + 1);
-                    store.balance_count_per_user.insert(user_key3, old_count_map);
-                }
+                add_to_ledger(store, user_id, item_id, unit_price_cents);
 
                 true
 
@@ -864,47 +1059,12 @@ impl Event for BLEvents {
                 true
             },
             &BLEvents::UndoPurchase { unique_id } => {
-                //remove purchase from list
-                let index = store
-                    .purchases
-                    .iter()
-                    .position(|x| x.has_unique_id(unique_id))
-                    .unwrap();
-                let old_size = store.purchases.len();
-                let element = store.purchases.remove(index);
-
-                let item_key1 : (u32, String) = (*element.get_item_id(), store.items.get(&element.get_item_id()).unwrap().name.to_string());
-                let item_key2 : (u32, String) = (*element.get_item_id(), store.items.get(&element.get_item_id()).unwrap().name.to_string());
-                let user_key : (u32, String) = (*element.get_user_id(), store.users.get(&element.get_user_id()).unwrap().username.to_string());
-
-                //remove cost and count lists:
-                let oldcost = *store
-                    .balance_cost_per_user
-                    .get(&user_key)
-                    .unwrap()
-                    .get(&item_key1)
-                    .unwrap();
-                let oldcount = *store
-                    .balance_count_per_user
-                    .get(&user_key)
-                    .unwrap()
-                    .get(&item_key1)
-                    .unwrap();
-
-
-                store
-                    .balance_cost_per_user
-                    .get_mut(&user_key)
-                    .unwrap()
-                    .insert(item_key1, oldcost - 1);
-                store
-                    .balance_count_per_user
-                    .get_mut(&user_key)
-                    .unwrap()
-                    .insert(item_key2, oldcount - 1);
-
-
-                old_size == index + 1
+                let last_id = store.purchases.last().map(|p| p.get_unique_id());
+                let group = purchase_group(store, unique_id);
+                for member_id in &group {
+                    undo_purchase_unit(store, config, *member_id);
+                }
+                last_id.map(|id| group.contains(&id)).unwrap_or(false)
             },
             //removes purchases from global list and also recomputes counts
             &BLEvents::FinalizeBill {  timestamp_from, timestamp_to } => {
@@ -927,6 +1087,7 @@ impl Event for BLEvents {
                     for purchase in filtered_purs {
 
                         let user : User = store.users[purchase.get_user_id()].clone();
+                        let unit_price : u64 = u64::from(purchase_unit_price(store, &purchase));
 
                         match purchase {
                             Purchase::SpecialPurchase{
@@ -947,12 +1108,7 @@ impl Event for BLEvents {
                                 }
 
                                 if !bill.finalized_data.user_consumption.get_mut(&consumer_id).unwrap().per_day.contains_key(&day_idx) {
-                                    bill.finalized_data.user_consumption.get_mut(&consumer_id).unwrap().per_day.insert(day_idx, BillUserDayInstance {
-                                        personally_consumed: HashMap::new(),
-                                        specials_consumed: Vec::new(),
-                                        ffa_giveouts: HashMap::new(),
-                                        giveouts_to_user_id: HashMap::new(),
-                                    });
+                                    bill.finalized_data.user_consumption.get_mut(&consumer_id).unwrap().per_day.insert(day_idx, BillUserDayInstance::default());
                                 }
                                 bill.finalized_data.user_consumption.get_mut(&consumer_id).unwrap().per_day.get_mut(&day_idx).unwrap().specials_consumed.push(PricedSpecial {
                                     purchase_id: unique_id,
@@ -965,6 +1121,7 @@ impl Event for BLEvents {
                                 timestamp_epoch_millis,
                                 item_id,
                                 consumer_id,
+                                ..
                             } => {
                                 let day_idx : usize = bill_cpy.get_day_index(timestamp_epoch_millis);
                                 let item: Item = store.items.get(&item_id).unwrap().clone();
@@ -1004,20 +1161,18 @@ impl Event for BLEvents {
                                             }
 
                                             if !bill.finalized_data.user_consumption.get_mut(&donor_id).unwrap().per_day.contains_key(&day_idx) {
-                                                bill.finalized_data.user_consumption.get_mut(&donor_id).unwrap().per_day.insert(day_idx, BillUserDayInstance {
-                                                    personally_consumed: HashMap::new(),
-                                                    specials_consumed: Vec::new(),
-                                                    ffa_giveouts: HashMap::new(),
-                                                    giveouts_to_user_id: HashMap::new(),
-                                                });
+                                                bill.finalized_data.user_consumption.get_mut(&donor_id).unwrap().per_day.insert(day_idx, BillUserDayInstance::default());
                                             }
 
-                                            *bill.finalized_data.user_consumption.get_mut(&donor_id).unwrap().per_day.get_mut(&day_idx).unwrap().giveouts_to_user_id.entry(consumer_id).or_insert(PaidFor {
+                                            let paid_for = bill.finalized_data.user_consumption.get_mut(&donor_id).unwrap().per_day.get_mut(&day_idx).unwrap().giveouts_to_user_id.entry(consumer_id).or_insert(PaidFor {
                                                 recipient_id: consumer_id,
                                                 count_giveouts_used: HashMap::new(),
                                                 budget_given: 0,
                                                 budget_gotten: 0,
-                                            }).count_giveouts_used.entry(item_id).or_insert(0) += 1;
+                                                count_giveouts_cents: HashMap::new(),
+                                            });
+                                            *paid_for.count_giveouts_used.entry(item_id).or_insert(0) += 1;
+                                            *paid_for.count_giveouts_cents.entry(item_id).or_insert(0) += unit_price;
 
 
                                         }
@@ -1035,12 +1190,9 @@ impl Event for BLEvents {
                                     },
                                     None => {
                                         //add purchase under consumer
-                                        *bill.finalized_data.user_consumption.get_mut(&consumer_id).unwrap().per_day.entry(day_idx).or_insert(BillUserDayInstance {
-                                            personally_consumed: HashMap::new(),
-                                            specials_consumed: Vec::new(),
-                                            ffa_giveouts: HashMap::new(),
-                                            giveouts_to_user_id: HashMap::new(),
-                                        }).personally_consumed.entry(item_id).or_insert(0u32) += 1;
+                                        let day = bill.finalized_data.user_consumption.get_mut(&consumer_id).unwrap().per_day.entry(day_idx).or_default();
+                                        *day.personally_consumed.entry(item_id).or_insert(0u32) += 1;
+                                        *day.personally_consumed_cents.entry(item_id).or_insert(0) += unit_price;
 
                                         //if a count giveout does not exist, find a budget giveout, and add decrease / increase if possible
                                         match budget_freeby_idx {
@@ -1048,7 +1200,7 @@ impl Event for BLEvents {
                                                 let max_budget : u64 = store.open_freebies.get(&consumer_id).unwrap().get(bidx).unwrap().get_budget_cents_left();
                                                 let donor_id: u32 = store.open_freebies.get(&consumer_id).unwrap().get(bidx).unwrap().get_donor();
                                                 let donor: User = store.users.get(&donor_id).unwrap().clone();
-                                                let item_cost : u64 = item.cost_cents as u64;
+                                                let item_cost : u64 = unit_price;
                                                 let taken_budget : u64 = cmp::min(max_budget, cmp::max(0u64, item_cost));
                                                 let used_up : bool = max_budget <= item_cost;
 
@@ -1068,12 +1220,7 @@ impl Event for BLEvents {
                                                         });
                                                     }
                                                     if !bill.finalized_data.user_consumption.get(&consumer_id).unwrap().per_day.contains_key(&day_idx) {
-                                                        bill.finalized_data.user_consumption.get_mut(&consumer_id).unwrap().per_day.insert(day_idx, BillUserDayInstance {
-                                                            personally_consumed: HashMap::new(),
-                                                            specials_consumed: Vec::new(),
-                                                            ffa_giveouts: HashMap::new(),
-                                                            giveouts_to_user_id: HashMap::new(),
-                                                    });
+                                                        bill.finalized_data.user_consumption.get_mut(&consumer_id).unwrap().per_day.insert(day_idx, BillUserDayInstance::default());
                                                 }
 
                                                 if !bill.finalized_data.user_consumption.get(&consumer_id).unwrap().per_day.get(&day_idx).unwrap().giveouts_to_user_id.contains_key(&donor_id) {
@@ -1082,6 +1229,7 @@ impl Event for BLEvents {
                                                         count_giveouts_used: HashMap::new(),
                                                         budget_given: 0,
                                                         budget_gotten: 0,
+                                                        count_giveouts_cents: HashMap::new(),
                                                     });
                                                 }
 
@@ -1090,6 +1238,7 @@ impl Event for BLEvents {
                                                     count_giveouts_used: HashMap::new(),
                                                     budget_given: 0,
                                                     budget_gotten: 0,
+                                                    count_giveouts_cents: HashMap::new(),
                                                 }).budget_gotten += taken_budget;
 
                                                 }
@@ -1104,12 +1253,7 @@ impl Event for BLEvents {
                                                         });
                                                     }
                                                     if !bill.finalized_data.user_consumption.get(&donor_id).unwrap().per_day.contains_key(&day_idx) {
-                                                        bill.finalized_data.user_consumption.get_mut(&donor_id).unwrap().per_day.insert(day_idx, BillUserDayInstance {
-                                                            personally_consumed: HashMap::new(),
-                                                            specials_consumed: Vec::new(),
-                                                            ffa_giveouts: HashMap::new(),
-                                                            giveouts_to_user_id: HashMap::new(),
-                                                        });
+                                                        bill.finalized_data.user_consumption.get_mut(&donor_id).unwrap().per_day.insert(day_idx, BillUserDayInstance::default());
                                                     }
 
                                                     if !bill.finalized_data.user_consumption.get(&donor_id).unwrap().per_day.get(&day_idx).unwrap().giveouts_to_user_id.contains_key(&consumer_id) {
@@ -1118,6 +1262,7 @@ impl Event for BLEvents {
                                                             count_giveouts_used: HashMap::new(),
                                                             budget_given: 0,
                                                             budget_gotten: 0,
+                                                            count_giveouts_cents: HashMap::new(),
                                                         });
                                                     }
 
@@ -1126,6 +1271,7 @@ impl Event for BLEvents {
                                                     count_giveouts_used: HashMap::new(),
                                                     budget_given: 0,
                                                     budget_gotten: 0,
+                                                    count_giveouts_cents: HashMap::new(),
                                                 }).budget_given += taken_budget;
                                                 }
 
@@ -1147,6 +1293,7 @@ impl Event for BLEvents {
                                 item_id,
                                 freeby_id,
                                 donor,
+                                ..
                             } => {
                                 let day_idx : usize = bill_cpy.get_day_index(timestamp_epoch_millis);
                                 let item: Item = store.items.get(&item_id).unwrap().clone();
@@ -1167,22 +1314,48 @@ impl Event for BLEvents {
 
 
                                 if !bill.finalized_data.user_consumption[&donor].per_day.contains_key(&day_idx) {
-                                    bill.finalized_data.user_consumption.get_mut(&donor).unwrap().per_day.insert(day_idx, BillUserDayInstance {
-                                        personally_consumed: HashMap::new(),
-                                        specials_consumed: Vec::new(),
-                                        ffa_giveouts: HashMap::new(),
-                                        giveouts_to_user_id: HashMap::new(),
-                                    });
+                                    bill.finalized_data.user_consumption.get_mut(&donor).unwrap().per_day.insert(day_idx, BillUserDayInstance::default());
                                 }
 
-                                *bill.finalized_data.user_consumption.get_mut(&donor).unwrap().per_day.get_mut(&day_idx).unwrap().ffa_giveouts.entry(item_id).or_insert(0u32) += 1;
+                                let day = bill.finalized_data.user_consumption.get_mut(&donor).unwrap().per_day.get_mut(&day_idx).unwrap();
+                                *day.ffa_giveouts.entry(item_id).or_insert(0u32) += 1;
+                                *day.ffa_giveouts_cents.entry(item_id).or_insert(0) += unit_price;
                             },
                         }
 
                 }
                 }
 
-                //TODO: balance_cost_per_user also has to be reduced for each purchase
+                //deposits go onto the bill apart from the purchases
+                let billed_ids: HashSet<u64> = purchase_indices.iter().map(|idx| store.purchases[*idx].get_unique_id()).collect();
+                for entry in store.deposits.take_billed(&billed_ids, &bill_cpy) {
+                    let user: User = store.users[&entry.user_id].clone();
+                    let day_idx: usize = bill_cpy.get_day_index(entry.timestamp_epoch_millis);
+                    let bill: &mut Bill = store.bills.get_mut(bill_idx).unwrap();
+                    bill.finalized_data.all_users.entry(entry.user_id).or_insert(user);
+                    let day = bill.finalized_data.user_consumption.entry(entry.user_id)
+                        .or_insert(BillUserInstance { user_id: entry.user_id, per_day: HashMap::new() })
+                        .per_day.entry(day_idx).or_default();
+                    match entry.kind {
+                        DepositKind::Charge => day.deposit_charged_cents += entry.cents,
+                        DepositKind::Refund => day.deposit_refunded_cents += entry.cents,
+                    }
+                }
+
+                //prepaid users pay the bill from their balance, corrected by what their purchases already took
+                let billed_user_ids: Vec<u32> = store.bills[bill_idx].finalized_data.user_consumption.keys().cloned().filter(|id| store.prepaid.is_prepaid(*id)).collect();
+                for user_id in billed_user_ids {
+                    let total = store.bills[bill_idx].total_cents_of(user_id);
+                    let deducted = store.prepaid.deducted_for(user_id, &billed_ids);
+                    store.prepaid.book(user_id, PrepaidTransactionKind::BillSettlement, deducted - total, timestamp_to, None, bill_cpy.comment.to_string());
+                    store.bills[bill_idx].finalized_data.prepaid_settlements.insert(user_id, total);
+                }
+
+                //billed purchases are no longer part of the open balances
+                for idx in &purchase_indices {
+                    let purchase = store.purchases[*idx].clone();
+                    remove_from_balances(store, &purchase);
+                }
 
                 //remove purchases from purchases vec
                 {
@@ -1205,6 +1378,10 @@ impl Event for BLEvents {
                 }
             },
             &BLEvents::SetPriceForSpecial { unique_id, price } => {
+                if let Some(Purchase::SpecialPurchase { consumer_id, timestamp_epoch_millis, specialcost, .. }) = store.get_purchase(unique_id) {
+                    let change = i64::from(specialcost.unwrap_or(0)) - i64::from(price);
+                    store.prepaid.book(consumer_id, PrepaidTransactionKind::Purchase, change, timestamp_epoch_millis, Some(unique_id), String::new());
+                }
                 let x = store.get_purchase_mut(unique_id).unwrap();
 
                 match x {
@@ -1232,6 +1409,146 @@ impl Event for BLEvents {
                     None => {return false;},
                 }
             },
+            &BLEvents::ScheduleItemUpdate { item_id, ref itemname, price_cents, ref category, effective_timestamp, ref tier_prices } => {
+                store.item_change_schedule.schedule(item_id, itemname.to_string(), price_cents, tier_prices.clone(), category.clone(), effective_timestamp);
+                true
+            },
+            &BLEvents::CancelScheduledItemUpdate { schedule_id } => store.item_change_schedule.cancel(schedule_id).is_some(),
+            &BLEvents::BulkUpdatePrices { ref selection, ref change, timestamp } => {
+                let valid_from = timestamp.unwrap_or_else(|| latest_purchase_millis(store).map(|t| t + 1).unwrap_or(i64::MIN));
+                for (item_id, price_cents) in new_prices(store, selection, change) {
+                    let item = store.items[&item_id].clone();
+                    //tiers with their own price get the same relative change, a fixed price is the member price only
+                    let tier_prices: Vec<TierPrice> = match *change {
+                        PriceChange::Fixed { .. } => vec![],
+                        _ => item.tier_prices.iter().map(|p| TierPrice { tier: p.tier, cost_cents: change.apply_to(p.cost_cents) }).collect(),
+                    };
+                    update_item(store, item_id, &item.name, price_cents, &tier_prices, &item.category, valid_from);
+                }
+                true
+            },
+            &BLEvents::DeliverStock { item_id, count, timestamp, ref comment } => {
+                store.inventory.deliver(item_id, count, timestamp, comment.to_string());
+                true
+            },
+            &BLEvents::CorrectStock { item_id, level, timestamp, ref comment } => {
+                store.inventory.correct(item_id, level, timestamp, comment.to_string());
+                true
+            },
+            &BLEvents::WriteOffStock { item_id, count, timestamp, ref comment } => {
+                store.inventory.write_off(item_id, count, timestamp, comment.to_string());
+                true
+            },
+            &BLEvents::TakeStock { ref counted, timestamp, ref comment } => {
+                let report = take_stock(store, counted, timestamp, comment);
+                store.stocktakes.push(report);
+                true
+            },
+            &BLEvents::SetAcquisitionCost { item_id, cost_cents, timestamp } => {
+                store.items.get_mut(&item_id).unwrap().set_acquisition_cost(cost_cents, timestamp);
+                true
+            },
+            BLEvents::CreateSupplier { name, contact } => {
+                store.purchasing.create_supplier(name.to_string(), contact.to_string());
+                true
+            },
+            &BLEvents::SetSupplierItem { supplier_id, item_id, pack_size, pack_cost_cents } => {
+                store.purchasing.suppliers.get_mut(&supplier_id).unwrap().set_item(SupplierItem { item_id, pack_size, pack_cost_cents });
+                true
+            },
+            &BLEvents::DraftSupplierOrder { supplier_id, timestamp, lookback_days, days_of_cover } => {
+                let lines = restock_suggestions(store, supplier_id, timestamp, lookback_days, days_of_cover);
+                store.purchasing.add_order(supplier_id, timestamp, lines);
+                true
+            },
+            &BLEvents::SetSupplierOrderLine { order_id, item_id, packs } => {
+                store.purchasing.set_order_line(order_id, item_id, packs);
+                true
+            },
+            &BLEvents::ReceiveSupplierOrder { order_id, timestamp } => {
+                let order = store.purchasing.order_mut(order_id).unwrap();
+                order.state = SupplierOrderState::Received;
+                order.received_timestamp = Some(timestamp);
+                let lines = order.lines.clone();
+                for line in lines {
+                    store.inventory.deliver(line.item_id, line.packs.saturating_mul(line.pack_size), timestamp, format!("order {}", order_id));
+                }
+                true
+            },
+            &BLEvents::CancelSupplierOrder { order_id } => {
+                store.purchasing.order_mut(order_id).unwrap().state = SupplierOrderState::Cancelled;
+                true
+            },
+            &BLEvents::SetItemDeposit { item_id, deposit_cents } => {
+                store.items.get_mut(&item_id).unwrap().deposit_cents = deposit_cents;
+                true
+            },
+            &BLEvents::ReturnDeposit { user_id, item_id, count, timestamp } => {
+                let deposit_cents = store.items[&item_id].deposit_cents;
+                store.deposits.refund(user_id, item_id, count, deposit_cents, timestamp);
+                true
+            },
+            &BLEvents::OpenPrepaidAccount { user_id, overdraft_limit_cents } => {
+                store.prepaid.open(user_id, overdraft_limit_cents);
+                true
+            },
+            BLEvents::TopUpPrepaid { user_id, cents, method, timestamp, comment } => {
+                store.prepaid.book(*user_id, *method, i64::try_from(*cents).unwrap(), *timestamp, None, comment.to_string());
+                true
+            },
+            BLEvents::SetConsumptionRules { user_id, rules } => {
+                store.consumption_limits.set_rules(*user_id, rules.clone());
+                true
+            },
+            BLEvents::CreatePricingRule { name, selection, change, window } => {
+                store.pricing_rules.create(name.to_string(), selection.clone(), change.clone(), window.clone());
+                true
+            },
+            BLEvents::UpdatePricingRule { rule_id, name, selection, change, window } => {
+                let rule = store.pricing_rules.get_mut(*rule_id).unwrap();
+                rule.name = name.to_string();
+                rule.selection = selection.clone();
+                rule.change = change.clone();
+                rule.window = window.clone();
+                true
+            },
+            &BLEvents::SetTimeZone { time_zone } => {
+                store.time_zone = time_zone;
+                true
+            },
+            &BLEvents::RemovePricingRule { rule_id } => {
+                store.pricing_rules.remove(rule_id);
+                true
+            },
+            &BLEvents::SetUserPriceTier { user_id, price_tier, timestamp } => {
+                let valid_from = timestamp.unwrap_or_else(|| latest_purchase_millis(store).map(|t| t + 1).unwrap_or(i64::MIN));
+                store.users.get_mut(&user_id).unwrap().set_price_tier(price_tier, valid_from);
+                true
+            },
+            BLEvents::CreateBundle { itemname, price_cents, category, components } => {
+                let item_id = store.item_id_counter;
+                BLEvents::CreateItem { itemname: itemname.to_string(), price_cents: *price_cents, category: category.clone() }.apply(store, config);
+                store.items.get_mut(&item_id).unwrap().bundle = components.clone();
+                true
+            },
+            BLEvents::SetBundleComponents { item_id, components } => {
+                store.items.get_mut(item_id).unwrap().bundle = components.clone();
+                true
+            },
+            &BLEvents::SetItemTierPrice { item_id, price_tier, cost_cents, timestamp } => {
+                let valid_from = timestamp.unwrap_or_else(|| latest_purchase_millis(store).map(|t| t + 1).unwrap_or(i64::MIN));
+                store.items.get_mut(&item_id).unwrap().set_tier_price(price_tier, cost_cents, valid_from);
+                true
+            },
+            BLEvents::OverrideConsumptionLimits { user_id, timestamp, valid_until, comment } => {
+                store.consumption_limits.overrides.push(LimitOverride {
+                    user_id: *user_id,
+                    timestamp: *timestamp,
+                    valid_until: *valid_until,
+                    comment: comment.to_string(),
+                });
+                true
+            },
         };
     }
 }
@@ -1281,6 +1598,7 @@ mod tests {
                 item_id: 1u32,
                 user_id: 1u32,
                 timestamp: 123456789i64,
+                quantity: 1,
             },
         ];
 
@@ -1311,6 +1629,7 @@ mod tests {
                 item_id: 1u32,
                 user_id: 1u32,
                 timestamp: 123456789i64,
+                quantity: 1,
             },
         ];
 
@@ -0,0 +1,145 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use datastore::TierPrice;
+use typescriptify::TypeScriptifyTrait;
+
+
+//an item update that takes effect at effective_timestamp.
+//it is applied right before the first purchase made at or after that time
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct ScheduledItemChange {
+    pub schedule_id: u64,
+    pub item_id: u32,
+    pub itemname: String,
+    pub price_cents: u32,
+    pub category: Option<String>,
+    pub effective_timestamp: i64,
+    //prices of other tiers changed along with the member price
+    #[serde(default)]
+    pub tier_prices: Vec<TierPrice>,
+}
+
+//pending changes, ordered by effective_timestamp and then by the order they were scheduled in
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ItemChangeSchedule {
+    pub pending: Vec<ScheduledItemChange>,
+    pub schedule_id_counter: u64,
+}
+
+impl ItemChangeSchedule {
+    pub fn schedule(&mut self, item_id: u32, itemname: String, price_cents: u32, tier_prices: Vec<TierPrice>, category: Option<String>, effective_timestamp: i64) -> u64 {
+        self.schedule_id_counter += 1;
+        let schedule_id = self.schedule_id_counter;
+        let idx = self.pending.partition_point(|c| c.effective_timestamp <= effective_timestamp);
+        self.pending.insert(idx, ScheduledItemChange {
+            schedule_id,
+            item_id,
+            itemname,
+            price_cents,
+            category,
+            effective_timestamp,
+            tier_prices,
+        });
+        schedule_id
+    }
+
+    pub fn contains(&self, schedule_id: u64) -> bool {
+        self.pending.iter().any(|c| c.schedule_id == schedule_id)
+    }
+
+    pub fn cancel(&mut self, schedule_id: u64) -> Option<ScheduledItemChange> {
+        let idx = self.pending.iter().position(|c| c.schedule_id == schedule_id)?;
+        Some(self.pending.remove(idx))
+    }
+
+    //drops everything scheduled for a deleted item
+    pub fn remove_item(&mut self, item_id: u32) {
+        self.pending.retain(|c| c.item_id != item_id);
+    }
+
+    //takes out all changes due at the given time, oldest first
+    pub fn take_due(&mut self, timestamp_epoch_millis: i64) -> Vec<ScheduledItemChange> {
+        let idx = self.pending.partition_point(|c| c.effective_timestamp <= timestamp_epoch_millis);
+        self.pending.drain(..idx).collect()
+    }
+
+    //the item's changes that are due at the given time but not applied yet, oldest first
+    pub fn due_for(&self, item_id: u32, timestamp_epoch_millis: i64) -> Vec<&ScheduledItemChange> {
+        self.pending.iter()
+            .take_while(|c| c.effective_timestamp <= timestamp_epoch_millis)
+            .filter(|c| c.item_id == item_id)
+            .collect()
+    }
+
+    pub fn pending_for(&self, item_id: Option<u32>) -> Vec<ScheduledItemChange> {
+        self.pending.iter().filter(|c| item_id.map(|id| c.item_id == id).unwrap_or(true)).cloned().collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use datastore::DatastoreQueries;
+    use datastore::PriceTier;
+    use datastore::PurchaseFunctions;
+    use prepaid::PrepaidTransactionKind;
+    use rustix_backend::WriteBackend;
+
+    #[test]
+    fn scheduled_changes_apply_with_the_first_purchase_after_their_time() {
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.create_item("beer".to_string(), 100, Some("alcohol".to_string()));
+        backend.create_item("mate".to_string(), 150, None);
+
+        assert!(backend.schedule_item_update(0, "beer".to_string(), 120, vec![], Some("alcohol".to_string()), 100));
+        assert!(backend.schedule_item_update(0, "pils".to_string(), 130, vec![], Some("beer".to_string()), 200));
+        assert!(backend.schedule_item_update(1, "club mate".to_string(), 150, vec![], None, 100));
+        assert!(!backend.schedule_item_update(7, "nothing".to_string(), 1, vec![], None, 100));
+        assert_eq!(backend.datastore.pending_item_changes(None).iter().map(|c| c.schedule_id).collect::<Vec<_>>(), vec![1, 3, 2]);
+
+        //nothing is due yet
+        backend.purchase(0, 0, 50);
+        assert_eq!(backend.datastore.items[&0].cost_cents, 100);
+        assert_eq!(backend.datastore.pending_item_changes(Some(0)).len(), 2);
+
+        backend.purchase(0, 0, 120);
+        assert_eq!(backend.datastore.items[&0].cost_cents, 120);
+        assert_eq!(backend.datastore.items[&1].name, "club mate");
+        assert_eq!(backend.datastore.purchases.iter().map(|p| p.get_unit_price()).collect::<Vec<_>>(), vec![Some(100), Some(120)]);
+        //purchases entered late still get the price of their time
+        backend.purchase(0, 0, 60);
+        assert_eq!(backend.datastore.purchases.iter().map(|p| p.get_unit_price()).collect::<Vec<_>>(), vec![Some(100), Some(100), Some(120)]);
+
+        assert!(backend.cancel_scheduled_item_update(2));
+        assert!(!backend.cancel_scheduled_item_update(2));
+        backend.purchase(0, 0, 300);
+        assert_eq!(backend.datastore.items[&0].name, "beer");
+        assert!(backend.datastore.pending_item_changes(None).is_empty());
+
+        //changes of deleted items are dropped
+        assert!(backend.schedule_item_update(1, "mate".to_string(), 200, vec![], None, 400));
+        backend.delete_item(1);
+        assert!(backend.datastore.pending_item_changes(None).is_empty());
+        assert!(!backend.schedule_item_update(1, "mate".to_string(), 200, vec![], None, 400));
+    }
+
+    #[test]
+    fn due_changes_are_priced_before_they_are_applied() {
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.create_item("beer".to_string(), 100, None);
+        assert!(backend.open_prepaid_account(0, 0));
+        assert!(backend.top_up_prepaid(0, 110, PrepaidTransactionKind::Cash, 1, "".to_string()));
+        assert!(backend.schedule_item_update(0, "beer".to_string(), 120, vec![], None, 100));
+
+        //nothing has been bought since, but listings and checks already use the new price
+        assert_eq!(backend.datastore.item_prices_at(50, PriceTier::Member)[0].effective_cents, 100);
+        assert_eq!(backend.datastore.item_prices_at(150, PriceTier::Member)[0].effective_cents, 120);
+        backend.purchase(0, 0, 150);
+        assert!(backend.datastore.purchases.is_empty());
+        backend.purchase(0, 0, 50);
+        assert_eq!(backend.datastore.prepaid_account(0).unwrap().balance_cents, 10);
+    }
+}
@@ -0,0 +1,439 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std;
+use std::cmp;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use datastore::Item;
+use datastore::User;
+use suffix_rs::SearchResult;
+use typescriptify::TypeScriptifyTrait;
+use unidecode::unidecode;
+
+
+//ordered from best to worst match quality
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, TypeScriptify)]
+pub enum MatchKind {
+    Exact,
+    Prefix,
+    WordPrefix,
+    Substring,
+    Fuzzy,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, TypeScriptify)]
+pub enum SearchField {
+    Username,
+    ExternalUserId,
+    ItemName,
+    Category,
+}
+
+//character range [start, end) inside the original (not normalized) field text
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, TypeScriptify)]
+pub struct HighlightRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct SearchHit {
+    pub id: u32,
+    pub field: SearchField,
+    pub text: String,
+    pub kind: MatchKind,
+    pub edit_distance: u32,
+    pub highlights: Vec<HighlightRange>,
+    pub popularity: u32,
+}
+
+
+//text folded to lowercase ascii, remembering for every folded char which original char it came from
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NormalizedText {
+    pub chars: Vec<char>,
+    pub origins: Vec<usize>,
+}
+
+pub fn normalize(input: &str) -> NormalizedText {
+    let mut chars: Vec<char> = Vec::new();
+    let mut origins: Vec<usize> = Vec::new();
+    for (idx, c) in input.chars().enumerate() {
+        for folded in unidecode(&c.to_string()).to_lowercase().chars() {
+            chars.push(folded);
+            origins.push(idx);
+        }
+    }
+    NormalizedText {
+        chars,
+        origins,
+    }
+}
+
+pub fn normalize_term(input: &str) -> Vec<char> {
+    unidecode(input.trim()).to_lowercase().chars().collect()
+}
+
+//short terms only match exactly, longer ones tolerate one or two typos
+pub fn default_max_edit_distance(term_length: usize) -> usize {
+    match term_length {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct IndexedField {
+    pub field: SearchField,
+    pub original: String,
+    pub normalized: NormalizedText,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SearchDocument {
+    pub id: u32,
+    pub fields: Vec<IndexedField>,
+}
+
+impl SearchDocument {
+    pub fn new(id: u32) -> Self {
+        SearchDocument {
+            id,
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn with_field(mut self, field: SearchField, text: &str) -> Self {
+        self.fields.push(IndexedField {
+            field,
+            original: text.to_string(),
+            normalized: normalize(text),
+        });
+        self
+    }
+
+    pub fn for_user(user: &User) -> Self {
+        let doc = SearchDocument::new(user.user_id).with_field(SearchField::Username, &user.username);
+        match user.external_user_id {
+            Some(ref external) => doc.with_field(SearchField::ExternalUserId, external),
+            None => doc,
+        }
+    }
+
+    pub fn for_item(item: &Item) -> Self {
+        let doc = SearchDocument::new(item.item_id).with_field(SearchField::ItemName, &item.name);
+        match item.category {
+            Some(ref category) => doc.with_field(SearchField::Category, category),
+            None => doc,
+        }
+    }
+
+    //best match over all fields of this document, if any field matches at all
+    pub fn best_match(&self, term: &[char], max_edit_distance: usize) -> Option<SearchHit> {
+        let mut best: Option<SearchHit> = None;
+        for field in &self.fields {
+            if let Some((kind, distance, start, end)) = match_field(term, &field.normalized.chars, max_edit_distance) {
+                let is_better = match best {
+                    Some(ref b) => (kind, distance as u32) < (b.kind, b.edit_distance),
+                    None => true,
+                };
+                if is_better {
+                    best = Some(SearchHit {
+                        id: self.id,
+                        field: field.field,
+                        text: field.original.to_string(),
+                        kind,
+                        edit_distance: distance as u32,
+                        highlights: highlight(&field.normalized, start, end),
+                        popularity: 0,
+                    });
+                }
+            }
+        }
+        best
+    }
+}
+
+
+fn highlight(text: &NormalizedText, start: usize, end: usize) -> Vec<HighlightRange> {
+    if start >= end {
+        return vec![];
+    }
+    vec![HighlightRange {
+        start: text.origins[start],
+        end: text.origins[end - 1] + 1,
+    }]
+}
+
+fn find_chars(haystack: &[char], needle: &[char]) -> Option<usize> {
+    if needle.len() > haystack.len() {
+        return None;
+    }
+    (0..(haystack.len() - needle.len() + 1)).find(|i| &haystack[*i..(*i + needle.len())] == needle)
+}
+
+fn is_word_start(text: &[char], idx: usize) -> bool {
+    idx == 0 || !text[idx - 1].is_alphanumeric()
+}
+
+//returns kind, edit distance and the matched range in normalized chars
+pub fn match_field(term: &[char], text: &[char], max_edit_distance: usize) -> Option<(MatchKind, usize, usize, usize)> {
+    if term.is_empty() {
+        return Some((MatchKind::Prefix, 0, 0, 0));
+    }
+    if term == text {
+        return Some((MatchKind::Exact, 0, 0, text.len()));
+    }
+    if text.starts_with(term) {
+        return Some((MatchKind::Prefix, 0, 0, term.len()));
+    }
+    if let Some(first) = find_chars(text, term) {
+        let mut idx = first;
+        loop {
+            if is_word_start(text, idx) {
+                return Some((MatchKind::WordPrefix, 0, idx, idx + term.len()));
+            }
+            match find_chars(&text[(idx + 1)..], term) {
+                Some(next) => idx = idx + 1 + next,
+                None => break,
+            }
+        }
+        return Some((MatchKind::Substring, 0, first, first + term.len()));
+    }
+    if max_edit_distance == 0 {
+        return None;
+    }
+    best_fuzzy_window(term, text, max_edit_distance).map(|(distance, start, end)| (MatchKind::Fuzzy, distance, start, end))
+}
+
+//smallest edit distance (with adjacent transpositions) of term to any substring of text, bounded by max_edit_distance
+pub fn best_fuzzy_window(term: &[char], text: &[char], max_edit_distance: usize) -> Option<(usize, usize, usize)> {
+    let n = text.len();
+    let mut prev_prev: Vec<usize> = vec![0; n + 1];
+    let mut prev_prev_start: Vec<usize> = (0..(n + 1)).collect();
+    let mut prev: Vec<usize> = vec![0; n + 1];
+    let mut prev_start: Vec<usize> = (0..(n + 1)).collect();
+
+    for (i, term_char) in term.iter().enumerate() {
+        let mut cur: Vec<usize> = vec![i + 1; n + 1];
+        let mut cur_start: Vec<usize> = vec![0; n + 1];
+        for j in 1..(n + 1) {
+            let cost = if *term_char == text[j - 1] { 0 } else { 1 };
+            let mut best = prev[j - 1] + cost;
+            let mut start = prev_start[j - 1];
+            if prev[j] + 1 < best {
+                best = prev[j] + 1;
+                start = prev_start[j];
+            }
+            if cur[j - 1] + 1 < best {
+                best = cur[j - 1] + 1;
+                start = cur_start[j - 1];
+            }
+            if i > 0 && j > 1 && *term_char == text[j - 2] && term[i - 1] == text[j - 1] && prev_prev[j - 2] + 1 < best {
+                best = prev_prev[j - 2] + 1;
+                start = prev_prev_start[j - 2];
+            }
+            cur[j] = best;
+            cur_start[j] = start;
+        }
+        if cur.iter().all(|d| *d > max_edit_distance) {
+            return None;
+        }
+        prev_prev = std::mem::replace(&mut prev, cur);
+        prev_prev_start = std::mem::replace(&mut prev_start, cur_start);
+    }
+
+    let mut best: Option<(usize, usize, usize)> = None;
+    for j in 0..(n + 1) {
+        let distance = prev[j];
+        let improves = match best {
+            Some((d, _, _)) => distance < d,
+            None => true,
+        };
+        if distance <= max_edit_distance && improves {
+            best = Some((distance, prev_start[j], j));
+        }
+    }
+    best
+}
+
+
+//match quality first, then popularity (descending), then text and id to keep results stable
+pub fn compare_hits(a: &SearchHit, b: &SearchHit) -> Ordering {
+    a.kind.cmp(&b.kind)
+        .then(a.edit_distance.cmp(&b.edit_distance))
+        .then(b.popularity.cmp(&a.popularity))
+        .then(a.text.to_lowercase().cmp(&b.text.to_lowercase()))
+        .then(a.id.cmp(&b.id))
+}
+
+pub fn ranked_search<'a, I, F>(searchterm: &str, documents: I, popularity: F) -> Vec<SearchHit>
+    where I: Iterator<Item = &'a SearchDocument>,
+          F: Fn(u32) -> u32 {
+    let term = normalize_term(searchterm);
+    let max_edit_distance = cmp::min(default_max_edit_distance(term.len()), term.len());
+    let mut hits: Vec<SearchHit> = documents
+        .filter_map(|doc| doc.best_match(&term, max_edit_distance))
+        .map(|mut hit| {
+            hit.popularity = popularity(hit.id);
+            hit
+        })
+        .collect();
+    hits.sort_by(compare_hits);
+    hits
+}
+
+
+
+//incrementally maintained search documents, keyed by user or item id
+//an old MockKDTree snapshot deserializes into an empty index, which has to be rebuilt after loading
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+pub struct SearchIndex {
+    #[serde(default)]
+    documents: HashMap<u32, SearchDocument>,
+}
+
+impl SearchIndex {
+    pub fn build<I: Iterator<Item = SearchDocument>>(documents: I) -> Self {
+        let mut index = SearchIndex::default();
+        for doc in documents {
+            index.upsert(doc);
+        }
+        index
+    }
+
+    //inserts a new document or replaces the existing one with the same id
+    pub fn upsert(&mut self, document: SearchDocument) -> bool {
+        self.documents.insert(document.id, document).is_none()
+    }
+
+    pub fn remove(&mut self, id: u32) -> bool {
+        self.documents.remove(&id).is_some()
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.documents.contains_key(&id)
+    }
+
+    pub fn get(&self, id: u32) -> Option<&SearchDocument> {
+        self.documents.get(&id)
+    }
+
+    pub fn ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.documents.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    //plain substring search on the first (primary) field of every document, sorted by id
+    pub fn search(&self, query: &str) -> Vec<SearchResult> {
+        let term = normalize_term(query);
+        let mut results: Vec<SearchResult> = self.documents.values()
+            .filter_map(|doc| doc.fields.first().and_then(|f| find_chars(&f.normalized.chars, &term)).map(|idx| SearchResult {
+                id: doc.id,
+                index: idx,
+            }))
+            .collect();
+        results.sort();
+        results
+    }
+
+    pub fn ranked_search<F: Fn(u32) -> u32>(&self, searchterm: &str, popularity: F) -> Vec<SearchHit> {
+        ranked_search(searchterm, self.documents.values(), popularity)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::*;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn normalization_folds_diacritics_and_keeps_origins() {
+        let n = normalize("Jürgen Ößwald");
+        let folded: String = n.chars.iter().collect();
+        assert_eq!(folded, "jurgen osswald");
+        //the two s from 'ß' both point to the same original char
+        assert_eq!(n.origins[8], 8);
+        assert_eq!(n.origins[9], 8);
+        assert_eq!(n.origins[10], 9);
+    }
+
+    #[test]
+    fn match_kinds_are_ranked() {
+        assert_eq!(match_field(&chars("bier"), &chars("bier"), 1).unwrap().0, MatchKind::Exact);
+        assert_eq!(match_field(&chars("bie"), &chars("bier"), 1).unwrap().0, MatchKind::Prefix);
+        assert_eq!(match_field(&chars("mate"), &chars("club mate"), 1).unwrap().0, MatchKind::WordPrefix);
+        assert_eq!(match_field(&chars("ate"), &chars("club mate"), 0).unwrap().0, MatchKind::Substring);
+        assert_eq!(match_field(&chars("beir"), &chars("bier"), 1), Some((MatchKind::Fuzzy, 1, 0, 4)));
+        assert_eq!(match_field(&chars("beir"), &chars("bier"), 0), None);
+        let fuzzy = match_field(&chars("biier"), &chars("pils bier"), 1).unwrap();
+        assert_eq!(fuzzy.0, MatchKind::Fuzzy);
+        assert_eq!(fuzzy.1, 1);
+        assert_eq!((fuzzy.2, fuzzy.3), (5, 9));
+        assert_eq!(match_field(&chars("cola"), &chars("bier"), 1), None);
+    }
+
+    #[test]
+    fn hits_highlight_original_text() {
+        let doc = SearchDocument::new(3).with_field(SearchField::Username, "Müller Jörg");
+        let hit = doc.best_match(&normalize_term("jorg"), 1).unwrap();
+        assert_eq!(hit.kind, MatchKind::WordPrefix);
+        assert_eq!(hit.highlights, vec![HighlightRange { start: 7, end: 11 }]);
+    }
+
+    #[test]
+    fn ranking_prefers_quality_then_popularity() {
+        let docs = vec![
+            SearchDocument::new(0).with_field(SearchField::Username, "anna"),
+            SearchDocument::new(1).with_field(SearchField::Username, "annabell"),
+            SearchDocument::new(2).with_field(SearchField::Username, "hannah"),
+            SearchDocument::new(3).with_field(SearchField::Username, "annika"),
+        ];
+        let hits = ranked_search("ann", docs.iter(), |id| id);
+        let ids: Vec<u32> = hits.iter().map(|h| h.id).collect();
+        assert_eq!(ids, vec![3, 1, 0, 2]);
+        let hits = ranked_search("anna", docs.iter(), |id| id);
+        let ids: Vec<u32> = hits.iter().map(|h| h.id).collect();
+        assert_eq!(ids, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn index_supports_insert_update_and_remove() {
+        let mut index = SearchIndex::default();
+        assert!(index.upsert(SearchDocument::new(0).with_field(SearchField::ItemName, "Bier")));
+        assert!(index.upsert(SearchDocument::new(1).with_field(SearchField::ItemName, "Cola")));
+        assert_eq!(index.search("bier").len(), 1);
+
+        assert!(!index.upsert(SearchDocument::new(0).with_field(SearchField::ItemName, "Radler")));
+        assert_eq!(index.search("bier").len(), 0);
+        assert_eq!(index.search("radl")[0].id, 0);
+        assert_eq!(index.search("").len(), 2);
+
+        assert!(index.remove(1));
+        assert!(!index.remove(1));
+        assert_eq!(index.search("").len(), 1);
+    }
+
+    #[test]
+    fn index_loads_from_legacy_tree_snapshot() {
+        let legacy = "---\nelements:\n  0: klaus\ncase_sensitive: false\n";
+        let index: SearchIndex = ::serde_yaml::from_str(legacy).unwrap();
+        assert!(index.is_empty());
+    }
+}
@@ -0,0 +1,453 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use calendar;
+use datastore::Bill;
+use datastore::Datastore;
+use datastore::Purchase;
+use datastore::PurchaseFunctions;
+use datastore::purchase_unit_price;
+use typescriptify::TypeScriptifyTrait;
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub enum BucketSize {
+    Hour,
+    Day,
+    Week,
+    Month,
+    Custom { length_millis: i64 },
+    //a single bucket spanning the complete queried range
+    WholeRange,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum StatisticsGrouping {
+    Item,
+    Category,
+    User,
+    Special,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, TypeScriptify)]
+pub enum StatisticsKey {
+    Item { item_id: u32 },
+    Category { category: Option<String> },
+    User { user_id: u32 },
+    Special { name: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct StatisticsEntry {
+    pub key: StatisticsKey,
+    pub count: u64,
+    pub revenue_cents: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct StatisticsBucket {
+    pub from_inclusive: i64,
+    pub to_exclusive: i64,
+    pub entries: Vec<StatisticsEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct MarginEntry {
+    pub key: StatisticsKey,
+    pub count: u64,
+    pub revenue_cents: u64,
+    pub cost_of_goods_cents: u64,
+    //revenue minus cost of goods, both only of units with a known acquisition cost
+    pub margin_cents: i64,
+    //units without a known acquisition cost, e.g. specials
+    pub uncosted_count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct MarginBucket {
+    pub from_inclusive: i64,
+    pub to_exclusive: i64,
+    pub entries: Vec<MarginEntry>,
+}
+
+#[derive(Default)]
+struct MarginSum {
+    count: u64,
+    revenue_cents: u64,
+    costed_revenue_cents: u64,
+    cost_of_goods_cents: u64,
+    uncosted_count: u64,
+}
+
+
+//one line of consumption, either a single open purchase or an aggregated line of a finalized bill
+//finalized bills only know the day of consumption, so their timestamp is the start of that day
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumptionRecord {
+    pub timestamp: i64,
+    //the user that consumed, or the donor of a free for all
+    pub user_id: u32,
+    pub item_id: Option<u32>,
+    pub special_name: Option<String>,
+    pub count: u64,
+    pub revenue_cents: u64,
+    //None for specials and items without a known acquisition cost
+    pub cost_of_goods_cents: Option<u64>,
+    pub from_finalized_bill: bool,
+    //given out by a free for all rather than bought
+    pub free_for_all: bool,
+}
+
+
+pub trait DatastoreStatistics {
+    fn consumption_records(&self, millis_start_inclusive: i64, millis_end_exclusive: i64) -> Vec<ConsumptionRecord>;
+
+    fn consumption_statistics(&self, millis_start_inclusive: i64, millis_end_exclusive: i64, bucket_size: &BucketSize, grouping: StatisticsGrouping) -> Vec<StatisticsBucket>;
+
+    //revenue, cost of goods and margin, with acquisition costs as of the time of consumption
+    fn margin_statistics(&self, millis_start_inclusive: i64, millis_end_exclusive: i64, bucket_size: &BucketSize, grouping: StatisticsGrouping) -> Vec<MarginBucket>;
+}
+
+
+pub fn bucket_boundaries(millis_start_inclusive: i64, millis_end_exclusive: i64, bucket_size: &BucketSize) -> Vec<(i64, i64)> {
+    let mut v: Vec<(i64, i64)> = vec![];
+    if millis_start_inclusive >= millis_end_exclusive {
+        return v;
+    }
+    let mut start = match *bucket_size {
+        BucketSize::Hour => calendar::start_of_hour(millis_start_inclusive),
+        BucketSize::Day => calendar::start_of_day(millis_start_inclusive),
+        BucketSize::Week => calendar::start_of_week(millis_start_inclusive),
+        BucketSize::Month => calendar::start_of_month(millis_start_inclusive),
+        BucketSize::Custom { .. } | BucketSize::WholeRange => millis_start_inclusive,
+    };
+    while start < millis_end_exclusive {
+        let next = match *bucket_size {
+            BucketSize::Hour => start + calendar::MILLIS_PER_HOUR,
+            BucketSize::Day => start + calendar::MILLIS_PER_DAY,
+            BucketSize::Week => start + calendar::MILLIS_PER_WEEK,
+            BucketSize::Month => calendar::add_months(start, 1),
+            BucketSize::Custom { length_millis } => start + length_millis.max(1),
+            BucketSize::WholeRange => millis_end_exclusive,
+        };
+        v.push((start.max(millis_start_inclusive), next.min(millis_end_exclusive)));
+        start = next;
+    }
+    v
+}
+
+
+fn unit_cost(store: &Datastore, item_id: u32, timestamp: i64, count: u32) -> Option<u64> {
+    store.items.get(&item_id).and_then(|i| i.acquisition_cost_at(timestamp)).map(|cost| u64::from(cost) * u64::from(count))
+}
+
+fn open_purchase_record(store: &Datastore, purchase: &Purchase) -> ConsumptionRecord {
+    match *purchase {
+        Purchase::SimplePurchase { timestamp_epoch_millis, item_id, consumer_id, .. } => ConsumptionRecord {
+            timestamp: timestamp_epoch_millis,
+            user_id: consumer_id,
+            item_id: Some(item_id),
+            special_name: None,
+            count: 1,
+            revenue_cents: u64::from(purchase_unit_price(store, purchase)),
+            cost_of_goods_cents: unit_cost(store, item_id, timestamp_epoch_millis, 1),
+            from_finalized_bill: false,
+            free_for_all: false,
+        },
+        Purchase::FFAPurchase { timestamp_epoch_millis, item_id, donor, .. } => ConsumptionRecord {
+            timestamp: timestamp_epoch_millis,
+            user_id: donor,
+            item_id: Some(item_id),
+            special_name: None,
+            count: 1,
+            revenue_cents: u64::from(purchase_unit_price(store, purchase)),
+            cost_of_goods_cents: unit_cost(store, item_id, timestamp_epoch_millis, 1),
+            from_finalized_bill: false,
+            free_for_all: true,
+        },
+        Purchase::SpecialPurchase { timestamp_epoch_millis, ref special_name, specialcost, consumer_id, .. } => ConsumptionRecord {
+            timestamp: timestamp_epoch_millis,
+            user_id: consumer_id,
+            item_id: None,
+            special_name: Some(special_name.to_string()),
+            count: 1,
+            revenue_cents: u64::from(specialcost.unwrap_or(0)),
+            cost_of_goods_cents: None,
+            from_finalized_bill: false,
+            free_for_all: false,
+        },
+    }
+}
+
+fn finalized_bill_records(store: &Datastore, bill: &Bill, millis_start_inclusive: i64, millis_end_exclusive: i64, records: &mut Vec<ConsumptionRecord>) {
+    let data = &bill.finalized_data;
+    //bills of older versions did not record cents, their items are valued at the prices of the bill
+    let item_record = |timestamp: i64, user_id: u32, item_id: u32, count: u32, cents: Option<&u64>, free_for_all: bool| ConsumptionRecord {
+        timestamp,
+        user_id,
+        item_id: Some(item_id),
+        special_name: None,
+        count: u64::from(count),
+        revenue_cents: cents.cloned().unwrap_or_else(|| u64::from(count) * data.all_items.get(&item_id).map(|i| u64::from(i.cost_cents)).unwrap_or(0)),
+        //bills only know the day, so the acquisition cost at its start is used
+        cost_of_goods_cents: unit_cost(store, item_id, timestamp, count),
+        from_finalized_bill: true,
+        free_for_all,
+    };
+
+    for (user_id, instance) in &data.user_consumption {
+        for (day_idx, day) in &instance.per_day {
+            let timestamp = bill.get_day_start(*day_idx);
+            if timestamp < millis_start_inclusive || timestamp >= millis_end_exclusive {
+                continue;
+            }
+            for (item_id, count) in &day.personally_consumed {
+                records.push(item_record(timestamp, *user_id, *item_id, *count, day.personally_consumed_cents.get(item_id), false));
+            }
+            for (item_id, count) in &day.ffa_giveouts {
+                records.push(item_record(timestamp, *user_id, *item_id, *count, day.ffa_giveouts_cents.get(item_id), true));
+            }
+            //count giveouts are listed under the donor, but were consumed by the recipient
+            for paid_for in day.giveouts_to_user_id.values() {
+                for (item_id, count) in &paid_for.count_giveouts_used {
+                    records.push(item_record(timestamp, paid_for.recipient_id, *item_id, *count, paid_for.count_giveouts_cents.get(item_id), false));
+                }
+            }
+            for special in &day.specials_consumed {
+                records.push(ConsumptionRecord {
+                    timestamp,
+                    user_id: *user_id,
+                    item_id: None,
+                    special_name: Some(special.name.to_string()),
+                    count: 1,
+                    revenue_cents: u64::from(special.price),
+                    cost_of_goods_cents: None,
+                    from_finalized_bill: true,
+                    free_for_all: false,
+                });
+            }
+        }
+    }
+}
+
+fn bucket_index(boundaries: &[(i64, i64)], timestamp: i64) -> Option<usize> {
+    boundaries.binary_search_by(|&(from, to)| {
+        if to <= timestamp {
+            ::std::cmp::Ordering::Less
+        } else if from > timestamp {
+            ::std::cmp::Ordering::Greater
+        } else {
+            ::std::cmp::Ordering::Equal
+        }
+    }).ok()
+}
+
+fn grouping_key(store: &Datastore, record: &ConsumptionRecord, grouping: StatisticsGrouping) -> Option<StatisticsKey> {
+    match grouping {
+        StatisticsGrouping::Item => record.item_id.map(|item_id| StatisticsKey::Item { item_id }),
+        StatisticsGrouping::Category => record.item_id.map(|item_id| StatisticsKey::Category {
+            category: store.items.get(&item_id).and_then(|i| i.category.clone()),
+        }),
+        StatisticsGrouping::User => Some(StatisticsKey::User { user_id: record.user_id }),
+        StatisticsGrouping::Special => record.special_name.as_ref().map(|name| StatisticsKey::Special { name: name.to_string() }),
+    }
+}
+
+
+impl DatastoreStatistics for Datastore {
+    fn consumption_records(&self, millis_start_inclusive: i64, millis_end_exclusive: i64) -> Vec<ConsumptionRecord> {
+        let mut records: Vec<ConsumptionRecord> = self.purchases.iter()
+            .filter(|p| {
+                let t = *p.get_timestamp();
+                t >= millis_start_inclusive && t < millis_end_exclusive
+            })
+            .map(|p| open_purchase_record(self, p))
+            .collect();
+        for bill in self.bills.iter().filter(|b| b.bill_state.is_finalized()) {
+            finalized_bill_records(self, bill, millis_start_inclusive, millis_end_exclusive, &mut records);
+        }
+        records
+    }
+
+    fn consumption_statistics(&self, millis_start_inclusive: i64, millis_end_exclusive: i64, bucket_size: &BucketSize, grouping: StatisticsGrouping) -> Vec<StatisticsBucket> {
+        let boundaries = bucket_boundaries(millis_start_inclusive, millis_end_exclusive, bucket_size);
+        let mut sums: Vec<HashMap<StatisticsKey, (u64, u64)>> = boundaries.iter().map(|_| HashMap::new()).collect();
+
+        for record in self.consumption_records(millis_start_inclusive, millis_end_exclusive) {
+            if let (Some(idx), Some(key)) = (bucket_index(&boundaries, record.timestamp), grouping_key(self, &record, grouping)) {
+                let sum = sums[idx].entry(key).or_insert((0, 0));
+                sum.0 += record.count;
+                sum.1 += record.revenue_cents;
+            }
+        }
+
+        boundaries.iter().zip(sums).map(|(&(from, to), sum)| {
+            let mut entries: Vec<StatisticsEntry> = sum.into_iter().map(|(key, (count, revenue_cents))| StatisticsEntry {
+                key,
+                count,
+                revenue_cents,
+            }).collect();
+            entries.sort_by(|a, b| a.key.cmp(&b.key));
+            StatisticsBucket {
+                from_inclusive: from,
+                to_exclusive: to,
+                entries,
+            }
+        }).collect()
+    }
+
+    fn margin_statistics(&self, millis_start_inclusive: i64, millis_end_exclusive: i64, bucket_size: &BucketSize, grouping: StatisticsGrouping) -> Vec<MarginBucket> {
+        let boundaries = bucket_boundaries(millis_start_inclusive, millis_end_exclusive, bucket_size);
+        let mut sums: Vec<HashMap<StatisticsKey, MarginSum>> = boundaries.iter().map(|_| HashMap::new()).collect();
+
+        for record in self.consumption_records(millis_start_inclusive, millis_end_exclusive) {
+            if let (Some(idx), Some(key)) = (bucket_index(&boundaries, record.timestamp), grouping_key(self, &record, grouping)) {
+                let sum = sums[idx].entry(key).or_default();
+                sum.count += record.count;
+                sum.revenue_cents += record.revenue_cents;
+                match record.cost_of_goods_cents {
+                    Some(cost) => {
+                        sum.costed_revenue_cents += record.revenue_cents;
+                        sum.cost_of_goods_cents += cost;
+                    },
+                    None => sum.uncosted_count += record.count,
+                }
+            }
+        }
+
+        boundaries.iter().zip(sums).map(|(&(from, to), sum)| {
+            let mut entries: Vec<MarginEntry> = sum.into_iter().map(|(key, sum)| MarginEntry {
+                key,
+                count: sum.count,
+                revenue_cents: sum.revenue_cents,
+                cost_of_goods_cents: sum.cost_of_goods_cents,
+                margin_cents: sum.costed_revenue_cents as i64 - sum.cost_of_goods_cents as i64,
+                uncosted_count: sum.uncosted_count,
+            }).collect();
+            entries.sort_by(|a, b| a.key.cmp(&b.key));
+            MarginBucket {
+                from_inclusive: from,
+                to_exclusive: to,
+                entries,
+            }
+        }).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use statistics::*;
+    use calendar;
+    use rustix_backend::WriteBackend;
+    use rustix_event_shop::BLEvents;
+    use datastore::UserGroup;
+
+    #[test]
+    fn boundaries_follow_calendar() {
+        let from = calendar::millis_from_civil(2018, 1, 20);
+        let to = calendar::millis_from_civil(2018, 3, 10);
+        let months = bucket_boundaries(from, to, &BucketSize::Month);
+        assert_eq!(months, vec![
+            (from, calendar::millis_from_civil(2018, 2, 1)),
+            (calendar::millis_from_civil(2018, 2, 1), calendar::millis_from_civil(2018, 3, 1)),
+            (calendar::millis_from_civil(2018, 3, 1), to),
+        ]);
+        assert_eq!(bucket_boundaries(from, to, &BucketSize::Week).len(), 8);
+        assert_eq!(bucket_boundaries(from, to, &BucketSize::WholeRange), vec![(from, to)]);
+        assert_eq!(bucket_boundaries(from, from + 10, &BucketSize::Custom { length_millis: 4 }).len(), 3);
+    }
+
+    #[test]
+    fn aggregates_open_and_finalized_purchases() {
+        let day = calendar::MILLIS_PER_DAY;
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.create_user("dieter".to_string());
+        backend.create_item("beer".to_string(), 100, Some("Alcohol".to_string()));
+        backend.create_item("soda".to_string(), 50, None);
+
+        backend.purchase(0, 0, 10);
+        backend.purchase(1, 0, 20);
+        backend.special_purchase(1, "pizza".to_string(), 30);
+        backend.create_bill(0, day, UserGroup::AllUsers, "first".to_string());
+        backend.update_user(0, "klaus".to_string(), false, false, None, true);
+        backend.update_user(1, "dieter".to_string(), false, false, None, true);
+        backend.apply(&BLEvents::SetPriceForSpecial { unique_id: 3, price: 700 });
+        assert!(backend.apply(&BLEvents::FinalizeBill { timestamp_from: 0, timestamp_to: day }));
+        assert_eq!(backend.datastore.purchases.len(), 0);
+
+        backend.purchase(0, 1, day + 10);
+        backend.purchase(0, 0, 2 * day + 10);
+
+        let per_item = backend.datastore.consumption_statistics(0, 3 * day, &BucketSize::WholeRange, StatisticsGrouping::Item);
+        assert_eq!(per_item.len(), 1);
+        assert_eq!(per_item[0].entries, vec![
+            StatisticsEntry { key: StatisticsKey::Item { item_id: 0 }, count: 3, revenue_cents: 300 },
+            StatisticsEntry { key: StatisticsKey::Item { item_id: 1 }, count: 1, revenue_cents: 50 },
+        ]);
+
+        let per_day = backend.datastore.consumption_statistics(0, 3 * day, &BucketSize::Day, StatisticsGrouping::User);
+        assert_eq!(per_day.len(), 3);
+        assert_eq!(per_day[0].entries, vec![
+            StatisticsEntry { key: StatisticsKey::User { user_id: 0 }, count: 1, revenue_cents: 100 },
+            StatisticsEntry { key: StatisticsKey::User { user_id: 1 }, count: 2, revenue_cents: 800 },
+        ]);
+        assert_eq!(per_day[1].entries, vec![
+            StatisticsEntry { key: StatisticsKey::User { user_id: 0 }, count: 1, revenue_cents: 50 },
+        ]);
+
+        let per_category = backend.datastore.consumption_statistics(day, 3 * day, &BucketSize::WholeRange, StatisticsGrouping::Category);
+        assert_eq!(per_category[0].entries, vec![
+            StatisticsEntry { key: StatisticsKey::Category { category: None }, count: 1, revenue_cents: 50 },
+            StatisticsEntry { key: StatisticsKey::Category { category: Some("Alcohol".to_string()) }, count: 1, revenue_cents: 100 },
+        ]);
+
+        let specials = backend.datastore.consumption_statistics(0, 3 * day, &BucketSize::WholeRange, StatisticsGrouping::Special);
+        assert_eq!(specials[0].entries, vec![
+            StatisticsEntry { key: StatisticsKey::Special { name: "pizza".to_string() }, count: 1, revenue_cents: 700 },
+        ]);
+    }
+
+    #[test]
+    fn margins_use_the_acquisition_cost_at_the_time_of_consumption() {
+        let day = calendar::MILLIS_PER_DAY;
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.update_user(0, "klaus".to_string(), false, false, None, true);
+        backend.create_item("beer".to_string(), 100, Some("Alcohol".to_string()));
+        backend.create_item("wine".to_string(), 300, Some("Alcohol".to_string()));
+        backend.create_item("soda".to_string(), 50, None);
+        assert!(backend.set_acquisition_cost(0, 60, 0));
+        assert!(backend.set_acquisition_cost(1, 200, 0));
+        assert!(!backend.set_acquisition_cost(7, 200, 0));
+
+        //one billed beer on the first day, then the brewery raises its price
+        backend.purchase(0, 0, 10);
+        backend.create_bill(0, day, UserGroup::AllUsers, "first".to_string());
+        assert!(backend.apply(&BLEvents::FinalizeBill { timestamp_from: 0, timestamp_to: day }));
+        backend.set_acquisition_cost(0, 80, day);
+        backend.purchase(0, 0, day + 10);
+        backend.purchase(0, 1, day + 20);
+        backend.purchase(0, 2, day + 30);
+
+        let per_item = backend.datastore.margin_statistics(0, 2 * day, &BucketSize::WholeRange, StatisticsGrouping::Item);
+        assert_eq!(per_item[0].entries, vec![
+            MarginEntry { key: StatisticsKey::Item { item_id: 0 }, count: 2, revenue_cents: 200, cost_of_goods_cents: 140, margin_cents: 60, uncosted_count: 0 },
+            MarginEntry { key: StatisticsKey::Item { item_id: 1 }, count: 1, revenue_cents: 300, cost_of_goods_cents: 200, margin_cents: 100, uncosted_count: 0 },
+            MarginEntry { key: StatisticsKey::Item { item_id: 2 }, count: 1, revenue_cents: 50, cost_of_goods_cents: 0, margin_cents: 0, uncosted_count: 1 },
+        ]);
+
+        let per_category = backend.datastore.margin_statistics(0, 2 * day, &BucketSize::Day, StatisticsGrouping::Category);
+        assert_eq!(per_category[0].entries[0].margin_cents, 40);
+        assert_eq!(per_category[1].entries[1], MarginEntry {
+            key: StatisticsKey::Category { category: Some("Alcohol".to_string()) },
+            count: 2,
+            revenue_cents: 400,
+            cost_of_goods_cents: 280,
+            margin_cents: 120,
+            uncosted_count: 0,
+        });
+    }
+}
@@ -0,0 +1,170 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use datastore::Datastore;
+use inventory::StockMovementKind;
+use typescriptify::TypeScriptifyTrait;
+
+
+//one counted item, with its movements since the previous stocktake
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, TypeScriptify)]
+pub struct ShrinkageLine {
+    pub item_id: u32,
+    pub itemname: String,
+    pub category: Option<String>,
+    //counted at the previous stocktake, 0 if there was none
+    pub previous_count: i64,
+    pub delivered: i64,
+    //purchases and ffa giveouts, minus undone ones
+    pub consumed: i64,
+    pub written_off: i64,
+    pub corrected: i64,
+    pub expected: i64,
+    pub counted: i64,
+    //positive if units are missing
+    pub shrinkage_units: i64,
+    pub shrinkage_cents: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, TypeScriptify)]
+pub struct CategoryShrinkage {
+    pub category: Option<String>,
+    pub shrinkage_units: i64,
+    pub shrinkage_cents: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, TypeScriptify)]
+pub struct StocktakeReport {
+    pub timestamp_epoch_millis: i64,
+    pub comment: String,
+    pub items: Vec<ShrinkageLine>,
+    pub categories: Vec<CategoryShrinkage>,
+    pub shrinkage_units: i64,
+    pub shrinkage_cents: i64,
+}
+
+fn shrinkage_line(store: &Datastore, item_id: u32, counted: i64, timestamp_epoch_millis: i64) -> ShrinkageLine {
+    let item = &store.items[&item_id];
+    //movements entered before a late count but dated after it belong to the next period
+    let movements: Vec<_> = store.inventory.movements_since_stocktake(item_id).into_iter().filter(|m| m.timestamp_epoch_millis <= timestamp_epoch_millis).collect();
+    let sum = |kinds: &[StockMovementKind]| -> i64 { movements.iter().filter(|m| kinds.contains(&m.kind)).map(|m| m.change).sum() };
+    let previous_count = store.inventory.last_stocktake(item_id).map(|m| m.level).unwrap_or(0);
+    //items counted for the first time start being tracked without shrinkage
+    let expected = if store.inventory.is_tracked(item_id) { previous_count + movements.iter().map(|m| m.change).sum::<i64>() } else { counted };
+    let shrinkage_units = expected - counted;
+    ShrinkageLine {
+        item_id,
+        itemname: item.name.to_string(),
+        category: item.category.clone(),
+        previous_count,
+        delivered: sum(&[StockMovementKind::Delivery]),
+        consumed: -sum(&[StockMovementKind::Purchase, StockMovementKind::FreeForAll, StockMovementKind::Undo]),
+        written_off: -sum(&[StockMovementKind::WriteOff]),
+        corrected: sum(&[StockMovementKind::Correction]),
+        expected,
+        counted,
+        shrinkage_units,
+        shrinkage_cents: shrinkage_units * i64::from(item.price_at(timestamp_epoch_millis)),
+    }
+}
+
+//compares the counts with the expected stock, then sets the stock to what was counted
+pub fn take_stock(store: &mut Datastore, counted: &HashMap<u32, i64>, timestamp_epoch_millis: i64, comment: &str) -> StocktakeReport {
+    let mut item_ids: Vec<u32> = counted.keys().cloned().collect();
+    item_ids.sort_unstable();
+    let items: Vec<ShrinkageLine> = item_ids.iter().map(|item_id| shrinkage_line(store, *item_id, counted[item_id], timestamp_epoch_millis)).collect();
+    for line in &items {
+        store.inventory.count(line.item_id, line.counted, timestamp_epoch_millis, comment.to_string());
+    }
+
+    let mut per_category: BTreeMap<Option<String>, (i64, i64)> = BTreeMap::new();
+    for line in &items {
+        let entry = per_category.entry(line.category.clone()).or_default();
+        entry.0 += line.shrinkage_units;
+        entry.1 += line.shrinkage_cents;
+    }
+    StocktakeReport {
+        timestamp_epoch_millis,
+        comment: comment.to_string(),
+        shrinkage_units: items.iter().map(|l| l.shrinkage_units).sum(),
+        shrinkage_cents: items.iter().map(|l| l.shrinkage_cents).sum(),
+        items,
+        categories: per_category.into_iter().map(|(category, (shrinkage_units, shrinkage_cents))| CategoryShrinkage {
+            category,
+            shrinkage_units,
+            shrinkage_cents,
+        }).collect(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use stocktake::*;
+    use datastore::DatastoreQueries;
+    use rustix_backend::WriteBackend;
+
+    #[test]
+    fn stocktakes_report_shrinkage_since_the_last_count() {
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.create_item("beer".to_string(), 100, Some("alcohol".to_string()));
+        backend.create_item("wine".to_string(), 300, Some("alcohol".to_string()));
+        backend.create_item("mate".to_string(), 150, None);
+
+        backend.deliver_stock(0, 24, 10, "".to_string());
+        backend.deliver_stock(1, 6, 10, "".to_string());
+        backend.purchase(0, 0, 20);
+        backend.purchase(0, 0, 21);
+        backend.write_off_stock(0, 1, 22, "broken".to_string());
+
+        let counts: HashMap<u32, i64> = vec![(0, 18), (1, 6), (2, 10)].into_iter().collect();
+        assert!(backend.take_stock(counts, 100, "march".to_string()));
+        assert!(!backend.take_stock(vec![(9, 1)].into_iter().collect(), 100, "".to_string()));
+        assert!(!backend.take_stock(HashMap::new(), 100, "".to_string()));
+
+        let report = &backend.datastore.stocktake_reports(0, 1000)[0];
+        let beer = &report.items[0];
+        assert_eq!((beer.delivered, beer.consumed, beer.written_off, beer.expected, beer.counted), (24, 2, 1, 21, 18));
+        assert_eq!((beer.shrinkage_units, beer.shrinkage_cents), (3, 300));
+        //mate is tracked from now on
+        assert_eq!(report.items[2].shrinkage_units, 0);
+        assert_eq!(backend.datastore.stock_level(2), Some(10));
+        assert_eq!(report.categories, vec![
+            CategoryShrinkage { category: None, shrinkage_units: 0, shrinkage_cents: 0 },
+            CategoryShrinkage { category: Some("alcohol".to_string()), shrinkage_units: 3, shrinkage_cents: 300 },
+        ]);
+
+        //the next stocktake only looks at what happened since
+        backend.purchase(0, 1, 110);
+        backend.deliver_stock(1, 6, 120, "".to_string());
+        backend.update_item_at(1, "wine".to_string(), 400, vec![], Some("alcohol".to_string()), 150);
+        backend.take_stock(vec![(1, 9)].into_iter().collect(), 200, "april".to_string());
+        let reports = backend.datastore.stocktake_reports(0, 1000);
+        let wine = &reports[1].items[0];
+        assert_eq!((wine.previous_count, wine.delivered, wine.consumed, wine.expected), (6, 6, 1, 11));
+        assert_eq!((reports[1].shrinkage_units, reports[1].shrinkage_cents), (2, 800));
+        assert_eq!(backend.datastore.stocktake_reports(150, 1000).len(), 1);
+
+        //a purchase entered late but made before the count was already missing when counting
+        backend.purchase(0, 1, 190);
+        assert_eq!(backend.datastore.stock_level(1), Some(9));
+        backend.purchase(0, 1, 210);
+        backend.take_stock(vec![(1, 8)].into_iter().collect(), 300, "may".to_string());
+        let wine = &backend.datastore.stocktake_reports(250, 1000)[0].items[0];
+        assert_eq!((wine.previous_count, wine.consumed, wine.expected, wine.shrinkage_units), (9, 1, 8, 0));
+
+        //a count entered late keeps the movements made after it on top of the counted level
+        backend.deliver_stock(1, 12, 320, "".to_string());
+        backend.purchase(0, 1, 330);
+        backend.take_stock(vec![(1, 7)].into_iter().collect(), 310, "late".to_string());
+        let wine = &backend.datastore.stocktake_reports(305, 315)[0].items[0];
+        assert_eq!((wine.previous_count, wine.delivered, wine.consumed, wine.expected, wine.shrinkage_units), (8, 0, 0, 8, 1));
+        assert_eq!(backend.datastore.stock_level(1), Some(7 + 12 - 1));
+        backend.take_stock(vec![(1, 18)].into_iter().collect(), 400, "june".to_string());
+        let wine = &backend.datastore.stocktake_reports(350, 1000)[0].items[0];
+        assert_eq!((wine.previous_count, wine.delivered, wine.consumed, wine.expected, wine.shrinkage_units), (7, 12, 1, 18, 0));
+    }
+}
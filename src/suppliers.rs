@@ -0,0 +1,273 @@
+// An attribute to hide warnings for unused code.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use calendar;
+use datastore::Datastore;
+use statistics::DatastoreStatistics;
+use typescriptify::TypeScriptifyTrait;
+
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct SupplierItem {
+    pub item_id: u32,
+    //units per pack, e.g. 20 bottles per crate
+    pub pack_size: u32,
+    pub pack_cost_cents: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct Supplier {
+    pub supplier_id: u32,
+    pub name: String,
+    pub contact: String,
+    pub items: Vec<SupplierItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, TypeScriptify)]
+pub enum SupplierOrderState {
+    Draft,
+    Received,
+    Cancelled,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct OrderLine {
+    pub item_id: u32,
+    pub packs: u32,
+    pub pack_size: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, TypeScriptify)]
+pub struct SupplierOrder {
+    pub order_id: u64,
+    pub supplier_id: u32,
+    pub created_timestamp: i64,
+    pub state: SupplierOrderState,
+    pub lines: Vec<OrderLine>,
+    pub received_timestamp: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Purchasing {
+    pub suppliers: HashMap<u32, Supplier>,
+    pub supplier_id_counter: u32,
+    pub orders: Vec<SupplierOrder>,
+    pub order_id_counter: u64,
+}
+
+impl Supplier {
+    pub fn item(&self, item_id: u32) -> Option<&SupplierItem> {
+        self.items.iter().find(|i| i.item_id == item_id)
+    }
+
+    //replaces the conditions of an item already supplied
+    pub fn set_item(&mut self, item: SupplierItem) {
+        match self.items.iter().position(|i| i.item_id == item.item_id) {
+            Some(idx) => self.items[idx] = item,
+            None => self.items.push(item),
+        }
+    }
+}
+
+impl Purchasing {
+    pub fn create_supplier(&mut self, name: String, contact: String) -> u32 {
+        let supplier_id = self.supplier_id_counter;
+        self.supplier_id_counter += 1;
+        self.suppliers.insert(supplier_id, Supplier {
+            supplier_id,
+            name,
+            contact,
+            items: vec![],
+        });
+        supplier_id
+    }
+
+    pub fn order(&self, order_id: u64) -> Option<&SupplierOrder> {
+        self.orders.iter().find(|o| o.order_id == order_id)
+    }
+
+    pub fn order_mut(&mut self, order_id: u64) -> Option<&mut SupplierOrder> {
+        self.orders.iter_mut().find(|o| o.order_id == order_id)
+    }
+
+    pub fn is_draft(&self, order_id: u64) -> bool {
+        self.order(order_id).map(|o| o.state == SupplierOrderState::Draft).unwrap_or(false)
+    }
+
+    pub fn add_order(&mut self, supplier_id: u32, created_timestamp: i64, lines: Vec<OrderLine>) -> u64 {
+        self.order_id_counter += 1;
+        self.orders.push(SupplierOrder {
+            order_id: self.order_id_counter,
+            supplier_id,
+            created_timestamp,
+            state: SupplierOrderState::Draft,
+            lines,
+            received_timestamp: None,
+        });
+        self.order_id_counter
+    }
+
+    //lines with 0 packs are removed
+    pub fn set_order_line(&mut self, order_id: u64, item_id: u32, packs: u32) {
+        let pack_size = match self.order(order_id).and_then(|o| self.suppliers.get(&o.supplier_id)).and_then(|s| s.item(item_id)) {
+            Some(item) => item.pack_size,
+            None => return,
+        };
+        if let Some(order) = self.order_mut(order_id) {
+            order.lines.retain(|l| l.item_id != item_id);
+            if packs > 0 {
+                order.lines.push(OrderLine { item_id, packs, pack_size });
+                order.lines.sort_by_key(|l| l.item_id);
+            }
+        }
+    }
+}
+
+//units consumed per item in the given range, open and already billed
+fn consumed_units(store: &Datastore, millis_start_inclusive: i64, millis_end_exclusive: i64) -> HashMap<u32, u64> {
+    let mut consumed: HashMap<u32, u64> = HashMap::new();
+    for record in store.consumption_records(millis_start_inclusive, millis_end_exclusive) {
+        if let Some(item_id) = record.item_id {
+            *consumed.entry(item_id).or_insert(0) += record.count;
+        }
+    }
+    consumed
+}
+
+//packs needed to last days_of_cover days, forecast from the consumption of the last lookback_days days.
+//items whose stock is not tracked are assumed to be out of stock
+pub fn restock_suggestions(store: &Datastore, supplier_id: u32, timestamp: i64, lookback_days: u32, days_of_cover: u32) -> Vec<OrderLine> {
+    let supplier = match store.purchasing.suppliers.get(&supplier_id) {
+        Some(supplier) => supplier,
+        None => return vec![],
+    };
+    let lookback_days = u64::from(lookback_days.max(1));
+    let since = timestamp - lookback_days as i64 * calendar::MILLIS_PER_DAY;
+    let consumed_per_item = consumed_units(store, since, timestamp);
+    let mut lines: Vec<OrderLine> = supplier.items.iter()
+        .filter(|i| i.pack_size > 0 && store.items.get(&i.item_id).map(|item| !item.deleted).unwrap_or(false))
+        .filter_map(|i| {
+            let consumed = consumed_per_item.get(&i.item_id).cloned().unwrap_or(0);
+            let target = (consumed * u64::from(days_of_cover)).div_ceil(lookback_days);
+            let in_stock = store.inventory.level(i.item_id).unwrap_or(0).max(0) as u64;
+            let missing = target.saturating_sub(in_stock);
+            //the units of a line have to fit into a delivery
+            let packs = u32::try_from(missing.div_ceil(u64::from(i.pack_size))).unwrap_or(u32::MAX).min(u32::MAX / i.pack_size);
+            if packs == 0 {
+                None
+            } else {
+                Some(OrderLine { item_id: i.item_id, packs, pack_size: i.pack_size })
+            }
+        })
+        .collect();
+    lines.sort_by_key(|l| l.item_id);
+    lines
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+//one line per ordered item, to be sent to the supplier
+pub fn order_csv(store: &Datastore, order_id: u64) -> Option<String> {
+    let order = store.purchasing.order(order_id)?;
+    let supplier = store.purchasing.suppliers.get(&order.supplier_id)?;
+    let mut csv = "order,supplier,item_id,item,packs,pack_size,units,pack_cost_cents,total_cents\n".to_string();
+    for line in &order.lines {
+        let itemname = store.items.get(&line.item_id).map(|i| i.name.to_string()).unwrap_or_default();
+        let pack_cost = supplier.item(line.item_id).and_then(|i| i.pack_cost_cents);
+        let fields = [
+            order.order_id.to_string(),
+            csv_field(&supplier.name),
+            line.item_id.to_string(),
+            csv_field(&itemname),
+            line.packs.to_string(),
+            line.pack_size.to_string(),
+            (u64::from(line.packs) * u64::from(line.pack_size)).to_string(),
+            pack_cost.map(|c| c.to_string()).unwrap_or_default(),
+            pack_cost.map(|c| (u64::from(c) * u64::from(line.packs)).to_string()).unwrap_or_default(),
+        ];
+        csv += &fields.join(",");
+        csv += "\n";
+    }
+    Some(csv)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use suppliers::*;
+    use datastore::DatastoreQueries;
+    use datastore::UserGroup;
+    use rustix_backend::WriteBackend;
+    use rustix_event_shop::BLEvents;
+
+    #[test]
+    fn orders_are_drafted_from_consumption_and_received_as_deliveries() {
+        let day = calendar::MILLIS_PER_DAY;
+        let mut backend = ::build_transient_backend();
+        backend.create_user("klaus".to_string());
+        backend.update_user(0, "klaus".to_string(), true, false, Some("klaus".to_string()), true);
+        backend.create_item("beer".to_string(), 100, None);
+        backend.create_item("mate, 0.5l".to_string(), 150, None);
+        backend.create_item("wine".to_string(), 300, None);
+
+        assert!(backend.create_supplier("Getränke Meier".to_string(), "order@meier.example".to_string()));
+        assert!(backend.set_supplier_item(0, 0, 20, Some(1200)));
+        assert!(backend.set_supplier_item(0, 1, 12, None));
+        assert!(backend.set_supplier_item(0, 2, 6, Some(1500)));
+        assert!(!backend.set_supplier_item(0, 9, 6, None));
+        assert!(!backend.set_supplier_item(1, 0, 6, None));
+
+        //30 beers and 3 mate in 10 days, 5 beers still in stock
+        backend.deliver_stock(0, 35, 0, "".to_string());
+        for i in 0..30 {
+            backend.purchase(0, 0, i * day / 3 + 1);
+        }
+        for i in 0..3 {
+            backend.purchase(0, 1, i * day + 2);
+        }
+        let now = 10 * day;
+        let suggestions = vec![
+            OrderLine { item_id: 0, packs: 2, pack_size: 20 },
+            OrderLine { item_id: 1, packs: 1, pack_size: 12 },
+        ];
+        assert_eq!(backend.datastore.restock_suggestions(0, now, 10, 14), suggestions);
+        //billing keeps the consumption history
+        backend.create_bill(0, now, UserGroup::AllUsers, "".to_string());
+        assert!(backend.apply(&BLEvents::FinalizeBill { timestamp_from: 0, timestamp_to: now }));
+        assert!(backend.datastore.purchases.is_empty());
+        assert_eq!(backend.datastore.restock_suggestions(0, now, 10, 14), suggestions);
+
+        assert!(backend.draft_supplier_order(0, now, 10, 14));
+        assert!(backend.set_supplier_order_line(1, 2, 1));
+        assert!(backend.set_supplier_order_line(1, 1, 0));
+        assert!(!backend.set_supplier_order_line(1, 9, 1));
+        assert!(!backend.set_supplier_order_line(1, 2, u32::MAX));
+        let csv = backend.datastore.supplier_order_csv(1).unwrap();
+        assert_eq!(csv.lines().collect::<Vec<_>>(), vec![
+            "order,supplier,item_id,item,packs,pack_size,units,pack_cost_cents,total_cents",
+            "1,Getränke Meier,0,beer,2,20,40,1200,2400",
+            "1,Getränke Meier,2,wine,1,6,6,1500,1500",
+        ]);
+
+        assert!(backend.receive_supplier_order(1, now + 5));
+        assert!(!backend.receive_supplier_order(1, now + 5));
+        assert!(!backend.set_supplier_order_line(1, 1, 1));
+        assert_eq!(backend.datastore.stock_level(0), Some(45));
+        assert_eq!(backend.datastore.stock_level(2), Some(6));
+        assert_eq!(backend.datastore.supplier_orders(Some(0))[0].state, SupplierOrderState::Received);
+
+        //names with commas are quoted
+        assert!(backend.draft_supplier_order(0, now, 10, 14));
+        assert!(backend.datastore.supplier_order_csv(2).unwrap().contains("\"mate, 0.5l\""));
+        assert!(backend.cancel_supplier_order(2));
+        assert!(!backend.receive_supplier_order(2, now));
+    }
+}